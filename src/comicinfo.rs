@@ -0,0 +1,140 @@
+/// A page as described by the `<Pages>` of a ComicInfo.xml
+///
+/// ```xml
+/// <Pages>
+///   <Page Image="0" Type="FrontCover" />
+///   <Page Image="2" Type="Advertisement" />
+///   <Page Image="1" />
+/// </Pages>
+/// ```
+///
+/// `Image` counts the images of the archive in the order of their names from 0, the order
+/// of the pages is the order to read them in.
+#[derive(Debug, PartialEq)]
+pub struct Page {
+    pub image: usize,
+    /// Roles like FrontCover or Story, a page may have several
+    pub types: Vec<String>,
+}
+
+impl Page {
+    /// Whether the page is not meant to be part of the book
+    pub fn is_dropped(&self) -> bool {
+        self.types
+            .iter()
+            .any(|kind| matches!(kind.as_str(), "Deleted" | "Advertisement"))
+    }
+}
+
+/// All pages listed in the ComicInfo.xml in their order, none if it has no `<Pages>`
+pub fn pages(xml: &str) -> Result<Vec<Page>, String> {
+    let mut pages = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find("<Page") {
+        let tag = &rest[start + "<Page".len()..];
+        let end = tag
+            .find('>')
+            .ok_or_else(|| format!("unterminated <Page> of page {}", pages.len() + 1))?;
+        rest = &tag[end..];
+        // <Pages> and <PageCount> start alike
+        if !tag.starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+            continue;
+        }
+        let attributes = attributes(tag[..end].trim_end_matches('/'))
+            .map_err(|e| format!("page {}: {e}", pages.len() + 1))?;
+        let (mut image, mut types) = (None, vec![]);
+        for (name, value) in attributes {
+            match name {
+                "Image" => {
+                    let index = value.parse();
+                    image =
+                        Some(index.map_err(|_| {
+                            format!("page {}: '{value}' is no image", pages.len() + 1)
+                        })?);
+                }
+                "Type" => types = value.split_whitespace().map(str::to_string).collect(),
+                _ => (),
+            }
+        }
+        let image = image.ok_or_else(|| format!("page {}: Image is missing", pages.len() + 1))?;
+        pages.push(Page { image, types });
+    }
+    Ok(pages)
+}
+
+/// Names and values of the attributes within a tag, like `Image="0" Type='Story'`
+fn attributes(tag: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut attributes = vec![];
+    let mut rest = tag.trim_start();
+    while !rest.is_empty() {
+        let (name, value) = rest
+            .split_once('=')
+            .ok_or_else(|| format!("attribute without value in '{}'", tag.trim()))?;
+        let value = value.trim_start();
+        let quote = value
+            .chars()
+            .next()
+            .filter(|&c| c == '"' || c == '\'')
+            .ok_or_else(|| format!("value of {} is not quoted", name.trim()))?;
+        let (value, after) = value[1..]
+            .split_once(quote)
+            .ok_or_else(|| format!("value of {} is not closed", name.trim()))?;
+        attributes.push((name.trim(), value));
+        rest = after.trim_start();
+    }
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages() {
+        let xml = r#"<?xml version="1.0"?>
+<ComicInfo>
+  <PageCount>4</PageCount>
+  <Pages>
+    <Page Image="0" Type="FrontCover" ImageWidth="800" />
+    <Page Image="3" Type='Advertisement'/>
+    <Page Type="Story Preview" Image="2"></Page>
+    <Page Image = "1">
+  </Pages>
+</ComicInfo>"#;
+        let page = |image, types: &[&str]| Page {
+            image,
+            types: types.iter().map(|kind| kind.to_string()).collect(),
+        };
+        let pages = pages(xml).unwrap();
+        assert_eq!(
+            pages,
+            [
+                page(0, &["FrontCover"]),
+                page(3, &["Advertisement"]),
+                page(2, &["Story", "Preview"]),
+                page(1, &[]),
+            ]
+        );
+        let dropped = pages.iter().map(Page::is_dropped).collect::<Vec<_>>();
+        assert_eq!(dropped, [false, true, false, false]);
+        assert_eq!(super::pages("<ComicInfo></ComicInfo>"), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_invalid_pages() {
+        let error = |xml: &str| pages(xml).unwrap_err();
+        assert_eq!(
+            error(r#"<Page Type="Story" />"#),
+            "page 1: Image is missing"
+        );
+        assert_eq!(
+            error(r#"<Page Image="0"/><Page Image="first"/>"#),
+            "page 2: 'first' is no image"
+        );
+        assert_eq!(
+            error("<Page Image=0 />"),
+            "page 1: value of Image is not quoted"
+        );
+        assert_eq!(error(r#"<Page Image="0""#), "unterminated <Page> of page 1");
+    }
+}
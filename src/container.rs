@@ -0,0 +1,638 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// The kind of archive a comic is stored in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Container {
+    Zip,
+    Tar,
+    /// Zip with a fixed layout, only the images are converted
+    Epub,
+}
+
+/// Extensions of all archives `Container::from_path` recognizes
+pub const ARCHIVE_EXTENSIONS: [&str; 5] = ["cbz", "zip", "cbt", "tar", "epub"];
+
+impl Container {
+    pub fn from_path(path: &Path) -> Option<Container> {
+        // archives are extracted to a directory named like their stem, which must stay beside them
+        if matches!(path.file_stem()?.to_str()?, "." | "..") {
+            return None;
+        }
+        match path.extension()?.to_str()? {
+            "cbz" | "zip" => Some(Container::Zip),
+            "cbt" | "tar" => Some(Container::Tar),
+            "epub" => Some(Container::Epub),
+            _ => None,
+        }
+    }
+
+    /// Extension used for converted archives
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Zip => "cbz",
+            Container::Tar => "cbt",
+            Container::Epub => "epub",
+        }
+    }
+
+    /// Whether all entries are stored within a directory named like the archive
+    pub fn has_root_dir(self) -> bool {
+        match self {
+            Container::Zip | Container::Tar => true,
+            Container::Epub => false,
+        }
+    }
+
+    /// Read through all entries of the archive to make sure it is intact
+    pub fn verify(self, path: &Path) -> io::Result<()> {
+        let file = File::open(path)?;
+        match self {
+            Container::Zip | Container::Epub => {
+                let mut archive = zip::ZipArchive::new(file)?;
+                for i in 0..archive.len() {
+                    io::copy(&mut archive.by_index(i)?, &mut io::sink())?;
+                }
+            }
+            Container::Tar => {
+                for entry in tar::Archive::new(file).entries()? {
+                    io::copy(&mut entry?, &mut io::sink())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The first `length` bytes of every file in the archive, read without extracting it
+    pub fn file_headers(self, path: &Path, length: u64) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+        let file = File::open(path)?;
+        let mut headers = vec![];
+        match self {
+            Container::Zip | Container::Epub => {
+                let mut archive = zip::ZipArchive::new(file)?;
+                for i in 0..archive.len() {
+                    let entry = archive.by_index(i)?;
+                    if entry.is_dir() {
+                        continue;
+                    }
+                    let name = PathBuf::from(normalize_separators(entry.name()));
+                    let mut header = vec![];
+                    entry.take(length).read_to_end(&mut header)?;
+                    headers.push((name, header));
+                }
+            }
+            Container::Tar => {
+                for entry in tar::Archive::new(file).entries()? {
+                    let entry = entry?;
+                    if entry.header().entry_type().is_dir() {
+                        continue;
+                    }
+                    let name = entry.path()?.into_owned();
+                    let mut header = vec![];
+                    entry.take(length).read_to_end(&mut header)?;
+                    headers.push((name, header));
+                }
+            }
+        }
+        Ok(headers)
+    }
+
+    /// Space the extracted entries take up, tar archives are not compressed in the first place
+    pub fn uncompressed_size(self, path: &Path) -> io::Result<u64> {
+        match self {
+            Container::Zip | Container::Epub => {
+                let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+                let mut size = 0;
+                for i in 0..archive.len() {
+                    size += archive.by_index_raw(i)?.size();
+                }
+                Ok(size)
+            }
+            Container::Tar => Ok(path.metadata()?.len()),
+        }
+    }
+
+    /// Bytes an entry takes up in the archive when stored uncompressed, headers included
+    pub fn stored_size(self, name: &str, size: u64) -> u64 {
+        let name_length = name.len() as u64;
+        match self {
+            // local header and central directory record both hold the name, plus extra fields
+            Container::Zip | Container::Epub => 30 + 46 + 2 * name_length + 64 + size,
+            Container::Tar => {
+                // names that do not fit the header get an extra entry of their own
+                let long_name = match name_length >= 100 {
+                    true => 512 + name_length.div_ceil(512) * 512,
+                    false => 0,
+                };
+                long_name + 512 + size.div_ceil(512) * 512
+            }
+        }
+    }
+
+    /// Bytes an archive takes up without any entries
+    pub fn empty_size(self) -> u64 {
+        match self {
+            Container::Zip => 22,
+            Container::Epub => {
+                22 + self.stored_size(EPUB_MIMETYPE_NAME, EPUB_MIMETYPE.len() as u64)
+            }
+            Container::Tar => 1024,
+        }
+    }
+
+    /// Whether the zip crate can read the archive, so 7z is not needed
+    pub fn is_zip(self) -> bool {
+        match self {
+            Container::Zip | Container::Epub => true,
+            Container::Tar => false,
+        }
+    }
+
+    /// Switch telling 7z which archive type to expect
+    pub fn seven_zip_type(self) -> &'static str {
+        match self {
+            Container::Zip | Container::Epub => "-tzip",
+            Container::Tar => "-ttar",
+        }
+    }
+}
+
+/// Name and content of the entry every EPUB has to start with
+const EPUB_MIMETYPE_NAME: &str = "mimetype";
+const EPUB_MIMETYPE: &str = "application/epub+zip";
+
+/// Unix permissions stored for the entries of a new archive
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntryModes {
+    pub file: u32,
+    pub dir: u32,
+}
+
+impl Default for EntryModes {
+    fn default() -> Self {
+        EntryModes {
+            file: 0o644,
+            dir: 0o755,
+        }
+    }
+}
+
+/// All entries of a zip archive and whether they are directories
+pub fn zip_entries(path: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+    let archive = zip::ZipArchive::new(File::open(path)?)?;
+    let entries = archive
+        .file_names()
+        .map(|name| {
+            let name = normalize_separators(name);
+            (
+                PathBuf::from(name.trim_end_matches('/')),
+                name.ends_with('/'),
+            )
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// The comment of a zip archive, empty if it has none
+pub fn zip_comment(path: &Path) -> io::Result<Vec<u8>> {
+    let archive = zip::ZipArchive::new(File::open(path)?)?;
+    Ok(archive.comment().to_vec())
+}
+
+/// Entry name with the `\\` some Windows tools separate directories with replaced by `/`
+///
+/// Zip entries are always separated by `/`, a backslash is no valid part of a name.
+pub fn normalize_separators(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+/// Extract all entries of a zip archive into the directory
+pub fn extract_zip(path: &Path, dir: &Path) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    if !archive.file_names().any(|name| name.contains('\\')) {
+        archive.extract(dir)?;
+        return Ok(());
+    }
+
+    // the zip crate would keep backslashes as part of the file names
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = normalize_separators(entry.name());
+        let relative = Path::new(&name);
+        let is_safe = relative
+            .components()
+            .all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+        if !is_safe {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("entry {name:?} points outside of the archive"),
+            ));
+        }
+        let target = dir.join(relative);
+        if name.ends_with('/') {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(&target)?)?;
+    }
+    Ok(())
+}
+
+/// Writes entries into a new archive of the respective container type
+pub enum ContainerWriter {
+    Zip {
+        zipper: Box<ZipWriter<File>>,
+        options: SimpleFileOptions,
+        modes: EntryModes,
+        buffer: Vec<u8>,
+        epub: bool,
+        /// Deflate entries that are not images, which are compressed already
+        compress_metadata: bool,
+    },
+    Tar {
+        builder: tar::Builder<File>,
+        modes: EntryModes,
+    },
+}
+
+impl ContainerWriter {
+    pub fn new(
+        container: Container,
+        file: File,
+        modes: EntryModes,
+        compress_metadata: bool,
+    ) -> io::Result<ContainerWriter> {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        let writer = match container {
+            Container::Zip => ContainerWriter::Zip {
+                zipper: Box::new(ZipWriter::new(file)),
+                options,
+                modes,
+                buffer: Vec::new(),
+                epub: false,
+                compress_metadata,
+            },
+            Container::Epub => {
+                // readers identify the book by this uncompressed first entry
+                let mut zipper = ZipWriter::new(file);
+                zipper.start_file(EPUB_MIMETYPE_NAME, options)?;
+                zipper.write_all(EPUB_MIMETYPE.as_bytes())?;
+                ContainerWriter::Zip {
+                    zipper: Box::new(zipper),
+                    options,
+                    modes,
+                    buffer: Vec::new(),
+                    epub: true,
+                    compress_metadata,
+                }
+            }
+            Container::Tar => ContainerWriter::Tar {
+                builder: tar::Builder::new(file),
+                modes,
+            },
+        };
+        Ok(writer)
+    }
+
+    pub fn add_directory(&mut self, name: &str, path: &Path) -> io::Result<()> {
+        match self {
+            ContainerWriter::Zip {
+                zipper,
+                options,
+                modes,
+                ..
+            } => Ok(zipper.add_directory(name, options.unix_permissions(modes.dir))?),
+            ContainerWriter::Tar { builder, modes } => {
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&path.metadata()?);
+                header.set_mode(modes.dir);
+                builder.append_data(&mut header, name, io::empty())
+            }
+        }
+    }
+
+    pub fn add_file(&mut self, name: &str, path: &Path) -> io::Result<()> {
+        match self {
+            ContainerWriter::Zip {
+                zipper,
+                options,
+                modes,
+                buffer,
+                epub,
+                compress_metadata,
+            } => {
+                if *epub && name == EPUB_MIMETYPE_NAME {
+                    // already written as the first entry
+                    return Ok(());
+                }
+                let options = match *compress_metadata && crate::image_format(path).is_none() {
+                    true => options.compression_method(CompressionMethod::Deflated),
+                    false => *options,
+                };
+                zipper.start_file(name, options.unix_permissions(modes.file))?;
+                File::open(path)?.read_to_end(buffer)?;
+                zipper.write_all(buffer)?;
+                buffer.clear();
+                Ok(())
+            }
+            ContainerWriter::Tar { builder, modes } => {
+                let file = File::open(path)?;
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&file.metadata()?);
+                header.set_mode(modes.file);
+                builder.append_data(&mut header, name, file)
+            }
+        }
+    }
+
+    /// Give the archive a comment, which tar archives have no place for
+    pub fn set_comment(&mut self, comment: &[u8]) {
+        if let ContainerWriter::Zip { zipper, .. } = self {
+            zipper.set_raw_comment(comment.into());
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            ContainerWriter::Zip { zipper, .. } => {
+                zipper.finish()?;
+                Ok(())
+            }
+            ContainerWriter::Tar { builder, .. } => builder.into_inner()?.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{write_zip, TempDir};
+
+    #[test]
+    fn test_container_from_extension() {
+        let container = |p: &str| Container::from_path(&PathBuf::from(p));
+        assert_eq!(container("a/book.cbz"), Some(Container::Zip));
+        assert_eq!(container("a/book.zip"), Some(Container::Zip));
+        assert_eq!(container("a/book.cbt"), Some(Container::Tar));
+        assert_eq!(container("a/book.tar"), Some(Container::Tar));
+        assert_eq!(container("a/book.epub"), Some(Container::Epub));
+        assert_eq!(container("a/book.cbr"), None);
+        assert_eq!(container("a/book"), None);
+        assert_eq!(container("a/My.Comic.v01.cbz"), Some(Container::Zip));
+        // these would be extracted to a and its parent
+        assert_eq!(container("a/..cbz"), None);
+        assert_eq!(container("a/...cbz"), None);
+    }
+
+    #[test]
+    fn test_write_tar() {
+        let dir = TempDir::new("write_tar");
+        std::fs::create_dir_all(dir.join("book")).unwrap();
+        std::fs::write(dir.join("book/page.png"), b"not really a png").unwrap();
+
+        let tar_path = dir.join("book.cbt");
+        let mut writer = ContainerWriter::new(
+            Container::Tar,
+            File::create(&tar_path).unwrap(),
+            EntryModes::default(),
+            false,
+        )
+        .unwrap();
+        writer.add_directory("book", &dir.join("book")).unwrap();
+        writer
+            .add_file("book/page.png", &dir.join("book/page.png"))
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut archive = tar::Archive::new(File::open(&tar_path).unwrap());
+        let names = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["book", "book/page.png"]);
+    }
+
+    #[test]
+    fn test_file_headers() {
+        let dir = TempDir::new("file_headers");
+        std::fs::create_dir_all(dir.join("book")).unwrap();
+        std::fs::write(dir.join("book/page.png"), b"not really a png").unwrap();
+
+        for container in [Container::Zip, Container::Tar] {
+            let path = dir.join(format!("book.{}", container.extension()));
+            let file = File::create(&path).unwrap();
+            let mut writer =
+                ContainerWriter::new(container, file, EntryModes::default(), false).unwrap();
+            writer.add_directory("book", &dir.join("book")).unwrap();
+            writer
+                .add_file("book/page.png", &dir.join("book/page.png"))
+                .unwrap();
+            writer.finish().unwrap();
+
+            assert_eq!(
+                container.file_headers(&path, 6).unwrap(),
+                [(PathBuf::from("book/page.png"), b"not re".to_vec())]
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_detects_truncated_zip() {
+        let dir = TempDir::new("verify_zip");
+        std::fs::write(dir.join("page.png"), vec![7; 4096]).unwrap();
+
+        let zip_path = dir.join("book.cbz");
+        let mut writer = ContainerWriter::new(
+            Container::Zip,
+            File::create(&zip_path).unwrap(),
+            EntryModes::default(),
+            false,
+        )
+        .unwrap();
+        writer.add_file("page.png", &dir.join("page.png")).unwrap();
+        writer.finish().unwrap();
+        assert!(Container::Zip.verify(&zip_path).is_ok());
+
+        let bytes = std::fs::read(&zip_path).unwrap();
+        std::fs::write(&zip_path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(Container::Zip.verify(&zip_path).is_err());
+    }
+
+    #[test]
+    fn test_entry_permissions() {
+        let dir = TempDir::new("entry_permissions");
+        std::fs::create_dir_all(dir.join("book")).unwrap();
+        std::fs::write(dir.join("book/page.png"), b"not really a png").unwrap();
+
+        let modes = |container, path: &Path, entry_modes| {
+            let mut writer =
+                ContainerWriter::new(container, File::create(path).unwrap(), entry_modes, false)
+                    .unwrap();
+            writer.add_directory("book", &dir.join("book")).unwrap();
+            writer
+                .add_file("book/page.png", &dir.join("book/page.png"))
+                .unwrap();
+            writer.finish().unwrap();
+            match container {
+                Container::Zip | Container::Epub => {
+                    let mut archive = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+                    (0..archive.len())
+                        .map(|i| archive.by_index(i).unwrap().unix_mode().unwrap() & 0o7777)
+                        .collect::<Vec<_>>()
+                }
+                Container::Tar => tar::Archive::new(File::open(path).unwrap())
+                    .entries()
+                    .unwrap()
+                    .map(|e| e.unwrap().header().mode().unwrap() & 0o7777)
+                    .collect::<Vec<_>>(),
+            }
+        };
+        let custom = EntryModes {
+            file: 0o600,
+            dir: 0o700,
+        };
+        for container in [Container::Zip, Container::Tar] {
+            let path = dir.join("book").with_extension(container.extension());
+            assert_eq!(
+                modes(container, &path, EntryModes::default()),
+                [0o755, 0o644]
+            );
+            assert_eq!(modes(container, &path, custom), [0o700, 0o600]);
+        }
+    }
+
+    #[test]
+    fn test_epub_starts_with_mimetype() {
+        let dir = TempDir::new("epub_mimetype");
+        std::fs::write(dir.join("content.opf"), b"<package/>").unwrap();
+        std::fs::write(dir.join("mimetype"), EPUB_MIMETYPE).unwrap();
+
+        let epub_path = dir.join("book.epub");
+        let mut writer = ContainerWriter::new(
+            Container::Epub,
+            File::create(&epub_path).unwrap(),
+            EntryModes::default(),
+            false,
+        )
+        .unwrap();
+        writer
+            .add_file("content.opf", &dir.join("content.opf"))
+            .unwrap();
+        writer.add_file("mimetype", &dir.join("mimetype")).unwrap();
+        writer.finish().unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&epub_path).unwrap()).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut mimetype = archive.by_index(0).unwrap();
+        assert_eq!(mimetype.name(), "mimetype");
+        assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        let mut content = String::new();
+        mimetype.read_to_string(&mut content).unwrap();
+        assert_eq!(content, EPUB_MIMETYPE);
+    }
+
+    #[test]
+    fn test_compress_metadata() {
+        let dir = TempDir::new("compress_metadata");
+        std::fs::write(dir.join("page.png"), vec![7; 4096]).unwrap();
+        std::fs::write(dir.join("ComicInfo.xml"), vec![b'x'; 4096]).unwrap();
+
+        let methods = |compress_metadata| {
+            let zip_path = dir.join("book.cbz");
+            let mut writer = ContainerWriter::new(
+                Container::Zip,
+                File::create(&zip_path).unwrap(),
+                EntryModes::default(),
+                compress_metadata,
+            )
+            .unwrap();
+            writer.add_file("page.png", &dir.join("page.png")).unwrap();
+            writer
+                .add_file("ComicInfo.xml", &dir.join("ComicInfo.xml"))
+                .unwrap();
+            writer.finish().unwrap();
+            let mut archive = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+            (0..archive.len())
+                .map(|i| archive.by_index(i).unwrap().compression())
+                .collect::<Vec<_>>()
+        };
+        use CompressionMethod::{Deflated, Stored};
+        assert_eq!(methods(false), [Stored, Stored]);
+        assert_eq!(methods(true), [Stored, Deflated]);
+    }
+
+    #[test]
+    fn test_zip_comment() {
+        let dir = TempDir::new("zip_comment");
+        let write = |name: &str, comment: Option<&[u8]>| {
+            let zip_path = dir.join(name);
+            let file = File::create(&zip_path).unwrap();
+            let mut writer =
+                ContainerWriter::new(Container::Zip, file, EntryModes::default(), false).unwrap();
+            if let Some(comment) = comment {
+                writer.set_comment(comment);
+            }
+            writer.finish().unwrap();
+            zip_path
+        };
+        let comment = "Series: Example, Vol. 1 – scanned".as_bytes();
+        assert_eq!(
+            zip_comment(&write("a.cbz", Some(comment))).unwrap(),
+            comment
+        );
+        assert!(zip_comment(&write("b.cbz", None)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_zip_without_7z() {
+        let dir = TempDir::new("read_zip");
+        let zip_path = dir.join("book.cbz");
+        write_zip(&zip_path, &[("book/page.png", b"not really a png")]);
+
+        assert_eq!(
+            zip_entries(&zip_path).unwrap(),
+            [
+                (PathBuf::from("book"), true),
+                (PathBuf::from("book/page.png"), false)
+            ]
+        );
+        let out_dir = dir.join("out");
+        extract_zip(&zip_path, &out_dir).unwrap();
+        assert_eq!(
+            std::fs::read(out_dir.join("book/page.png")).unwrap(),
+            b"not really a png"
+        );
+    }
+
+    #[test]
+    fn test_backslash_separators() {
+        let dir = TempDir::new("backslash_zip");
+
+        // as written by some Windows tools
+        let zip_path = dir.join("book.cbz");
+        let mut zipper = ZipWriter::new(File::create(&zip_path).unwrap());
+        zipper
+            .start_file("book\\ch 1\\page.png", SimpleFileOptions::default())
+            .unwrap();
+        zipper.write_all(b"not really a png").unwrap();
+        zipper.finish().unwrap();
+
+        assert_eq!(
+            zip_entries(&zip_path).unwrap(),
+            [(PathBuf::from("book/ch 1/page.png"), false)]
+        );
+        let out_dir = dir.join("out");
+        extract_zip(&zip_path, &out_dir).unwrap();
+        assert_eq!(
+            std::fs::read(out_dir.join("book/ch 1/page.png")).unwrap(),
+            b"not really a png"
+        );
+    }
+}
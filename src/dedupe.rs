@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{DefaultHasher, Hasher};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+/// Files with identical content, the first one being kept as the original
+#[derive(Debug, PartialEq)]
+pub struct Duplicates {
+    pub files: Vec<PathBuf>,
+    pub size: u64,
+}
+
+impl Duplicates {
+    /// Bytes that would be saved by storing the content only once
+    pub fn redundant_bytes(&self) -> u64 {
+        self.size * (self.files.len() as u64 - 1)
+    }
+}
+
+/// Find all groups of identical files below `dir`
+pub fn find_duplicates(dir: &Path) -> io::Result<Vec<Duplicates>> {
+    let mut files = vec![];
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push(entry.into_path());
+        }
+    }
+    duplicates_among(&files)
+}
+
+/// Groups of identical files among `files`
+pub fn duplicates_among(files: &[PathBuf]) -> io::Result<Vec<Duplicates>> {
+    let mut duplicates = vec![];
+    for group in identical(files)? {
+        duplicates.push(Duplicates {
+            size: fs::metadata(&files[group[0]])?.len(),
+            files: group
+                .into_iter()
+                .map(|index| files[index].clone())
+                .collect(),
+        });
+    }
+    duplicates.sort_by(|a, b| a.files.cmp(&b.files));
+    Ok(duplicates)
+}
+
+/// Replace all files of the group but the first with a hard link to it, returns the bytes
+/// saved by doing so
+///
+/// Files that are already linked to the first one save nothing.
+pub fn hard_link(duplicates: &Duplicates) -> io::Result<u64> {
+    let id = |path: &Path| fs::metadata(path).map(|meta| (meta.dev(), meta.ino()));
+    let original = &duplicates.files[0];
+    let mut saved = 0;
+    for file in &duplicates.files[1..] {
+        if id(file)? == id(original)? {
+            continue;
+        }
+        // the duplicate is only replaced once the link is in place
+        let mut link = file.clone().into_os_string();
+        link.push(".cbzin-link");
+        fs::hard_link(original, &link)?;
+        if let Err(e) = fs::rename(&link, file) {
+            let _ = fs::remove_file(&link);
+            return Err(e);
+        }
+        saved += duplicates.size;
+    }
+    Ok(saved)
+}
+
+/// Groups of files with identical content, as ascending indices into `files`
+///
+/// Only files of the same size are hashed, files with the same hash are compared byte by byte.
+pub fn identical(files: &[PathBuf]) -> io::Result<Vec<Vec<usize>>> {
+    let mut by_size = HashMap::<u64, Vec<usize>>::new();
+    for (index, file) in files.iter().enumerate() {
+        by_size
+            .entry(fs::metadata(file)?.len())
+            .or_default()
+            .push(index);
+    }
+
+    let mut groups = vec![];
+    for indices in by_size.into_values().filter(|indices| indices.len() > 1) {
+        let mut by_hash = HashMap::<u64, Vec<(usize, Vec<u8>)>>::new();
+        for index in indices {
+            let content = fs::read(&files[index])?;
+            let mut hasher = DefaultHasher::new();
+            hasher.write(&content);
+            by_hash
+                .entry(hasher.finish())
+                .or_default()
+                .push((index, content));
+        }
+        for mut candidates in by_hash.into_values() {
+            // a hash collision must not let different pages be treated as one
+            while !candidates.is_empty() {
+                let content = candidates[0].1.clone();
+                let (same, different): (Vec<_>, Vec<_>) = candidates
+                    .into_iter()
+                    .partition(|(_, other)| *other == content);
+                if same.len() > 1 {
+                    groups.push(same.into_iter().map(|(index, _)| index).collect());
+                }
+                candidates = different;
+            }
+        }
+    }
+    groups.sort();
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+
+    #[test]
+    fn test_find_duplicates() {
+        let dir = TempDir::new("find_duplicates");
+        fs::create_dir_all(dir.join("chapter")).unwrap();
+        fs::write(dir.join("1.avif"), b"divider").unwrap();
+        fs::write(dir.join("2.avif"), b"page two").unwrap();
+        fs::write(dir.join("chapter/3.avif"), b"divider").unwrap();
+        fs::write(dir.join("4.avif"), b"dividex").unwrap();
+
+        let duplicates = find_duplicates(&dir).unwrap();
+        assert_eq!(
+            duplicates,
+            [Duplicates {
+                files: vec![dir.join("1.avif"), dir.join("chapter/3.avif")],
+                size: 7,
+            }]
+        );
+        assert_eq!(duplicates[0].redundant_bytes(), 7);
+    }
+
+    #[test]
+    fn test_hard_link() {
+        let dir = TempDir::new("hard_link");
+        let files = ["a.avif.cbz", "b.avif.cbz", "c.avif.cbz"].map(|name| dir.join(name));
+        for file in &files {
+            fs::write(file, b"same book").unwrap();
+        }
+
+        let duplicates = duplicates_among(&files).unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(hard_link(&duplicates[0]).unwrap(), 18);
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+        assert!(files.iter().all(|file| inode(file) == inode(&files[0])));
+        assert_eq!(fs::read(&files[2]).unwrap(), b"same book");
+        // linked already, nothing more to save
+        assert_eq!(hard_link(&duplicates[0]).unwrap(), 0);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_identical_indices() {
+        let dir = TempDir::new("identical_indices");
+        let files = ["a", "b", "c", "d", "e"].map(|name| dir.join(name));
+        for (file, content) in files
+            .iter()
+            .zip(["recap", "page", "recap", "page", "other"])
+        {
+            fs::write(file, content).unwrap();
+        }
+        assert_eq!(identical(&files).unwrap(), [vec![0, 2], vec![1, 3]]);
+    }
+}
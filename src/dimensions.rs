@@ -0,0 +1,323 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Bytes read from the start of an image, all headers of interest lie within
+pub const HEADER_LENGTH: u64 = 64 * 1024;
+
+/// Width and height of the image at `path` as stated in its header, without decoding it
+///
+/// Images in no known format or with a header that cannot be made sense of give `None`.
+pub fn image_dimensions(path: &Path) -> io::Result<Option<(u64, u64)>> {
+    let mut header = Vec::new();
+    File::open(path)?
+        .take(HEADER_LENGTH)
+        .read_to_end(&mut header)?;
+    Ok(dimensions(&header))
+}
+
+/// Whether the Avif image at `path` is an image sequence, which declares itself by its brand
+pub fn is_animated_avif(path: &Path) -> io::Result<bool> {
+    let mut header = Vec::new();
+    File::open(path)?
+        .take(HEADER_LENGTH)
+        .read_to_end(&mut header)?;
+    Ok(avif_is_sequence(&header))
+}
+
+/// Whether the Png image at `path` has an alpha channel or a transparent color
+pub fn png_has_alpha(path: &Path) -> io::Result<bool> {
+    let mut header = Vec::new();
+    File::open(path)?
+        .take(HEADER_LENGTH)
+        .read_to_end(&mut header)?;
+    Ok(png_is_transparent(&header))
+}
+
+/// Color types 4 and 6 carry alpha, any other one may name transparent colors in a chunk
+/// before the image data
+fn png_is_transparent(header: &[u8]) -> bool {
+    if matches!(header.get(25), Some(4 | 6)) {
+        return true;
+    }
+    let mut at = 8;
+    while let (Some(length), Some(kind)) = (be32(header, at), header.get(at + 4..at + 8)) {
+        match kind {
+            b"tRNS" => return true,
+            b"IDAT" | b"IEND" => return false,
+            // length, type and checksum around the data
+            _ => at += 12 + length as usize,
+        }
+    }
+    false
+}
+
+/// The major or any compatible brand of the file type box is `avis` for sequences
+fn avif_is_sequence(header: &[u8]) -> bool {
+    let size = be32(header, 0).unwrap_or(0) as usize;
+    match header.get(4..8) {
+        Some(b"ftyp") => header
+            .get(8..size.min(header.len()))
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .any(|brand| brand == b"avis"),
+        _ => false,
+    }
+}
+
+/// Width and height as stated in the header, the first [`HEADER_LENGTH`] bytes of an image
+pub fn dimensions(header: &[u8]) -> Option<(u64, u64)> {
+    match header {
+        [0xFF, 0xD8, 0xFF, ..] => jpeg(header),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => png(header),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => webp(header),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => avif(header),
+        [0xFF, 0x0A, ..] => jxl(&header[2..]),
+        [0, 0, 0, 0x0C, b'J', b'X', b'L', b' ', ..] => jxl_container(header),
+        _ => None,
+    }
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u64> {
+    let bytes = bytes.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]) as u64)
+}
+
+fn be32(bytes: &[u8], at: usize) -> Option<u64> {
+    let bytes = bytes.get(at..at + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()) as u64)
+}
+
+fn le16(bytes: &[u8], at: usize) -> Option<u64> {
+    let bytes = bytes.get(at..at + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]) as u64)
+}
+
+fn le24(bytes: &[u8], at: usize) -> Option<u64> {
+    let bytes = bytes.get(at..at + 3)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) as u64)
+}
+
+/// The frame header follows any number of segments like Exif or ICC profiles
+fn jpeg(header: &[u8]) -> Option<(u64, u64)> {
+    let mut at = 2;
+    loop {
+        let marker = *header.get(at + 1)?;
+        match marker {
+            // start of frame, except for huffman tables, arithmetic coding and JPEG-LS
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Some((be16(header, at + 7)?, be16(header, at + 5)?))
+            }
+            // start of scan, the frame header would have come before
+            0xDA => return None,
+            _ => at += 2 + be16(header, at + 2)? as usize,
+        }
+    }
+}
+
+fn png(header: &[u8]) -> Option<(u64, u64)> {
+    match header.get(12..16)? {
+        b"IHDR" => Some((be32(header, 16)?, be32(header, 20)?)),
+        _ => None,
+    }
+}
+
+fn webp(header: &[u8]) -> Option<(u64, u64)> {
+    match header.get(12..16)? {
+        // after the frame tag and start code
+        b"VP8 " => Some((le16(header, 26)? & 0x3FFF, le16(header, 28)? & 0x3FFF)),
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(header.get(21..25)?.try_into().unwrap()) as u64;
+            Some(((bits & 0x3FFF) + 1, (bits >> 14 & 0x3FFF) + 1))
+        }
+        b"VP8X" => Some((le24(header, 24)? + 1, le24(header, 27)? + 1)),
+        _ => None,
+    }
+}
+
+/// Every image item has a spatial extent property, the largest one is the primary image
+fn avif(header: &[u8]) -> Option<(u64, u64)> {
+    header
+        .windows(4)
+        .enumerate()
+        .filter(|(_, window)| *window == b"ispe")
+        .filter_map(|(at, _)| Some((be32(header, at + 8)?, be32(header, at + 12)?)))
+        .max_by_key(|(width, height)| width * height)
+}
+
+/// The codestream is stored in a single box or split over several partial ones
+fn jxl_container(header: &[u8]) -> Option<(u64, u64)> {
+    let mut at = 0;
+    while at + 8 <= header.len() {
+        let size = be32(header, at)? as usize;
+        match header.get(at + 4..at + 8)? {
+            b"jxlc" => return jxl(header.get(at + 8..)?.strip_prefix(&[0xFF, 0x0A])?),
+            // partial boxes start with their index
+            b"jxlp" => return jxl(header.get(at + 12..)?.strip_prefix(&[0xFF, 0x0A])?),
+            _ if size < 8 => return None,
+            _ => at += size,
+        }
+    }
+    None
+}
+
+/// The size header right after the signature of a codestream
+fn jxl(codestream: &[u8]) -> Option<(u64, u64)> {
+    let mut bits = Bits {
+        bytes: codestream,
+        position: 0,
+    };
+    let small = bits.read(1)? == 1;
+    let size = |bits: &mut Bits| match small {
+        true => Some((bits.read(5)? + 1) * 8),
+        false => {
+            let length = [9, 13, 18, 30][bits.read(2)? as usize];
+            Some(bits.read(length)? + 1)
+        }
+    };
+    let height = size(&mut bits)?;
+    let width = match bits.read(3)? {
+        0 => size(&mut bits)?,
+        ratio => {
+            let (numerator, denominator) =
+                [(1, 1), (12, 10), (4, 3), (3, 2), (16, 9), (5, 4), (2, 1)][ratio as usize - 1];
+            height * numerator / denominator
+        }
+    };
+    Some((width, height))
+}
+
+/// Reads bits starting from the least significant one, as Jpeg XL stores them
+struct Bits<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, count: usize) -> Option<u64> {
+        let mut value = 0;
+        for bit in 0..count {
+            let byte = self.bytes.get(self.position / 8)?;
+            value |= ((*byte as u64 >> (self.position % 8)) & 1) << bit;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_and_jpeg() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend(3000u32.to_be_bytes());
+        png.extend(4000u32.to_be_bytes());
+        assert_eq!(dimensions(&png), Some((3000, 4000)));
+
+        // an app segment before the baseline frame header
+        let jpeg = [
+            0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x0F,
+            0xA0, 0x0B, 0xB8, 0x03,
+        ];
+        assert_eq!(dimensions(&jpeg), Some((3000, 4000)));
+        assert_eq!(dimensions(&jpeg[..12]), None);
+    }
+
+    #[test]
+    fn test_webp() {
+        let riff = |chunk: &[u8], data: &[u8]| {
+            let mut header = b"RIFF\0\0\0\0WEBP".to_vec();
+            header.extend(chunk);
+            header.extend([0; 4]);
+            header.extend(data);
+            header
+        };
+        let lossy = riff(
+            b"VP8 ",
+            &[0, 0, 0, 0x9D, 0x01, 0x2A, 0xB8, 0x0B, 0xA0, 0x0F],
+        );
+        assert_eq!(dimensions(&lossy), Some((3000, 4000)));
+        // 14 bits each for width and height minus one
+        let bits = 2999u32 | 3999 << 14;
+        let mut data = vec![0x2F];
+        data.extend(bits.to_le_bytes());
+        assert_eq!(dimensions(&riff(b"VP8L", &data)), Some((3000, 4000)));
+        let extended = riff(b"VP8X", &[0, 0, 0, 0, 0xB7, 0x0B, 0, 0x9F, 0x0F, 0]);
+        assert_eq!(dimensions(&extended), Some((3000, 4000)));
+    }
+
+    #[test]
+    fn test_avif_takes_largest_item() {
+        let mut avif = b"\0\0\0\x1cftypavif".to_vec();
+        for (width, height) in [(300u32, 400u32), (3000, 4000)] {
+            avif.extend(b"\0\0\0\x14ispe\0\0\0\0");
+            avif.extend(width.to_be_bytes());
+            avif.extend(height.to_be_bytes());
+        }
+        assert_eq!(dimensions(&avif), Some((3000, 4000)));
+    }
+
+    #[test]
+    fn test_png_transparency() {
+        let png = |color_type: u8, chunk: &[u8; 4]| {
+            let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+            png.extend([0, 0, 0, 1, 0, 0, 0, 1, 8, color_type, 0, 0, 0]);
+            png.extend([0; 4]);
+            png.extend(b"\0\0\0\x02");
+            png.extend(chunk);
+            png.extend([0; 6]);
+            png
+        };
+        assert!(!png_is_transparent(&png(2, b"IDAT")));
+        assert!(png_is_transparent(&png(6, b"IDAT")));
+        assert!(png_is_transparent(&png(4, b"IDAT")));
+        // a palette with a transparent entry
+        assert!(png_is_transparent(&png(3, b"tRNS")));
+        assert!(!png_is_transparent(&png(3, b"PLTE")));
+        assert!(!png_is_transparent(b"\x89PNG"));
+    }
+
+    #[test]
+    fn test_animated_avif() {
+        let still = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf";
+        assert!(!avif_is_sequence(still));
+        // the major brand of a sequence, and a still image that declares one compatible
+        let sequence = b"\0\0\0\x20ftypavis\0\0\0\0avifavismsf1miaf";
+        assert!(avif_is_sequence(sequence));
+        let compatible = b"\0\0\0\x1cftypavif\0\0\0\0avifavismiaf\0\0\0\x08avis";
+        assert!(avif_is_sequence(compatible));
+        // brands of boxes after the file type box do not count
+        let later = b"\0\0\0\x14ftypavif\0\0\0\0avif\0\0\0\x08avis";
+        assert!(!avif_is_sequence(later));
+        assert!(!avif_is_sequence(b"\0\0"));
+    }
+
+    #[test]
+    fn test_jxl_size_header() {
+        // not small, height 4000 and width 3000 with 13 bits each
+        let mut value: u64 = 0;
+        let mut position = 0;
+        for (field, length) in [(0, 1), (1, 2), (3999, 13), (0, 3), (1, 2), (2999, 13)] {
+            value |= field << position;
+            position += length;
+        }
+        let mut codestream = vec![0xFF, 0x0A];
+        codestream.extend(&value.to_le_bytes()[..5]);
+        assert_eq!(dimensions(&codestream), Some((3000, 4000)));
+
+        // small with a 3:2 aspect ratio: height (15 + 1) * 8 = 128
+        let small = 1 | 15 << 1 | 4 << 6;
+        assert_eq!(
+            dimensions(&[0xFF, 0x0A, small as u8, 0x01]),
+            Some((192, 128))
+        );
+
+        let mut container = b"\0\0\0\x0cJXL \x0d\x0a\x87\x0a".to_vec();
+        container.extend(b"\0\0\0\x14ftypjxl \0\0\0\0jxl ");
+        container.extend(b"\0\0\0\0jxlc");
+        container.extend(&codestream);
+        assert_eq!(dimensions(&container), Some((3000, 4000)));
+    }
+}
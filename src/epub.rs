@@ -0,0 +1,151 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+use crate::ImageFormat;
+
+/// Documents of an EPUB that may refer to images
+const DOCUMENT_EXTENSIONS: [&str; 8] = ["xhtml", "html", "htm", "opf", "ncx", "css", "svg", "smil"];
+
+/// An image of the book that got a new file name through its conversion
+pub struct Renamed {
+    pub old_name: String,
+    pub new_name: String,
+    pub format: ImageFormat,
+}
+
+fn media_type(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Png => "image/png",
+        ImageFormat::Avif => "image/avif",
+        ImageFormat::Jxl => "image/jxl",
+        ImageFormat::Webp => "image/webp",
+    }
+}
+
+/// Point all documents within the extracted book at the converted images
+pub fn update_references(book_dir: &Path, renamed: &[Renamed]) -> io::Result<()> {
+    for entry in WalkDir::new(book_dir).into_iter().filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_document = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| DOCUMENT_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+        if !entry.file_type().is_file() || !is_document {
+            continue;
+        }
+        // documents are UTF-8 by the specification, leave anything else alone
+        let Ok(document) = fs::read_to_string(path) else {
+            continue;
+        };
+        let mut updated = document.clone();
+        for image in renamed {
+            updated = replace_references(&updated, &image.old_name, &image.new_name);
+        }
+        if path.extension().is_some_and(|ext| ext == "opf") {
+            updated = update_media_types(&updated, renamed);
+        }
+        if updated != document {
+            fs::write(path, updated)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` is found in `text` at `index` as a whole path component
+fn is_reference(text: &str, index: usize, name: &str) -> bool {
+    let before = text[..index].chars().next_back();
+    let after = text[index + name.len()..].chars().next();
+    let starts = matches!(before, None | Some('/' | '"' | '\'' | '(' | '=' | ' '));
+    let ends = matches!(after, None | Some('"' | '\'' | ')' | '#' | '?' | ' '));
+    starts && ends
+}
+
+/// Replace every reference to the file `old_name` with `new_name`
+fn replace_references(text: &str, old_name: &str, new_name: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut last = 0;
+    for (index, _) in text.match_indices(old_name) {
+        if is_reference(text, index, old_name) {
+            replaced.push_str(&text[last..index]);
+            replaced.push_str(new_name);
+            last = index + old_name.len();
+        }
+    }
+    replaced.push_str(&text[last..]);
+    replaced
+}
+
+/// Fix the media type of every manifest item referring to a converted image
+fn update_media_types(opf: &str, renamed: &[Renamed]) -> String {
+    let mut updated = String::with_capacity(opf.len());
+    let mut rest = opf;
+    while let Some(start) = rest.find("<item ") {
+        let Some(length) = rest[start..].find('>') else {
+            break;
+        };
+        let (before, tag) = (&rest[..start], &rest[start..start + length]);
+        updated.push_str(before);
+        let image = renamed.iter().find(|image| {
+            tag.match_indices(&image.new_name)
+                .any(|(index, _)| is_reference(tag, index, &image.new_name))
+        });
+        match (image, tag.find("media-type=\"")) {
+            (Some(image), Some(attribute)) => {
+                let value = attribute + "media-type=\"".len();
+                let value_end = value + tag[value..].find('"').unwrap_or(0);
+                updated.push_str(&tag[..value]);
+                updated.push_str(media_type(image.format));
+                updated.push_str(&tag[value_end..]);
+            }
+            _ => updated.push_str(tag),
+        }
+        rest = &rest[start + length..];
+    }
+    updated.push_str(rest);
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_references() {
+        let html = r#"<img src="../images/p1.jpg"/><img src="p1.jpg#x"/><img src="ap1.jpg"/>"#;
+        assert_eq!(
+            replace_references(html, "p1.jpg", "p1.avif"),
+            r#"<img src="../images/p1.avif"/><img src="p1.avif#x"/><img src="ap1.jpg"/>"#
+        );
+        let css = "body { background: url(p1.jpg) }";
+        assert_eq!(
+            replace_references(css, "p1.jpg", "p1.avif"),
+            "body { background: url(p1.avif) }"
+        );
+    }
+
+    #[test]
+    fn test_update_media_types() {
+        let opf = r#"<manifest>
+<item id="p1" href="images/p1.avif" media-type="image/jpeg"/>
+<item id="p2" href="images/p2.png" media-type="image/png"/>
+<item id="c" href="p1.xhtml" media-type="application/xhtml+xml"/>
+</manifest>"#;
+        let renamed = [Renamed {
+            old_name: "p1.jpg".to_string(),
+            new_name: "p1.avif".to_string(),
+            format: ImageFormat::Avif,
+        }];
+        assert_eq!(
+            update_media_types(opf, &renamed),
+            r#"<manifest>
+<item id="p1" href="images/p1.avif" media-type="image/avif"/>
+<item id="p2" href="images/p2.png" media-type="image/png"/>
+<item id="c" href="p1.xhtml" media-type="application/xhtml+xml"/>
+</manifest>"#
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use std::fmt;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+use crate::ImageFormat;
+
+/// Archives to convert as given by a JSON job spec
+///
+/// ```json
+/// {
+///   "target": "avif",
+///   "quality": 80,
+///   "archives": ["a.cbz", { "path": "b.cbz", "target": "webp", "quality": 75 }]
+/// }
+/// ```
+///
+/// The top level `target` and `quality` apply to all archives without their own.
+#[derive(Debug, PartialEq)]
+pub struct JobSpec {
+    pub archives: Vec<ArchiveSpec>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ArchiveSpec {
+    pub path: PathBuf,
+    pub target: Option<ImageFormat>,
+    /// Quality of the encoder for the target format
+    pub quality: Option<u8>,
+}
+
+impl JobSpec {
+    /// Read the spec, checking every archive in it for existence
+    ///
+    /// Relative paths of archives are taken from the directory of the spec.
+    pub fn read(path: &Path) -> Result<JobSpec, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("could not read {path:?}: {e}"))?;
+        let mut spec = text.parse::<JobSpec>()?;
+        let spec_dir = path.parent().unwrap_or(Path::new(""));
+        for (index, archive) in spec.archives.iter_mut().enumerate() {
+            archive.path = spec_dir.join(&archive.path);
+            if !archive.path.exists() {
+                return Err(format!(
+                    "archives[{index}].path: {:?} does not exist",
+                    archive.path
+                ));
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl std::str::FromStr for JobSpec {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut deserializer = serde_json::Deserializer::from_str(text);
+        let Object(spec): Object<SpecFile> = serde_path_to_error::deserialize(&mut deserializer)
+            .map_err(|e| match e.path().to_string().as_str() {
+                "." => e.inner().to_string(),
+                path => format!("{path}: {}", e.inner()),
+            })?;
+        deserializer.end().map_err(|e| e.to_string())?;
+        let archives = spec
+            .archives
+            .into_iter()
+            .map(|ArchiveEntry(entry)| ArchiveSpec {
+                path: entry.path,
+                target: entry.target.or(spec.target),
+                quality: entry.quality.or(spec.quality),
+            })
+            .collect();
+        Ok(JobSpec { archives })
+    }
+}
+
+/// The spec as written, before the top level settings are applied to the archives
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecFile {
+    archives: Vec<ArchiveEntry>,
+    #[serde(default, deserialize_with = "target")]
+    target: Option<ImageFormat>,
+    #[serde(default, deserialize_with = "quality")]
+    quality: Option<u8>,
+}
+
+/// An archive of the spec, either just its path or an object with its own settings
+struct ArchiveEntry(ArchiveFields);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArchiveFields {
+    path: PathBuf,
+    #[serde(default, deserialize_with = "target")]
+    target: Option<ImageFormat>,
+    #[serde(default, deserialize_with = "quality")]
+    quality: Option<u8>,
+}
+
+impl<'de> Deserialize<'de> for ArchiveEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = ArchiveEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a path or an object")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> Result<ArchiveEntry, E> {
+                Ok(ArchiveEntry(ArchiveFields {
+                    path: PathBuf::from(path),
+                    target: None,
+                    quality: None,
+                }))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ArchiveEntry, A::Error> {
+                ArchiveFields::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(ArchiveEntry)
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+/// Reads `T` from an object only, derived structs would take a list of their fields as well
+struct Object<T>(T);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Object<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ObjectVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for ObjectVisitor<T> {
+            type Value = T;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<T, A::Error> {
+                T::deserialize(de::value::MapAccessDeserializer::new(map))
+            }
+        }
+
+        deserializer
+            .deserialize_map(ObjectVisitor(PhantomData))
+            .map(Object)
+    }
+}
+
+fn target<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ImageFormat>, D::Error> {
+    let formats = "jpeg, png, avif, jxl or webp";
+    let value = String::deserialize(deserializer)?;
+    ImageFormat::from_str(&value, true)
+        .map(Some)
+        .map_err(|_| de::Error::custom(format!("'{value}' is not one of {formats}")))
+}
+
+fn quality<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u8>, D::Error> {
+    match f64::deserialize(deserializer) {
+        Ok(value) if value.fract() == 0.0 && (1.0..=100.0).contains(&value) => {
+            Ok(Some(value as u8))
+        }
+        _ => Err(de::Error::custom("expected a whole number from 1 to 100")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TempDir;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = r#"{
+            "target": "avif",
+            "quality": 80,
+            "archives": [
+                "a.cbz",
+                { "path": "dir/b \"2\".cbz", "target": "WebP", "quality": 75 },
+                { "path": "c.cbt", "target": "jxl" }
+            ]
+        }"#
+        .parse::<JobSpec>()
+        .unwrap();
+        let archive = |path: &str, target, quality| ArchiveSpec {
+            path: PathBuf::from(path),
+            target: Some(target),
+            quality,
+        };
+        assert_eq!(
+            spec.archives,
+            [
+                archive("a.cbz", ImageFormat::Avif, Some(80)),
+                archive("dir/b \"2\".cbz", ImageFormat::Webp, Some(75)),
+                archive("c.cbt", ImageFormat::Jxl, Some(80)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_spec() {
+        let dir = TempDir::new("read_spec");
+        fs::create_dir_all(dir.join("books")).unwrap();
+        fs::write(dir.join("books/a.cbz"), b"").unwrap();
+        let spec_path = dir.join("spec.json");
+        let absolute = dir.join("books/a.cbz");
+        let spec = format!(r#"{{"archives": ["books/a.cbz", {absolute:?}]}}"#);
+        fs::write(&spec_path, spec).unwrap();
+        let paths = JobSpec::read(&spec_path)
+            .unwrap()
+            .archives
+            .into_iter()
+            .map(|archive| archive.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, [absolute.clone(), absolute]);
+
+        fs::write(&spec_path, r#"{"archives": ["a.cbz"]}"#).unwrap();
+        assert_eq!(
+            JobSpec::read(&spec_path).unwrap_err(),
+            format!("archives[0].path: {:?} does not exist", dir.join("a.cbz"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        let error = |spec: &str| spec.parse::<JobSpec>().unwrap_err();
+        assert_eq!(
+            error("[]"),
+            "invalid type: sequence, expected an object at line 1 column 0"
+        );
+        assert_eq!(error("{}"), "missing field `archives` at line 1 column 2");
+        assert_eq!(
+            error(r#"{"archives": [1]}"#),
+            "archives[0]: invalid type: integer `1`, expected a path or an object at line 1 column 15"
+        );
+        assert_eq!(
+            error(r#"{"archives": ["a.cbz", {"quality": 50}]}"#),
+            "archives[1]: missing field `path` at line 1 column 38"
+        );
+        assert_eq!(
+            error(r#"{"archives": [{"path": "a.cbz", "quality": 101}]}"#),
+            "archives[0].quality: expected a whole number from 1 to 100 at line 1 column 47"
+        );
+        assert_eq!(
+            error(r#"{"target": "gif", "archives": []}"#),
+            "target: 'gif' is not one of jpeg, png, avif, jxl or webp at line 1 column 16"
+        );
+        assert_eq!(
+            error(r#"{"archive": []}"#),
+            "archive: unknown field `archive`, expected one of `archives`, `target`, `quality` \
+             at line 1 column 10"
+        );
+        assert_eq!(
+            error(r#"{"archives": [{"path": "a.cbz", "size": 1}]}"#),
+            "archives[0].size: unknown field `size`, expected one of `path`, `target`, `quality` \
+             at line 1 column 38"
+        );
+        assert_eq!(
+            error("{\n\"archives\": [\"a.cbz\"\n"),
+            "archives: EOF while parsing a list at line 3 column 0"
+        );
+        assert_eq!(
+            error(r#"{"archives": []} x"#),
+            "trailing characters at line 1 column 18"
+        );
+        // nesting deeper than a spec ever does is refused right away
+        let nested = "[".repeat(100_000);
+        assert!(error(&format!(r#"{{"archives": {nested}"#)).starts_with("archives[0]: "));
+    }
+}
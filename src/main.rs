@@ -1,22 +1,37 @@
+mod comicinfo;
+mod container;
+mod dedupe;
+mod dimensions;
+mod epub;
+mod jobspec;
+mod output;
+mod pages;
 mod spawn;
+mod timings;
 
-use std::collections::VecDeque;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs::{self, File};
-use std::io::{BufRead, Read, Write};
-use std::path::PathBuf;
-use std::process::{exit, Child, Command, Stdio};
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock, PoisonError, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use clap::Parser;
-use log::{debug, error, info, trace};
-use signal_hook::{
-    consts::{SIGCHLD, SIGINT},
-    iterator::Signals,
-};
+use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use signal_hook::consts::SIGINT;
 use thiserror::Error;
 use walkdir::WalkDir;
-use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use container::{Container, ContainerWriter, EntryModes};
+use jobspec::JobSpec;
+use pages::PageRanges;
+use spawn::{Failure, Invocation, JpegTool, PngTool, Tool};
 
 #[derive(Error, Debug)]
 enum ConversionError {
@@ -32,16 +47,39 @@ enum ConversionError {
     Interrupt,
     #[error("Error during extraction: {0}")]
     ExtractionError(String),
-    #[error("child process finished abnormally for '{0}'")]
-    AbnormalExit(PathBuf),
+    #[error("not enough space to extract '{0}', {1} bytes are needed but {2} are free")]
+    NotEnoughSpace(PathBuf, u64, u64),
+    #[error("the entry '{0}' gets too long a path when extracted to '{1}'")]
+    PathTooLong(PathBuf, PathBuf),
+    #[error("could not list the contents of '{0}'")]
+    ListingFailed(PathBuf),
+    #[error("could not create '{0}': {1}")]
+    CannotCreate(PathBuf, #[source] io::Error),
+    #[error("'{0}' is animated, all frames but the first would be lost")]
+    Animated(PathBuf),
+    #[error("'{0}' is transparent, which Jpeg cannot keep")]
+    Transparent(PathBuf),
+    #[error("'{0}' turned out to be {1}, which is not to be converted")]
+    Unselected(PathBuf, ImageFormat),
+    #[error("'{0}' has {1} pixels, more than the {2} allowed")]
+    TooManyPixels(PathBuf, u64, u64),
+    #[error("converted archive '{0}' could not be read back")]
+    VerificationFailed(PathBuf),
+    #[error("converted archive '{0}' holds {2} images instead of {1}")]
+    ImageCountMismatch(PathBuf, usize, usize),
+    #[error("child process finished abnormally for '{0}' ({1})")]
+    AbnormalExit(PathBuf, Failure),
     #[error("could not start process with the program '{0}'")]
     SpawnFailure(String),
+    #[error("'{0}' needs tools that are not installed: {1}")]
+    MissingTools(PathBuf, String),
     #[error("unspecific error '{0}'")]
     Unspecific(String),
 }
 use ConversionError::*;
 
-#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+#[derive(clap::ValueEnum, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
 enum ImageFormat {
     #[default]
     Jpeg,
@@ -52,6 +90,30 @@ enum ImageFormat {
 }
 use ImageFormat::*;
 
+/// Longest file name most filesystems allow, in bytes
+const NAME_MAX: usize = 255;
+
+/// How often running children are checked for completion
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Least time between two progress lines of `--progress-style plain`
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Set by a second SIGINT within the grace period, running jobs are killed right away
+static STOP_NOW: AtomicBool = AtomicBool::new(false);
+
+/// Shared by the running tools, a retry after running out of memory takes it for itself
+static TOOL_SLOTS: RwLock<()> = RwLock::new(());
+
+/// Marks intermediate files, placed before the extension that the tools look at
+const INTERMEDIATE_SUFFIX: &str = "cbzin-tmp";
+
+/// Lists every original image and its converted counterpart within dual format archives
+const DUAL_FORMAT_MANIFEST: &str = "dual-format.txt";
+
+/// Runs with `--replace` or `--force` ask for confirmation from this many images on
+const CONFIRMATION_THRESHOLD: usize = 1000;
+
 impl std::fmt::Display for ImageFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -64,30 +126,625 @@ impl std::fmt::Display for ImageFormat {
     }
 }
 
-#[derive(Default, Clone, Copy, Debug, PartialEq)]
-enum JobStatus {
-    Init,
-    Decoding,
-    Encoding,
-    #[default]
-    Done,
+impl ImageFormat {
+    /// Rough peak memory of a tool writing a large page in this format
+    fn memory_estimate(self) -> u64 {
+        match self {
+            Jxl => 1 << 30,
+            Avif => 768 << 20,
+            Webp => 256 << 20,
+            Jpeg | Png => 128 << 20,
+        }
+    }
+
+    /// File extensions images of this format are recognized by, the first one is the default
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Jpeg => &["jpeg", "jpg"],
+            Png => &["png"],
+            Avif => &["avif", "avifs"],
+            Jxl => &["jxl"],
+            Webp => &["webp"],
+        }
+    }
+
+    /// Extension of files written in this format, as configured or the default one
+    fn ext_for(self, config: &ConversionConfig) -> &str {
+        config
+            .extensions
+            .iter()
+            .find(|(format, _)| *format == self)
+            .map_or(self.extensions()[0], |(_, ext)| ext)
+    }
+}
+
+/// Settings for the conversion of archives and every single image within
+#[derive(Clone, Debug, Default)]
+struct ConversionConfig {
+    target: ImageFormat,
+    force: bool,
+    /// Encode images already in the target format again, at the configured quality
+    recompress: bool,
+    /// Replace the original archive with the converted one
+    replace: bool,
+    /// Size the converted archive may have at most, split into several parts otherwise
+    split_size: Option<u64>,
+    /// Leave the first page in reading order as it is
+    keep_cover: bool,
+    /// Quality of the encoder for the target format for the first `cover_pages` pages
+    cover_quality: Option<u8>,
+    /// Number of pages from the start, in reading order, that count as covers
+    cover_pages: usize,
+    /// Longest side an image may have, larger ones are scaled down
+    max_dimension: Option<u32>,
+    /// Drop all color information
+    grayscale: bool,
+    /// Transform the colors of all images to sRGB
+    to_srgb: bool,
+    /// ICC profile to transform the colors to instead of the plain sRGB colorspace
+    srgb_profile: Option<PathBuf>,
+    /// Report identical images after the conversion
+    dedupe: bool,
+    /// Convert archives again when they changed after their last conversion
+    incremental: bool,
+    /// Look for archives in subdirectories of the given directory as well
+    recursive: bool,
+    /// Directories not to look into for archives, by name or by path
+    exclude_dirs: Vec<PathBuf>,
+    /// Take archives named like the output of any conversion from a directory as well
+    include_converted: bool,
+    /// Convert converted archives to another format, naming them after their original
+    retarget: bool,
+    /// Print the command line of every tool invocation
+    print_commands: bool,
+    /// Only print the invocations, no tool is run and no archive written
+    dry_run: bool,
+    /// Print the path of every written archive
+    print_output: bool,
+    /// What happens when an image cannot be converted
+    on_error: OnError,
+    /// Guess where a tool for inspecting images is missing, instead of failing
+    best_effort: bool,
+    /// Skip archives that need a tool which is not installed, instead of failing on them
+    skip_unsatisfiable: bool,
+    /// Write the pages in the order of the archive's ComicInfo.xml
+    use_comic_info: bool,
+    /// Leave out pages the ComicInfo.xml marks as deleted or advertisements
+    drop_deleted_pages: bool,
+    /// Write the archive anew without converting any image
+    repack_only: bool,
+    /// Pixels an image may have at most, larger ones are refused before any tool sees them
+    max_pixels: Option<u64>,
+    /// Keep the original images beside the converted ones
+    dual_format: bool,
+    /// Convert images with the same content only once within an archive
+    reuse_identical: bool,
+    /// Leave the extraction directory of a failed archive behind
+    keep_temp_on_error: bool,
+    /// Leave the files between the steps of a conversion beside the images
+    keep_intermediates: bool,
+    /// Convert animated images to a still one of their first frame
+    flatten_animated: bool,
+    /// Color to fill transparent areas of Png images with when converting them to Jpeg
+    alpha_background: Option<String>,
+    /// Describe failed archives with the whole error instead of its message
+    verbose_errors: bool,
+    /// How long running images may go on after an interrupt, no further ones are started
+    shutdown_grace: Option<Duration>,
+    /// How long a tool may take for a single step of a conversion before it counts as hung
+    tool_timeout: Option<Duration>,
+    /// How long a tool inspecting an image may take before it counts as hung
+    probe_timeout: Option<Duration>,
+    /// Formats to write an archive of their own for, besides the one of `target`
+    extra_targets: Vec<ImageFormat>,
+    /// Bytes that have to stay free after extracting an archive
+    min_free_space: u64,
+    /// Appended to the name of converted archives instead of the target format
+    suffix: Option<String>,
+    /// Detect the format of images from their content instead of their extension
+    probe_format: bool,
+    /// Write the outcome of every image to a file beside the converted archive
+    archive_log: bool,
+    /// Pages in reading order to convert, all of them if not set
+    pages: Option<PageRanges>,
+    /// Directory within the archives to convert images in, all of them if not set
+    subpath: Option<PathBuf>,
+    /// Formats of the images to convert, all of them if empty
+    only: Vec<ImageFormat>,
+    /// Formats of the images to leave as they are, even when forced
+    exclude: Vec<ImageFormat>,
+    /// Permissions of the entries within the converted archive
+    modes: EntryModes,
+    /// Deflate the entries of zip archives that are not images
+    compress_metadata: bool,
+    /// Leave files that are no images out of the converted archive
+    drop_unconvertible: bool,
+    /// Keep files of the operating system like `__MACOSX/` and `.DS_Store` in the archive
+    keep_junk: bool,
+    /// Extensions of written images that differ from the default of their format
+    extensions: Vec<(ImageFormat, String)>,
+    /// Tool for converting Jpeg to Png
+    png_tool: PngTool,
+    /// Optimize every converted Png image with oxipng
+    optimize_png: bool,
+    /// Tool for converting Png to Jpeg
+    jpeg_tool: JpegTool,
+    /// Environment variables set for every tool converting images
+    encoder_env: Vec<(String, String)>,
+    quality: Quality,
+}
+
+/// Quality settings of the lossy encoders, from 1 to 100
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Quality {
+    jpeg: u8,
+    /// Jpeg written by avifdec, which has its own default unless a Jpeg quality is given
+    avifdec: u8,
+    avif: u8,
+    webp: u8,
+}
+
+impl Quality {
+    /// Use `quality` for encoding to `format`, formats without a quality setting ignore it
+    fn set(&mut self, format: ImageFormat, quality: u8) {
+        match format {
+            Jpeg => (self.jpeg, self.avifdec) = (quality, quality),
+            Avif => self.avif = quality,
+            Webp => self.webp = quality,
+            Png | Jxl => (),
+        }
+    }
+}
+
+impl Default for Quality {
+    fn default() -> Self {
+        Quality {
+            jpeg: 92,
+            avifdec: 80,
+            avif: 88,
+            webp: 90,
+        }
+    }
+}
+
+impl ConversionConfig {
+    /// A config for every target to convert to, starting with `target`
+    fn per_target(&self) -> Vec<ConversionConfig> {
+        let mut configs = vec![self.clone()];
+        configs.extend(self.extra_targets.iter().map(|&target| ConversionConfig {
+            target,
+            ..self.clone()
+        }));
+        configs
+            .iter_mut()
+            .for_each(|config| config.extra_targets.clear());
+        configs
+    }
+
+    /// Whether an image operation is requested that only magick can do
+    fn needs_magick(&self) -> bool {
+        self.max_dimension.is_some() || self.grayscale || self.to_srgb
+    }
+
+    /// Whether the file goes into the converted archive
+    fn keeps_file(&self, container: Container, name: &Path) -> bool {
+        if (!self.keep_junk && is_junk(name))
+            || target_override(name).is_some()
+            || (self.keep_intermediates && is_intermediate(name))
+        {
+            return false;
+        }
+        // the documents of a book are needed to read it
+        !self.drop_unconvertible || container == Container::Epub || image_format(name).is_some()
+    }
+
+    /// Whether the image at this path within the archive is to be converted at all
+    fn selects_path(&self, image_path: &Path) -> bool {
+        self.subpath
+            .as_ref()
+            .is_none_or(|subpath| image_path.starts_with(subpath))
+    }
+
+    /// Whether images of this format are to be converted at all
+    fn selects(&self, format: ImageFormat) -> bool {
+        (self.only.is_empty() || self.only.contains(&format)) && !self.exclude.contains(&format)
+    }
+
+    /// Whether an image is converted from `from` to `to`, as chosen by --only and --force
+    fn converts(&self, from: ImageFormat, to: ImageFormat) -> bool {
+        (self.force || !convert_only_when_forced(from, to)) && self.selects(from)
+    }
+
+    /// Added to the name of converted archives, like `.avif` without a custom suffix
+    fn converted_suffix(&self) -> String {
+        match &self.suffix {
+            Some(suffix) => suffix.clone(),
+            None if self.repack_only => ".repacked".to_string(),
+            None => format!(".{}", self.target.ext_for(self)),
+        }
+    }
+
+    /// Ending of converted archives, like `.avif.cbz` without a custom suffix
+    fn converted_ending(&self, container: Container) -> String {
+        format!("{}.{}", self.converted_suffix(), container.extension())
+    }
+
+    /// Name of the archive at `path` that the converted one is named after
+    fn source_name<'a>(&self, path: &'a Path) -> &'a str {
+        let stem = path.file_stem().unwrap().to_str().unwrap();
+        match self.retarget {
+            // the format of the earlier conversion gives way to the new one
+            true => strip_format_suffix(stem, self).unwrap_or(stem),
+            false => stem,
+        }
+    }
+
+    /// Where the converted archive of the one at `path` is written to
+    fn converted_path(&self, path: &Path, container: Container) -> PathBuf {
+        let name = self.source_name(path);
+        path.with_file_name(format!("{name}{}", self.converted_ending(container)))
+    }
+
+    /// Where a part of a converted archive split by size is written to, counting from 1
+    fn part_path(&self, path: &Path, container: Container, part: usize) -> PathBuf {
+        let name = self.source_name(path);
+        let suffix = self.converted_suffix();
+        path.with_file_name(format!(
+            "{name}{suffix}.part{part:02}.{}",
+            container.extension()
+        ))
+    }
+
+    /// Whether the archive at `path` was written by this conversion, whatever its extension,
+    /// so that converting it again does not add the suffix twice
+    fn is_converted_archive(&self, path: &Path) -> bool {
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            return false;
+        };
+        stem.ends_with(&self.converted_suffix()) || self.is_converted_part(path)
+    }
+
+    /// Whether the archive at `path` is a part of a converted archive split by size
+    fn is_converted_part(&self, path: &Path) -> bool {
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            return false;
+        };
+        let Some((name, number)) = stem.rsplit_once(".part") else {
+            return false;
+        };
+        let is_number = !number.is_empty() && number.chars().all(|c| c.is_ascii_digit());
+        is_number && name.ends_with(&self.converted_suffix())
+    }
+}
+
+/// A single invocation of an external tool within a conversion
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    /// Convert with magick, which also applies image operations
+    ///
+    /// Planned for conversions between Jpeg and Png, other formats only come up when magick
+    /// steps in for a dedicated tool that failed.
+    Convert(ImageFormat, ImageFormat),
+    /// Encode a Jpeg or Png to one of the newer formats
+    Encode(ImageFormat, ImageFormat),
+    /// Decode one of the newer formats to Jpeg or Png
+    Decode(ImageFormat, ImageFormat),
+    /// Compress an image better without changing its format
+    Optimize(ImageFormat),
 }
 
 struct ConversionJob {
-    status: JobStatus,
     image_path: PathBuf,
     current: ImageFormat,
-    intermediate: Option<ImageFormat>,
     target: ImageFormat,
+    steps: Vec<Step>,
+    used_fallback: bool,
+    /// Tools that finished their step successfully, in order
+    tools: Vec<Tool>,
     child: Option<Child>,
+    /// Images with the same content and target, which get a copy of the converted image
+    identical: Vec<PathBuf>,
+    /// Quality of the encoder for the target format, instead of the configured one
+    quality: Option<u8>,
+}
+
+/// Final state of a single image after a conversion run
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ImageOutcome {
+    Converted,
+    /// Converted, but only after magick took over from the dedicated tool
+    Fallback,
+    Skipped,
+    Failed,
+}
+
+impl std::fmt::Display for ImageOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ImageOutcome::Converted => "converted",
+            ImageOutcome::Fallback => "converted with magick",
+            ImageOutcome::Skipped => "skipped",
+            ImageOutcome::Failed => "failed",
+        };
+        write!(f, "{description}")
+    }
+}
+
+/// Receives progress and the outcome of every image within an archive
+///
+/// This keeps reporting separate from the execution of the conversion jobs, so the CLI and
+/// anyone embedding the conversion can present results however they like.
+trait ConversionReporter {
+    /// Called once before any image of the archive is handled
+    fn start(&mut self, _cbz_path: &Path, _images: usize) {}
+
+    /// Called once for every image, with the path relative to the archive root
+    fn report(
+        &mut self,
+        relative_path: &Path,
+        from: ImageFormat,
+        to: ImageFormat,
+        outcome: ImageOutcome,
+    );
+
+    /// Called for every converted image with its size in bytes before and after
+    fn sizes(&mut self, _from: ImageFormat, _to: ImageFormat, _input: u64, _output: u64) {}
+}
+
+/// Reporter used by the CLI, logs every image together with the overall progress
+///
+/// Without `per_image` a line is only logged for every finished archive, converted images
+/// are logged at debug level then. The `style` decides on any progress lines beyond that.
+#[derive(Default)]
+struct LogReporter {
+    per_image: bool,
+    style: ProgressStyle,
+    /// When the last progress line of the plain style was logged
+    last_progress: Option<Instant>,
+    cbz_path: PathBuf,
+    total: usize,
+    handled: usize,
+    converted: usize,
+    fallbacks: usize,
+    skipped: usize,
+    failed: usize,
+    /// Images and their bytes before and after for every pair of formats, in order of appearance
+    sizes: Vec<((ImageFormat, ImageFormat), FormatSizes)>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct FormatSizes {
+    images: usize,
+    input: u64,
+    output: u64,
+}
+
+impl LogReporter {
+    /// Print how all images of the run turned out
+    fn summary(&self) {
+        output::stdout(format!(
+            "{} images converted, {} skipped, {} failed",
+            self.converted, self.skipped, self.failed
+        ));
+        if self.fallbacks > 0 {
+            output::stdout(format!(
+                "{} images required the magick fallback",
+                self.fallbacks
+            ));
+        }
+        for line in self.size_breakdown() {
+            output::stdout(line);
+        }
+    }
+
+    /// How much every pair of formats saved over all images converted between them
+    fn size_breakdown(&self) -> Vec<String> {
+        self.sizes
+            .iter()
+            .filter(|(_, sizes)| sizes.input > 0)
+            .map(|((from, to), sizes)| {
+                let ratio = sizes.output as f64 / sizes.input as f64;
+                let change = match ratio <= 1.0 {
+                    true => format!("saved {:.0}%", (1.0 - ratio) * 100.0),
+                    false => format!("grew by {:.0}%", (ratio - 1.0) * 100.0),
+                };
+                format!(
+                    "{from} -> {to} {change} ({} -> {} over {} images)",
+                    human_size(sizes.input),
+                    human_size(sizes.output),
+                    sizes.images
+                )
+            })
+            .collect()
+    }
+
+    /// A reporter for a single archive converted alongside others, logging like this one
+    fn for_archive(&self) -> LogReporter {
+        LogReporter {
+            per_image: self.per_image,
+            style: self.style,
+            ..Default::default()
+        }
+    }
+
+    /// Add the images of an archive reported on its own
+    fn merge(&mut self, other: &LogReporter) {
+        self.converted += other.converted;
+        self.fallbacks += other.fallbacks;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        for ((from, to), other_sizes) in other.sizes.iter() {
+            match self
+                .sizes
+                .iter_mut()
+                .find(|(pair, _)| pair == &(*from, *to))
+            {
+                Some((_, sizes)) => {
+                    sizes.images += other_sizes.images;
+                    sizes.input += other_sizes.input;
+                    sizes.output += other_sizes.output;
+                }
+                None => self.sizes.push(((*from, *to), *other_sizes)),
+            }
+        }
+    }
+}
+
+impl ConversionReporter for LogReporter {
+    fn start(&mut self, cbz_path: &Path, images: usize) {
+        self.cbz_path = cbz_path.to_path_buf();
+        self.total = images;
+        self.handled = 0;
+    }
+
+    fn report(
+        &mut self,
+        relative_path: &Path,
+        from: ImageFormat,
+        to: ImageFormat,
+        outcome: ImageOutcome,
+    ) {
+        self.handled += 1;
+        match outcome {
+            ImageOutcome::Converted => self.converted += 1,
+            ImageOutcome::Fallback => {
+                self.converted += 1;
+                self.fallbacks += 1;
+            }
+            ImageOutcome::Skipped => self.skipped += 1,
+            ImageOutcome::Failed => self.failed += 1,
+        }
+        let progress = format!("[{}/{}]", self.handled, self.total);
+        match outcome {
+            ImageOutcome::Converted if self.per_image => {
+                info!("{progress} converted {relative_path:?} ({from} -> {to})")
+            }
+            ImageOutcome::Converted => {
+                debug!("{progress} converted {relative_path:?} ({from} -> {to})")
+            }
+            ImageOutcome::Fallback => {
+                warn!("{progress} converted {relative_path:?} ({from} -> {to}) with magick")
+            }
+            ImageOutcome::Skipped => debug!("{progress} skipped {relative_path:?} ({from})"),
+            ImageOutcome::Failed => {
+                error!("{progress} failed on {relative_path:?} ({from} -> {to})")
+            }
+        }
+        let is_done = self.handled == self.total;
+        let log_progress = match self.style {
+            ProgressStyle::Lines => !self.per_image && is_done,
+            ProgressStyle::Plain => {
+                is_done
+                    || self
+                        .last_progress
+                        .is_none_or(|last| last.elapsed() >= PLAIN_PROGRESS_INTERVAL)
+            }
+            ProgressStyle::None => false,
+        };
+        if log_progress {
+            self.last_progress = Some(Instant::now());
+            match is_done {
+                true => info!("{progress} handled all images of {:?}", self.cbz_path),
+                false => info!("{progress} handled images of {:?}", self.cbz_path),
+            }
+        }
+    }
+
+    fn sizes(&mut self, from: ImageFormat, to: ImageFormat, input: u64, output: u64) {
+        let sizes = match self.sizes.iter_mut().find(|(pair, _)| *pair == (from, to)) {
+            Some((_, sizes)) => sizes,
+            None => {
+                self.sizes.push(((from, to), FormatSizes::default()));
+                &mut self.sizes.last_mut().unwrap().1
+            }
+        };
+        sizes.images += 1;
+        sizes.input += input;
+        sizes.output += output;
+    }
 }
 
 struct WorkUnit {
     cbz_path: PathBuf,
+    container: Container,
+    root_dir: PathBuf,
     job_queue: VecDeque<ConversionJob>,
-    jobs_in_process: Vec<ConversionJob>,
-    target_format: ImageFormat,
+    skipped: Vec<(PathBuf, ImageFormat)>,
+    config: ConversionConfig,
     workers: usize,
+    /// Only remove the extraction directory if we were the ones creating it
+    extracted: bool,
+    /// Every image in reading order, as listed in the archive
+    pages: Vec<PathBuf>,
+    /// The ComicInfo.xml of the archive, if it has one
+    comic_info: Option<PathBuf>,
+    /// Position of the pages in the order of the ComicInfo.xml, by path without extension
+    page_order: HashMap<PathBuf, usize>,
+}
+
+impl Step {
+    fn tool(self) -> Tool {
+        match self {
+            Step::Convert(..) => Tool::Magick,
+            Step::Encode(_, Avif) => spawn::avif_encoder(),
+            Step::Encode(_, Jxl) => Tool::Cjxl,
+            Step::Encode(_, Webp) => Tool::Cwebp,
+            Step::Encode(_, Jpeg) => Tool::Cjpeg,
+            Step::Optimize(_) => Tool::Oxipng,
+            Step::Decode(Avif, _) => Tool::Avifdec,
+            Step::Decode(Jxl, _) => Tool::Djxl,
+            Step::Decode(Webp, _) => Tool::Dwebp,
+            Step::Encode(from, to) | Step::Decode(from, to) => {
+                unreachable!("no tool for {from} to {to}")
+            }
+        }
+    }
+
+    fn from(self) -> ImageFormat {
+        match self {
+            Step::Convert(from, _) | Step::Encode(from, _) | Step::Decode(from, _) => from,
+            Step::Optimize(format) => format,
+        }
+    }
+
+    fn to(self) -> ImageFormat {
+        match self {
+            Step::Convert(_, to) | Step::Encode(_, to) | Step::Decode(_, to) => to,
+            Step::Optimize(format) => format,
+        }
+    }
+
+    fn invocation(
+        self,
+        input_path: &Path,
+        output_path: &Path,
+        config: &ConversionConfig,
+    ) -> Result<Invocation, ConversionError> {
+        let invocation = match self {
+            Step::Convert(_, to) => spawn::convert_with_magick(input_path, output_path, to, config),
+            Step::Encode(_, Avif) => {
+                spawn::encode_avif(input_path, output_path, config.quality.avif)
+            }
+            Step::Encode(from, Jxl) => spawn::encode_jxl(input_path, output_path, from),
+            Step::Encode(_, Webp) => {
+                spawn::encode_webp(input_path, output_path, config.quality.webp)
+            }
+            Step::Encode(Png, Jpeg) => {
+                spawn::encode_jpeg(input_path, output_path, config.quality.jpeg)
+            }
+            Step::Optimize(Png) => spawn::optimize_png(input_path, output_path),
+            Step::Decode(Avif, Png) => spawn::decode_avif_to_png(input_path, output_path),
+            Step::Decode(Avif, Jpeg) => {
+                spawn::decode_avif_to_jpeg(input_path, output_path, config.quality.avifdec)
+            }
+            Step::Decode(Jxl, Png) => spawn::decode_jxl_to_png(input_path, output_path),
+            Step::Decode(Jxl, Jpeg) => spawn::decode_jxl_to_jpeg(input_path, output_path),
+            Step::Decode(Webp, Png) => spawn::decode_webp(input_path, output_path),
+            Step::Encode(from, to) | Step::Decode(from, to) => return Err(NotSupported(from, to)),
+            Step::Optimize(format) => return Err(NotSupported(format, format)),
+        };
+        Ok(invocation.with_env(&config.encoder_env))
+    }
 }
 
 impl ConversionJob {
@@ -96,305 +753,491 @@ impl ConversionJob {
         from: ImageFormat,
         to: ImageFormat,
     ) -> Result<ConversionJob, ConversionError> {
-        let result = match (from, to) {
+        match (from, to) {
             (a, b) if a == b => Err(NotSupported(from, to)),
             (_, Jpeg | Png | Avif | Jxl | Webp) => Ok(()),
-        };
-        if let Err(e) = result {
-            return Err(e);
-        }
+        }?;
 
         Ok(ConversionJob {
-            status: JobStatus::Init,
             image_path,
             current: from,
-            intermediate: None,
             target: to,
+            steps: vec![],
+            used_fallback: false,
+            tools: vec![],
             child: None,
+            identical: vec![],
+            quality: None,
         })
     }
 
-    fn on_init(&mut self) -> Result<JobStatus, ConversionError> {
-        let next_status = match (self.current, self.target) {
-            (Jpeg, to @ Png) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::convert_jpeg_to_png(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Png, to @ Jpeg) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::convert_png_to_jpeg(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Jpeg | Png, to @ Avif) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::encode_avif(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Jpeg | Png, to @ Jxl) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::encode_jxl(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Jpeg | Png, to @ Webp) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::encode_webp(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Avif, to @ Jpeg) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::decode_avif_to_jpeg(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Avif, to @ Png) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::decode_avif_to_png(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Jxl, to @ Jpeg) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::decode_jxl_to_jpeg(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Jxl, to @ Png) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::decode_jxl_to_png(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Webp, to @ Png) => {
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::decode_webp(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (Avif, Jxl | Webp) => {
-                self.intermediate = Some(Png);
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(Png.to_string());
-                let child = spawn::decode_avif_to_png(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Decoding
-            }
-            (Jxl, Avif | Webp) => {
-                let input_path = &self.image_path;
-                let child = if jxl_is_compressed_jpeg(&self.image_path)? {
-                    self.intermediate = Some(Jpeg);
-                    let output_path = self.image_path.with_extension(Jpeg.to_string());
-                    spawn::decode_jxl_to_jpeg(input_path, &output_path)?
-                } else {
-                    self.intermediate = Some(Png);
-                    let output_path = self.image_path.with_extension(Png.to_string());
-                    spawn::decode_jxl_to_png(input_path, &output_path)?
-                };
-                self.child = Some(child);
-                JobStatus::Decoding
-            }
-            (Webp, Jpeg | Avif | Jxl) => {
-                self.intermediate = Some(Png);
-                let input_path = &self.image_path;
-                let output_path = self.image_path.with_extension(Png.to_string());
-                let child = spawn::decode_webp(input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Decoding
-            }
-            (Jpeg, Jpeg) => JobStatus::Done,
-            (Png, Png) => JobStatus::Done,
-            (Avif, Avif) => JobStatus::Done,
-            (Jxl, Jxl) => JobStatus::Done,
-            (Webp, Webp) => JobStatus::Done,
-        };
-        self.status = next_status;
-        Ok(next_status)
+    /// Job for converting to the target, which includes images already in the target format
+    /// when they are to be recompressed
+    fn for_config(
+        image_path: PathBuf,
+        from: ImageFormat,
+        to: ImageFormat,
+        config: &ConversionConfig,
+    ) -> Result<ConversionJob, ConversionError> {
+        if from != to || !config.recompress {
+            return ConversionJob::new(image_path, from, to);
+        }
+        Ok(ConversionJob {
+            image_path,
+            current: from,
+            target: from,
+            steps: vec![],
+            used_fallback: false,
+            tools: vec![],
+            child: None,
+            identical: vec![],
+            quality: None,
+        })
     }
 
-    fn on_decoding(&mut self) -> Result<JobStatus, ConversionError> {
-        let child: &mut Child = match &mut self.child {
-            Some(child) => child,
-            None => unreachable!(),
+    /// Determine the tool invocations needed to get from the current to the target format
+    fn plan(&self, config: &ConversionConfig) -> Result<Vec<Step>, ConversionError> {
+        let jxl_over = match self.inspects_jxl() {
+            true => match jxl_is_compressed_jpeg(&self.image_path, config.probe_timeout) {
+                Ok(true) => Jpeg,
+                Ok(false) => Png,
+                Err(SpawnFailure(_)) if config.best_effort => {
+                    debug!("jxlinfo is missing, convert {:?} over png", self.image_path);
+                    Png
+                }
+                Err(e) => return Err(e),
+            },
+            false => Png,
         };
-        match child.wait() {
-            Ok(status) if !status.success() => {
-                let output = extract_console_output(child);
-                debug!("error on process:\n{output}");
-                return Err(AbnormalExit(self.image_path.clone()));
+        Ok(plan_steps(self.current, self.target, config, jxl_over))
+    }
+
+    /// Whether the plan depends on the Jxl image being a recompressed Jpeg, which takes jxlinfo
+    fn inspects_jxl(&self) -> bool {
+        matches!((self.current, self.target), (Jxl, Avif | Webp))
+    }
+
+    /// The config to convert this image with, with its own quality if it has one
+    fn config_for<'a>(&self, config: &'a ConversionConfig) -> Cow<'a, ConversionConfig> {
+        match self.quality {
+            Some(quality) => {
+                let mut config = config.clone();
+                config.quality.set(self.target, quality);
+                Cow::Owned(config)
+            }
+            None => Cow::Borrowed(config),
+        }
+    }
+
+    /// Run all steps one after the other, only the final image remains afterwards
+    fn run(
+        &mut self,
+        config: &ConversionConfig,
+        cancel: &Cancellation,
+    ) -> Result<(), ConversionError> {
+        let config = &*self.config_for(config);
+        if config.probe_format {
+            self.probe_format()?;
+            if self.current == self.target && !config.recompress {
+                return self.fix_extension(config);
             }
-            Ok(_) => {
-                let output = extract_console_output(child);
-                trace!("process output:\n{output}");
-                ()
+            if !config.converts(self.current, self.target) {
+                return Err(Unselected(self.image_path.clone(), self.current));
             }
-            Err(_) => return Err(Unspecific("error during wait".to_string())),
         }
+        if let Some(limit) = config.max_pixels {
+            self.check_pixels(limit)?;
+        }
+        if self.current == Avif && !config.flatten_animated {
+            self.check_still()?;
+        }
+        if (self.current, self.target) == (Png, Jpeg) && config.alpha_background.is_none() {
+            self.check_opaque()?;
+        }
+        self.steps = self.plan(config)?;
+        debug!("start {self:?}");
+
+        let mut steps = VecDeque::from(self.steps.clone());
+        let mut input_path = self.image_path.clone();
+        let (mut retried, mut run_alone) = (false, false);
+        while let Some(step) = steps.pop_front() {
+            let output_path = self.output_path(step, steps.is_empty(), config);
+            let invocation = step.invocation(&input_path, &output_path, config)?;
+            if config.print_commands {
+                output::stdout(&invocation);
+            }
+            if config.dry_run {
+                input_path = output_path;
+                continue;
+            }
+            // tools writing over a file would change the pristine copy it is linked to as well
+            break_hard_link(&output_path)
+                .map_err(|e| Unspecific(format!("could not copy {output_path:?}: {e}")))?;
+            let start = Instant::now();
+            let result = {
+                let (_shared, _alone);
+                // the retry runs alone, with the memory of all other workers at its disposal
+                match std::mem::take(&mut run_alone) {
+                    true => _alone = TOOL_SLOTS.write().unwrap_or_else(PoisonError::into_inner),
+                    false => _shared = TOOL_SLOTS.read().unwrap_or_else(PoisonError::into_inner),
+                }
+                self.child = Some(invocation.spawn()?);
+                self.wait_for_child(invocation.tool(), cancel, config.tool_timeout)
+                    .and_then(|()| self.check_output(&output_path))
+            };
+            match result {
+                Ok(()) => {
+                    timings::record(step, start);
+                    self.tools.push(invocation.tool());
+                }
+                Err(AbnormalExit(_, Failure::OutOfMemory)) if !retried && !cancel.is_set() => {
+                    // memory is shared with the other workers, it may suffice on its own
+                    warn!(
+                        "{step:?} ran out of memory for {:?}, retry without other tools running",
+                        self.image_path
+                    );
+                    (retried, run_alone) = (true, true);
+                    steps.push_front(step);
+                    continue;
+                }
+                Err(AbnormalExit(_, failure))
+                    if failure != Failure::UnsupportedInput
+                        && !self.used_fallback
+                        && !cancel.is_set()
+                        && spawn::magick_can_write(self.target) =>
+                {
+                    // let magick take over from the input the dedicated tool choked on
+                    let fallback = Step::Convert(step.from(), self.target);
+                    warn!(
+                        "{step:?} failed for {:?} ({failure}), retry with magick",
+                        self.image_path
+                    );
+                    if output_path != input_path {
+                        let _ = fs::remove_file(&output_path);
+                    }
+                    self.used_fallback = true;
+                    steps = VecDeque::from([fallback]);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
 
-        if let Err(_) = fs::remove_file(&self.image_path) {
+            // the original stays until the last step is done, so a failed job leaves it intact
+            // intermediate steps work in place when they keep the format
+            let is_intermediate = input_path != output_path && input_path != self.image_path;
+            if is_intermediate && config.keep_intermediates {
+                debug!("keep intermediate {input_path:?}");
+            } else if is_intermediate && fs::remove_file(&input_path).is_err() {
+                return Err(Unspecific(format!(
+                    "{step:?}: Could not delete '{input_path:?}'",
+                )));
+            }
+            input_path = output_path;
+        }
+        // dual format archives keep the original beside the converted image
+        if input_path != self.image_path
+            && !config.dual_format
+            && fs::remove_file(&self.image_path).is_err()
+        {
             return Err(Unspecific(format!(
-                "intermediate step: Could not delete '{:?}'",
+                "Could not delete '{:?}'",
                 self.image_path
             )));
         }
+        debug!("done {self:?}");
+        Ok(())
+    }
 
-        let next_status = match (self.intermediate.unwrap(), self.target) {
-            (from @ (Jpeg | Png), to @ Avif) => {
-                let input_path = self.image_path.with_extension(from.to_string());
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::encode_avif(&input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (from @ (Jpeg | Png), to @ Jxl) => {
-                let input_path = self.image_path.with_extension(from.to_string());
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::encode_jxl(&input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (from @ Png, to @ Jpeg) => {
-                let input_path = self.image_path.with_extension(from.to_string());
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::convert_png_to_jpeg(&input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (from @ Png, to @ Webp) => {
-                let input_path = self.image_path.with_extension(from.to_string());
-                let output_path = self.image_path.with_extension(to.to_string());
-                let child = spawn::encode_webp(&input_path, &output_path)?;
-                self.child = Some(child);
-                JobStatus::Encoding
-            }
-            (_, Jpeg | Png | Avif | Jxl | Webp) => unreachable!(),
-        };
-        self.status = next_status;
-        Ok(next_status)
-    }
-
-    // wait on child process and delete original image file
-    fn on_encoding(&mut self) -> Result<JobStatus, ConversionError> {
-        let child: &mut Child = match &mut self.child {
-            Some(child) => child,
-            None => unreachable!(),
-        };
-        match child.wait() {
-            Ok(status) if !status.success() => {
-                let output = extract_console_output(child);
-                debug!("error on process:\n{output}");
-                return Err(AbnormalExit(self.image_path.clone()));
+    /// Make sure a tool that exited successfully did write something, before the input of the
+    /// step gets deleted
+    fn check_output(&self, output_path: &Path) -> Result<(), ConversionError> {
+        match fs::metadata(output_path) {
+            Ok(meta) if meta.len() > 0 => Ok(()),
+            _ => {
+                debug!("{output_path:?} is missing or empty");
+                Err(AbnormalExit(self.image_path.clone(), Failure::EmptyOutput))
             }
-            Ok(_) => {
-                let output = extract_console_output(child);
-                trace!("process output:\n{output}");
-                ()
-            }
-            Err(_) => return Err(Unspecific("error during wait".to_string())),
         }
-        let delete_path = match self.intermediate {
-            Some(intermediate) => self.image_path.with_extension(intermediate.to_string()),
-            None => self.image_path.clone(),
-        };
+    }
 
-        self.status = JobStatus::Done;
-        match fs::remove_file(&delete_path) {
-            Ok(_) => Ok(self.status),
-            Err(_) => Err(Unspecific(format!(
-                "converting step: Could not delete '{:?}'",
-                delete_path
+    /// Refuse image sequences, none of the tools carries the animation over
+    fn check_still(&self) -> Result<(), ConversionError> {
+        match dimensions::is_animated_avif(&self.image_path) {
+            Ok(true) => Err(Animated(self.image_path.clone())),
+            Ok(false) => Ok(()),
+            Err(e) => Err(Unspecific(format!(
+                "could not read {:?}: {e}",
+                self.image_path
             ))),
         }
     }
 
-    fn proceed(&mut self) -> Result<JobStatus, ConversionError> {
-        debug!("proceed with {self:?}");
-        let result = match self.status {
-            JobStatus::Init => self.on_init(),
-            JobStatus::Decoding => self.on_decoding(),
-            JobStatus::Encoding => self.on_encoding(),
-            JobStatus::Done => Ok(JobStatus::Done),
-        };
-        debug!("after proceed {self:?}");
-        result
+    /// Refuse transparent images, which would be flattened against an arbitrary color
+    fn check_opaque(&self) -> Result<(), ConversionError> {
+        match dimensions::png_has_alpha(&self.image_path) {
+            Ok(true) => Err(Transparent(self.image_path.clone())),
+            Ok(false) => Ok(()),
+            Err(e) => Err(Unspecific(format!(
+                "could not read {:?}: {e}",
+                self.image_path
+            ))),
+        }
     }
 
-    fn can_proceed(&mut self) -> Result<bool, ConversionError> {
-        match self.status {
-            JobStatus::Init => unreachable!(),
-            JobStatus::Decoding => (),
-            JobStatus::Encoding => (),
-            JobStatus::Done => return Ok(false),
+    /// Refuse images whose header claims more pixels than allowed, which might be made to
+    /// exhaust the memory of the tools
+    fn check_pixels(&self, limit: u64) -> Result<(), ConversionError> {
+        let dimensions = dimensions::image_dimensions(&self.image_path)
+            .map_err(|e| Unspecific(format!("could not read {:?}: {e}", self.image_path)))?;
+        match dimensions {
+            Some((width, height)) if width * height > limit => Err(TooManyPixels(
+                self.image_path.clone(),
+                width * height,
+                limit,
+            )),
+            Some(_) => Ok(()),
+            None => {
+                debug!("no dimensions found in the header of {:?}", self.image_path);
+                Ok(())
+            }
         }
-        let child: &mut Child = match &mut self.child {
-            Some(child) => child,
-            None => unreachable!(),
-        };
-        match child.try_wait() {
-            Ok(Some(_)) => {
-                trace!("ready");
-                return Ok(true);
+    }
+
+    /// Replace the format guessed from the extension with the one found in the file
+    fn probe_format(&mut self) -> Result<(), ConversionError> {
+        let probed = probe_format(&self.image_path)
+            .map_err(|e| Unspecific(format!("could not read {:?}: {e}", self.image_path)))?;
+        match probed {
+            Some(format) if format != self.current => {
+                warn!(
+                    "{:?} is actually {format}, convert it as such",
+                    self.image_path
+                );
+                self.current = format;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Give an image that turned out to be in the target format its proper extension
+    fn fix_extension(&self, config: &ConversionConfig) -> Result<(), ConversionError> {
+        let output_path = self.image_path.with_extension(self.target.ext_for(config));
+        fs::rename(&self.image_path, &output_path)
+            .map_err(|e| Unspecific(format!("could not rename {:?}: {e}", self.image_path)))
+    }
+
+    /// Tool invocations of the planned steps, without any fallback
+    fn invocations(
+        &self,
+        steps: &[Step],
+        config: &ConversionConfig,
+    ) -> Result<Vec<Invocation>, ConversionError> {
+        let config = &*self.config_for(config);
+        let mut input_path = self.image_path.clone();
+        let mut invocations = vec![];
+        for (index, step) in steps.iter().enumerate() {
+            let output_path = self.output_path(*step, index + 1 == steps.len(), config);
+            invocations.push(step.invocation(&input_path, &output_path, config)?);
+            input_path = output_path;
+        }
+        Ok(invocations)
+    }
+
+    /// Remove whatever the steps wrote, so that only the original image remains after a failure
+    fn remove_leftovers(&self, config: &ConversionConfig) {
+        let final_path = self.image_path.with_extension(self.target.ext_for(config));
+        let intermediates = self
+            .steps
+            .iter()
+            .map(|step| self.output_path(*step, false, config));
+        for path in intermediates.chain([final_path]) {
+            if path != self.image_path {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Put a copy of the converted image in place of the identical image at `image_path`
+    fn reuse_for(&self, image_path: &Path, config: &ConversionConfig) -> io::Result<()> {
+        let ext = self.target.ext_for(config);
+        let output = self.image_path.with_extension(ext);
+        let copy = image_path.with_extension(ext);
+        // a link is enough as long as neither is changed in place later on
+        let staged = image_path.with_extension(format!("{INTERMEDIATE_SUFFIX}.{ext}"));
+        fs::hard_link(&output, &staged).or_else(|_| fs::copy(&output, &staged).map(|_| ()))?;
+        fs::rename(&staged, &copy)?;
+        if copy != image_path && !config.dual_format {
+            fs::remove_file(image_path)?;
+        }
+        Ok(())
+    }
+
+    /// Where a step writes its result, intermediate files must not replace pages of the archive
+    fn output_path(&self, step: Step, is_last: bool, config: &ConversionConfig) -> PathBuf {
+        let ext = step.to().ext_for(config);
+        match is_last {
+            true => self.image_path.with_extension(ext),
+            false => self
+                .image_path
+                .with_extension(format!("{INTERMEDIATE_SUFFIX}.{ext}")),
+        }
+    }
+
+    /// Wait until the current child exits, killing it when the run gets cancelled or it takes
+    /// longer than `timeout`
+    fn wait_for_child(
+        &mut self,
+        tool: Tool,
+        cancel: &Cancellation,
+        timeout: Option<Duration>,
+    ) -> Result<(), ConversionError> {
+        let mut child = self.child.take().expect("no child to wait for");
+        let start = Instant::now();
+        let status = loop {
+            if cancel.stops_running() {
+                trace!("cancel {:?}", self.image_path);
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Interrupt);
             }
-            Ok(None) => {
-                trace!("not ready");
-                return Ok(false);
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                debug!("{tool} hung on {:?}, kill it", self.image_path);
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(AbnormalExit(self.image_path.clone(), Failure::TimedOut));
             }
-            Err(_) => {
-                trace!("error");
-                return Err(Unspecific(self.image_path.to_string_lossy().to_string()));
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => thread::sleep(CHILD_POLL_INTERVAL),
+                Err(_) => return Err(Unspecific("error during wait".to_string())),
             }
+        };
+
+        let (stdout, stderr) = read_console_output(&mut child);
+        let output = format!("stdout:\n{stdout}\nstderr:\n{stderr}");
+        if !status.success() {
+            debug!("error on process:\n{output}");
+            let failure = spawn::classify_failure(tool, &output);
+            return Err(AbnormalExit(self.image_path.clone(), failure));
+        }
+        trace!("process output:\n{output}");
+        if !stderr.trim().is_empty() {
+            debug!(
+                "{tool} printed for {:?}:\n{}",
+                self.image_path,
+                stderr.trim_end()
+            );
         }
+        // a tool may well succeed with a worse result, only worth mentioning when asked for
+        let warnings = spawn::tool_warnings(&stderr);
+        if spawn::is_verbose() && !warnings.is_empty() {
+            warn!(
+                "{tool} warned about {:?}:\n{}",
+                self.image_path,
+                warnings.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    fn relative_path(&self, root_dir: &Path) -> PathBuf {
+        self.image_path
+            .strip_prefix(root_dir)
+            .unwrap_or(&self.image_path)
+            .to_path_buf()
     }
 }
 
 impl WorkUnit {
     fn new(
-        cbz_path: &PathBuf,
-        target_format: ImageFormat,
+        cbz_path: &Path,
+        config: &ConversionConfig,
         workers: usize,
-        force: bool,
     ) -> Result<WorkUnit, ConversionError> {
-        let cbz_path = cbz_path.clone();
+        let cbz_path = cbz_path.to_path_buf();
         trace!("called WorkUnit::new()");
-        let not_correct_extention = cbz_path
-            .extension()
-            .map_or(true, |e| e != "cbz" && e != "zip");
-        if not_correct_extention {
-            return Err(NotAnArchive(cbz_path.to_path_buf()));
-        }
+        let container = match Container::from_path(&cbz_path) {
+            Some(container) => container,
+            None => return Err(NotAnArchive(cbz_path)),
+        };
 
-        let root_dir = get_extraction_root_dir(&cbz_path);
-        let job_queue = images_in_archive(&cbz_path)?
+        let (root_dir, entries) = get_extraction_root_dir(&cbz_path)?;
+        let mut job_queue = VecDeque::new();
+        let mut skipped = vec![];
+        let files = files_in_archive(&cbz_path)?;
+        let targets = target_overrides(&files);
+        let comic_info = files
             .iter()
-            .filter_map(|(image_path, format)| {
-                ConversionJob::new(root_dir.join(image_path), *format, target_format).ok()
+            .map(|(file, _)| file)
+            .filter(|file| {
+                file.file_name()
+                    .is_some_and(|name| name.eq_ignore_ascii_case("ComicInfo.xml"))
             })
-            .filter(|job| force || !convert_only_when_forced(job.current, job.target))
-            .collect::<VecDeque<_>>();
-        if job_queue.is_empty() {
+            .min_by_key(|file| file.components().count())
+            .cloned();
+        let mut images = files
+            .into_iter()
+            .filter_map(|(file, format)| Some((file, format?)))
+            .collect::<Vec<_>>();
+        // resource forks of macOS are named like the images they belong to
+        images.retain(|(image_path, _)| config.keeps_file(container, image_path));
+        sort_reading_order(&mut images);
+        let pages = images
+            .iter()
+            .map(|(image_path, _)| image_path.clone())
+            .collect();
+        for (page, (image_path, format)) in images.into_iter().enumerate() {
+            let not_selected = config
+                .pages
+                .as_ref()
+                .is_some_and(|pages| !pages.contains(page + 1));
+            // the tools are handed their paths as UTF-8
+            let unnamed = image_path.to_str().is_none();
+            if unnamed {
+                warn!("keep {image_path:?} as it is, its name is not valid UTF-8");
+            }
+            let keep = config.repack_only
+                || unnamed
+                || (config.keep_cover && page == 0)
+                || not_selected
+                || !config.selects_path(&image_path);
+            let target = targets.get(&image_path).copied().unwrap_or(config.target);
+            let job = ConversionJob::for_config(root_dir.join(&image_path), format, target, config)
+                .ok()
+                // the real format of the image is only known once it is probed
+                .filter(|job| config.probe_format || config.converts(job.current, job.target))
+                .filter(|_| !keep)
+                .map(|mut job| {
+                    job.quality = config.cover_quality.filter(|_| page < config.cover_pages);
+                    job
+                });
+            match job {
+                Some(job) => job_queue.push_back(job),
+                None => skipped.push((image_path, format)),
+            }
+        }
+        let converted = job_queue
+            .iter()
+            .filter_map(|job| job.image_path.strip_prefix(&root_dir).ok())
+            .collect();
+        check_path_lengths(&root_dir, &entries, &converted)?;
+        if job_queue.is_empty() && !config.repack_only {
             return Err(NothingToDo(cbz_path));
         }
 
         Ok(WorkUnit {
             cbz_path,
+            container,
+            root_dir,
             job_queue,
-            jobs_in_process: vec![],
-            target_format,
+            skipped,
+            config: config.clone(),
             workers,
+            extracted: false,
+            pages,
+            comic_info,
+            page_order: HashMap::new(),
         })
     }
 
@@ -410,179 +1253,855 @@ impl WorkUnit {
                 "Extract directory already exists, delete it and try again".to_string(),
             ));
         }
+        self.check_free_space()?;
         fs::create_dir_all(&extract_dir).unwrap();
+        self.extracted = true;
+        extract_archive(&self.cbz_path, self.container, &self.root_dir, &extract_dir)
+    }
 
-        let mut command = Command::new("7z");
-        command.args([
-            "x",
-            "-tzip", // undocumented switch to remove header lines
-            self.cbz_path.to_str().unwrap(),
-            "-spe",
-            format!("-o{}", extract_dir.to_str().unwrap()).as_str(),
-        ]);
-        let child = command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(|_| SpawnFailure("7z".to_string()))
-            .unwrap();
-
-        match child.wait_with_output() {
-            Ok(output) if output.status.code().is_some_and(|code| code == 0) => Ok(()),
-            Ok(_) => Err(ConversionError::ExtractionError(
-                "Extraction with 7z unsuccessful".to_string(),
-            )),
-            Err(e) => Err(ConversionError::ExtractionError(format!(
-                "{}",
-                e.to_string()
-            ))),
+    /// Make sure the extracted archive fits on the disk, leaving the configured space free
+    fn check_free_space(&self) -> Result<(), ConversionError> {
+        let dir = self.cbz_path.parent().unwrap();
+        let (needed, available) = match (
+            self.container.uncompressed_size(&self.cbz_path),
+            available_space(dir),
+        ) {
+            (Ok(size), Ok(available)) => (size + self.config.min_free_space, available),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!(
+                    "could not check the free space for {:?}: {e}",
+                    self.cbz_path
+                );
+                return Ok(());
+            }
+        };
+        debug!(
+            "{needed} bytes needed for {:?}, {available} available",
+            self.cbz_path
+        );
+        match needed <= available {
+            true => Ok(()),
+            false => Err(NotEnoughSpace(self.cbz_path.clone(), needed, available)),
         }
     }
 
-    fn compress_cbz(&mut self) {
+    /// Write the converted archive, or its parts when it exceeds the split size
+    fn compress_cbz(&mut self, cancel: &Cancellation) -> Result<Vec<PathBuf>, ConversionError> {
         trace!("called compress_cbz() with {:?}", self.cbz_path);
 
-        let dir = self.cbz_path.parent().unwrap();
-        let name = self.cbz_path.file_stem().unwrap();
-        let zip_path = dir.join(format!(
-            "{}.{}.cbz",
-            name.to_str().unwrap(),
-            self.target_format.to_string()
-        ));
-        debug!("create cbz at {:?}", zip_path);
-        let file = File::create(zip_path).unwrap();
+        let entries = self.entries_to_compress()?;
+        let parts = match self.config.split_size {
+            // the parts of a book would not be readable on their own
+            Some(_) if self.container == Container::Epub => {
+                warn!("epub books are not split, write {:?} as one", self.cbz_path);
+                vec![(0..entries.len()).collect()]
+            }
+            Some(limit) => split_entries(self.container, &entries, limit),
+            None => vec![(0..entries.len()).collect()],
+        };
+        if let [part] = &parts[..] {
+            let zip_path = self.config.converted_path(&self.cbz_path, self.container);
+            debug!("create archive at {:?}", zip_path);
+            let entries = part
+                .iter()
+                .map(|&index| &entries[index])
+                .collect::<Vec<_>>();
+            write_atomically(&zip_path, |file, partial_path| {
+                self.write_archive(file, &zip_path, &entries, cancel)?;
+                self.verify_archive(partial_path, &zip_path)
+            })?;
+            return Ok(vec![zip_path]);
+        }
 
-        let mut zipper = ZipWriter::new(file);
-        let options = SimpleFileOptions::default()
-            .compression_method(CompressionMethod::Stored)
-            .unix_permissions(0o755);
+        let mut written = vec![];
+        for (number, part) in parts.iter().enumerate() {
+            let part_path = self
+                .config
+                .part_path(&self.cbz_path, self.container, number + 1);
+            debug!(
+                "create part {} of {} at {part_path:?}",
+                number + 1,
+                parts.len()
+            );
+            let entries = part
+                .iter()
+                .map(|&index| &entries[index])
+                .collect::<Vec<_>>();
+            let result = write_partial(&part_path, |file, partial_path| {
+                self.write_archive(file, &part_path, &entries, cancel)?;
+                self.verify_archive(partial_path, &part_path)
+            });
+            match result {
+                Ok(partial_path) => written.push((partial_path, part_path)),
+                Err(e) => {
+                    // an incomplete set of parts is of no use
+                    for (partial_path, _) in written {
+                        let _ = fs::remove_file(partial_path);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        // the first part marks the archive as converted, so it only shows up after all others
+        let mut moved = vec![];
+        for (partial_path, part_path) in written.iter().rev() {
+            if let Err(e) = move_into_place(partial_path, part_path) {
+                for (partial_path, _) in &written {
+                    let _ = fs::remove_file(partial_path);
+                }
+                for part_path in moved {
+                    let _ = fs::remove_file(part_path);
+                }
+                return Err(e);
+            }
+            moved.push(part_path.clone());
+        }
+        moved.reverse();
+        Ok(moved)
+    }
 
+    /// Everything below the extraction directory, named as within the archive
+    fn entries_to_compress(&self) -> Result<Vec<ArchiveEntry>, ConversionError> {
         let extract_dir = get_conversion_root_dir(&self.cbz_path);
+        let names_relative_to = match self.container.has_root_dir() {
+            true => extract_dir.parent().unwrap(),
+            false => &extract_dir,
+        };
         trace!("compress directory {extract_dir:?}");
-        let mut buffer = Vec::new();
+        let mut entries = vec![];
+        // parts of split archives follow the reading order
         for entry in WalkDir::new(&extract_dir)
+            .sort_by(|a, b| {
+                natural_cmp(
+                    &a.file_name().to_string_lossy(),
+                    &b.file_name().to_string_lossy(),
+                )
+            })
             .into_iter()
+            .filter_entry(|entry| self.config.keep_junk || !is_junk(Path::new(entry.file_name())))
             .filter_map(|e| e.ok())
         {
-            let entry = entry.path();
-            debug!("add to archive: {:?}", entry);
-            let file_name = entry.strip_prefix(&extract_dir.parent().unwrap()).unwrap();
-            let path_string = file_name
-                .to_str()
-                .to_owned()
-                .expect("Path is not UTF-8 conformant");
+            let file_name = entry.path().strip_prefix(names_relative_to).unwrap();
+            if file_name.as_os_str().is_empty() {
+                continue;
+            }
+            // entry names are written as UTF-8, zip archives know no other encoding
+            let name = file_name.to_string_lossy().into_owned();
+            if file_name.to_str().is_none() {
+                warn!("{file_name:?} is not valid UTF-8, store it as {name:?}");
+            }
+            let is_file = entry.file_type().is_file();
+            if is_file && !self.config.keeps_file(self.container, file_name) {
+                debug!("leave out {file_name:?}");
+                continue;
+            }
+            let size = match is_file {
+                true => Some(
+                    entry
+                        .metadata()
+                        .map_err(|e| Unspecific(e.to_string()))?
+                        .len(),
+                ),
+                false => None,
+            };
+            entries.push(ArchiveEntry {
+                name,
+                path: entry.into_path(),
+                size,
+            });
+        }
+        if !self.page_order.is_empty() {
+            // pages follow the ComicInfo.xml, after everything else and before unlisted images
+            entries.sort_by_key(|entry| {
+                let page = entry.path.strip_prefix(&self.root_dir).ok();
+                let page = page.map(|page| page.with_extension(""));
+                match page.and_then(|page| self.page_order.get(&page)) {
+                    Some(&position) => (1, position),
+                    None if entry.size.is_some() && image_format(&entry.path).is_some() => (2, 0),
+                    None => (0, 0),
+                }
+            });
+        }
+        Ok(entries)
+    }
 
-            if entry.is_file() {
-                zipper.start_file(path_string, options).unwrap();
-                File::open(entry).unwrap().read_to_end(&mut buffer).unwrap();
-                zipper.write_all(&buffer).unwrap();
-                buffer.clear();
-            } else if !file_name.as_os_str().is_empty() {
-                zipper.add_directory(path_string, options).unwrap();
+    /// Put the entries into the file of the new archive at `path`
+    fn write_archive(
+        &self,
+        file: File,
+        path: &Path,
+        entries: &[&ArchiveEntry],
+        cancel: &Cancellation,
+    ) -> Result<(), ConversionError> {
+        let write_error = |e: io::Error| Unspecific(format!("could not write {path:?}: {e}"));
+        let mut writer = ContainerWriter::new(
+            self.container,
+            file,
+            self.config.modes,
+            self.config.compress_metadata,
+        )
+        .map_err(write_error)?;
+        // readers keep metadata in the comment of zip archives at times
+        if self.container.is_zip() {
+            match container::zip_comment(&self.cbz_path) {
+                Ok(comment) => writer.set_comment(&comment),
+                Err(e) => debug!("no comment taken over from {:?}: {e}", self.cbz_path),
             }
         }
 
-        zipper.finish().unwrap();
+        for entry in entries {
+            if cancel.stops_running() {
+                return Err(Interrupt);
+            }
+            debug!("add to archive: {:?}", entry.path);
+            match entry.size {
+                Some(_) => writer.add_file(&entry.name, &entry.path),
+                None => writer.add_directory(&entry.name, &entry.path),
+            }
+            .map_err(write_error)?;
+        }
+
+        writer.finish().map_err(write_error)
     }
 
-    fn run(mut self) -> Result<(), ConversionError> {
-        debug!("start conversion for {:?}", self.cbz_path);
+    /// Read back the archive just written to `partial_path`, before it is moved to `path`
+    fn verify_archive(&self, partial_path: &Path, path: &Path) -> Result<(), ConversionError> {
+        self.container.verify(partial_path).map_err(|e| {
+            debug!("verification of {partial_path:?} failed: {e}");
+            VerificationFailed(path.to_path_buf())
+        })
+    }
 
-        assert!(!self.job_queue.is_empty());
-        self.extract_cbz()?;
+    /// Swap the converted archive in for the original, which was verified when it was written
+    fn replace_original(&self, zip_path: &Path) -> Result<(), ConversionError> {
+        debug!("replace {:?} with {:?}", self.cbz_path, zip_path);
+        fs::rename(zip_path, &self.cbz_path)
+            .map_err(|e| Unspecific(format!("could not replace '{:?}': {e}", self.cbz_path)))
+    }
 
-        // these signals will be catched from here on out until the end of this function
-        let mut signals = match Signals::new(&[SIGINT, SIGCHLD]) {
-            Ok(signals) => signals,
-            Err(_) => return Err(Unspecific("could not listen to signals".to_string())),
+    /// Print the groups of identical files within the extracted archive
+    fn report_duplicates(&self) {
+        let extract_dir = get_conversion_root_dir(&self.cbz_path);
+        let duplicates = match dedupe::find_duplicates(&extract_dir) {
+            Ok(duplicates) => duplicates,
+            Err(e) => {
+                warn!("could not look for duplicates in {extract_dir:?}: {e}");
+                return;
+            }
         };
+        if duplicates.is_empty() {
+            return;
+        }
+        output::stdout(format!("{}: identical pages", self.cbz_path.display()));
+        for group in duplicates.iter() {
+            let files = group
+                .files
+                .iter()
+                .map(|file| file.strip_prefix(&self.root_dir).unwrap_or(file))
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>();
+            output::stdout(format!("  {}", files.join(", ")));
+        }
+        let redundant = duplicates
+            .iter()
+            .map(|group| group.redundant_bytes())
+            .sum::<u64>();
+        output::stdout(format!("  {redundant} bytes could be saved"));
+    }
 
-        // start out as many jobs as allowed
-        trace!("start initial jobs");
-        while self.jobs_in_process.len() < self.workers {
-            let mut job = match self.job_queue.pop_front() {
-                Some(job) => job,
-                None => break,
-            };
-
-            let status = job.proceed()?;
-            match status {
-                JobStatus::Init => unreachable!(),
-                JobStatus::Decoding => self.jobs_in_process.push(job),
-                JobStatus::Encoding => self.jobs_in_process.push(job),
-                JobStatus::Done => (),
+    /// Tools that the planned steps of any image need
+    fn required_tools(&self) -> BTreeSet<Tool> {
+        let mut tools = BTreeSet::new();
+        for job in self.job_queue.iter() {
+            let steps = plan_steps(job.current, job.target, &self.config, Png);
+            tools.extend(steps.iter().map(|step| step.tool()));
+            if job.inspects_jxl() {
+                tools.insert(Tool::Jxlinfo);
             }
         }
+        tools
+    }
 
-        trace!("start new jobs as old ones complete");
-        while self.jobs_pending() {
-            for signal in signals.wait() {
-                match signal {
-                    SIGINT => {
-                        debug!("got signal SIGINT");
-                        return Err(Interrupt);
-                    }
-                    SIGCHLD => {
-                        debug!("got signal SIGCHLD");
-                        self.proceed_jobs()?;
-                        if !self.job_queue.is_empty() {
-                            self.start_next_jobs()?;
+    /// Print the planned steps of every image without touching any file
+    fn analyze(&self, tools: &mut BTreeSet<Tool>) {
+        tools.extend(self.required_tools());
+        output::stdout(format!("{}:", self.cbz_path.display()));
+        for (image_path, format) in self.skipped.iter() {
+            output::stdout(format!("  {}: {format}, skipped", image_path.display()));
+        }
+        for job in self.job_queue.iter() {
+            // which intermediate Jxl needs is only known by looking at the extracted image
+            let steps = plan_steps(job.current, job.target, &self.config, Png);
+            let note = match job.inspects_jxl() {
+                true => " (over jpeg for recompressed jpegs)",
+                false => "",
+            };
+            let step_names = steps.iter().map(Step::to_string).collect::<Vec<_>>();
+            output::stdout(format!(
+                "  {}: {}{note}",
+                job.relative_path(&self.root_dir).display(),
+                step_names.join(", ")
+            ));
+            if self.config.print_commands {
+                match job.invocations(&steps, &self.config) {
+                    Ok(invocations) => {
+                        for invocation in invocations {
+                            output::stdout(format!("    {invocation}"));
                         }
                     }
-                    _ => unreachable!(),
+                    Err(e) => output::stdout(format!("    {e}")),
                 }
             }
         }
+    }
+
+    /// The planned steps of every image, as --dump-plan json prints them
+    fn dump_plan(&self) -> ArchivePlan {
+        let mut images = Vec::new();
+        for (image_path, format) in self.skipped.iter() {
+            images.push(ImagePlan::Skipped {
+                path: image_path.to_string_lossy().to_string(),
+                format: *format,
+                skipped: true,
+                steps: vec![],
+            });
+        }
+        for job in self.job_queue.iter() {
+            let steps = plan_steps(job.current, job.target, &self.config, Png);
+            let intermediate = match &steps[..] {
+                [first, _, ..] => Some(first.to()),
+                _ => None,
+            };
+            let steps = steps
+                .iter()
+                .map(|step| StepPlan {
+                    tool: step.tool(),
+                    from: step.from(),
+                    to: step.to(),
+                })
+                .collect();
+            images.push(ImagePlan::Converted {
+                path: job
+                    .relative_path(&self.root_dir)
+                    .to_string_lossy()
+                    .to_string(),
+                format: job.current,
+                target: job.target,
+                skipped: false,
+                intermediate,
+                depends_on_image: job.inspects_jxl(),
+                steps,
+            });
+        }
+        ArchivePlan {
+            archive: self.cbz_path.to_string_lossy().to_string(),
+            converted: self.job_queue.len(),
+            skipped: self.skipped.len(),
+            images,
+            tools: self.required_tools(),
+        }
+    }
+
+    /// Order the pages as the ComicInfo.xml lists them and leave out the ones it marks as
+    /// deleted or advertisements with `drop_deleted_pages`
+    ///
+    /// A ComicInfo.xml that cannot be read is only warned about, the pages keep their order.
+    fn apply_comic_info(&mut self) -> Result<(), ConversionError> {
+        let Some(comic_info) = &self.comic_info else {
+            return Ok(());
+        };
+        let path = self.root_dir.join(comic_info);
+        let pages = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|xml| comicinfo::pages(&xml));
+        let pages = match pages {
+            Ok(pages) => pages,
+            Err(e) => {
+                warn!("ignore {path:?}: {e}");
+                return Ok(());
+            }
+        };
+        for page in pages {
+            let Some(image_path) = self.pages.get(page.image).cloned() else {
+                warn!(
+                    "{path:?} lists image {}, but there are only {}",
+                    page.image,
+                    self.pages.len()
+                );
+                continue;
+            };
+            if self.config.drop_deleted_pages && page.is_dropped() {
+                info!(
+                    "leave out {image_path:?}, it is marked as {}",
+                    page.types.join(" ")
+                );
+                let full_path = self.root_dir.join(&image_path);
+                self.job_queue.retain(|job| job.image_path != full_path);
+                self.skipped.retain(|(skipped, _)| *skipped != image_path);
+                fs::remove_file(&full_path)
+                    .map_err(|e| Unspecific(format!("could not remove {full_path:?}: {e}")))?;
+                continue;
+            }
+            let position = self.page_order.len();
+            self.page_order
+                .entry(image_path.with_extension(""))
+                .or_insert(position);
+        }
+        Ok(())
+    }
 
-        self.compress_cbz();
+    /// Extract the archive, which may happen well before the conversion starts
+    fn extract(&mut self, interrupted: &AtomicBool) -> Result<(), ConversionError> {
+        assert!(!self.job_queue.is_empty() || self.config.repack_only);
+        let start = Instant::now();
+        self.extract_cbz()?;
+        timings::record("extract", start);
+        if interrupted.load(Ordering::Relaxed) {
+            return Err(Interrupt);
+        }
         Ok(())
     }
 
-    fn proceed_jobs(&mut self) -> Result<(), ConversionError> {
-        trace!("proceed all ready jobs");
-        for job in self.jobs_in_process.iter_mut() {
-            trace!("job in process: {job:?}");
-            if job.can_proceed()? {
-                match job.proceed()? {
-                    JobStatus::Init => unreachable!(),
-                    JobStatus::Decoding => unreachable!(),
-                    JobStatus::Encoding => (),
-                    JobStatus::Done => (),
+    /// Leave only one job for images with the same content and target, the others are
+    /// handed the result of that one
+    fn group_identical_jobs(&mut self) -> Result<(), ConversionError> {
+        let paths = self
+            .job_queue
+            .iter()
+            .map(|job| job.image_path.clone())
+            .collect::<Vec<_>>();
+        let groups = dedupe::identical(&paths)
+            .map_err(|e| Unspecific(format!("could not compare the images: {e}")))?;
+        let mut jobs = std::mem::take(&mut self.job_queue)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        for group in groups {
+            let conversion = |job: &Option<ConversionJob>| {
+                job.as_ref()
+                    .map(|job| (job.current, job.target, job.quality))
+            };
+            let first = conversion(&jobs[group[0]]);
+            for index in group[1..].iter().copied() {
+                if conversion(&jobs[index]) == first {
+                    let image_path = jobs[index].take().unwrap().image_path.clone();
+                    jobs[group[0]].as_mut().unwrap().identical.push(image_path);
                 }
             }
         }
+        self.job_queue = jobs.into_iter().flatten().collect();
         Ok(())
     }
 
-    fn start_next_jobs(&mut self) -> Result<(), ConversionError> {
-        trace!("start new jobs");
-        'replace: for job in self.jobs_in_process.iter_mut() {
-            trace!("job in process: {job:?}");
-            if let JobStatus::Done = job.status {
-                let mut new_job = 'search: loop {
-                    let mut new_job = match self.job_queue.pop_front() {
-                        Some(new_job) => new_job,
-                        None => break 'replace,
-                    };
-                    match new_job.proceed()? {
-                        JobStatus::Done => continue,
-                        _ => break 'search new_job,
+    /// Take the extraction over from a copy of it left by the conversion to another target
+    fn extract_from(&mut self, pristine: &Path) -> Result<(), ConversionError> {
+        let extract_dir = get_conversion_root_dir(&self.cbz_path);
+        if extract_dir.exists() {
+            return Err(ConversionError::ExtractionError(
+                "Extract directory already exists, delete it and try again".to_string(),
+            ));
+        }
+        self.extracted = true;
+        link_tree(pristine, &extract_dir).map_err(|e| ExtractionError(e.to_string()))
+    }
+
+    fn run(
+        mut self,
+        reporter: &mut dyn ConversionReporter,
+        interrupted: &AtomicBool,
+    ) -> Result<(), ConversionError> {
+        let result = self.convert(reporter, interrupted);
+        if result.is_err() && self.config.keep_temp_on_error && !interrupted.load(Ordering::Relaxed)
+        {
+            // whatever did get converted is left for inspection
+            self.extracted = false;
+            warn!(
+                "keep the extracted images at {:?}",
+                get_conversion_root_dir(&self.cbz_path)
+            );
+        }
+        result
+    }
+
+    fn convert(
+        &mut self,
+        reporter: &mut dyn ConversionReporter,
+        interrupted: &AtomicBool,
+    ) -> Result<(), ConversionError> {
+        debug!("start conversion for {:?}", self.cbz_path);
+        if !self.extracted {
+            self.extract(interrupted)?;
+        }
+        if self.config.use_comic_info {
+            self.apply_comic_info()?;
+        }
+
+        let images = self.skipped.len() + self.job_queue.len();
+        reporter.start(&self.cbz_path, images);
+        for (image_path, format) in self.skipped.iter() {
+            reporter.report(
+                image_path,
+                *format,
+                self.config.target,
+                ImageOutcome::Skipped,
+            );
+        }
+
+        if self.config.reuse_identical {
+            self.group_identical_jobs()?;
+        }
+        trace!("start {} workers", self.workers);
+        let job_queue = Mutex::new(std::mem::take(&mut self.job_queue));
+        let cancel = Cancellation::new(interrupted, self.config.shutdown_grace);
+        let (sender, receiver) = mpsc::channel();
+        let mut renamed = vec![];
+        let mut dual_formats = vec![];
+        let mut archive_log = self
+            .skipped
+            .iter()
+            .map(|(image_path, format)| format!("{}: {format}, skipped", image_path.display()))
+            .collect::<Vec<_>>();
+        let file_name = |path: &Path| path.file_name().unwrap().to_string_lossy().to_string();
+        let results = thread::scope(|scope| {
+            let workers = (0..self.workers)
+                .map(|_| {
+                    let sender = sender.clone();
+                    let (job_queue, cancel) = (&job_queue, &cancel);
+                    let (config, root_dir) = (&self.config, &self.root_dir);
+                    scope.spawn(move || work_on_queue(job_queue, config, cancel, root_dir, sender))
+                })
+                .collect::<Vec<_>>();
+            drop(sender);
+
+            // reporting happens on this thread, so the reporter does not need to be shared
+            for report in receiver {
+                let (relative_path, to) = (&report.relative_path, report.to);
+                reporter.report(relative_path, report.from, to, report.outcome);
+                if let Some((input, output)) = report.sizes {
+                    reporter.sizes(report.from, to, input, output);
+                }
+                if matches!(
+                    report.outcome,
+                    ImageOutcome::Converted | ImageOutcome::Fallback
+                ) {
+                    let converted_path = relative_path.with_extension(to.ext_for(&self.config));
+                    if self.config.dual_format && converted_path != *relative_path {
+                        dual_formats.push(format!(
+                            "{}\t{}",
+                            relative_path.display(),
+                            converted_path.display()
+                        ));
                     }
-                };
-                trace!("replace job {job:?} for {new_job:?}");
-                std::mem::swap(job, &mut new_job);
+                    renamed.push(epub::Renamed {
+                        old_name: file_name(relative_path),
+                        new_name: file_name(&converted_path),
+                        format: to,
+                    });
+                }
+                archive_log.push(report.to_string());
+            }
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("worker thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        // prefer the error that caused the cancellation over the interrupts it triggered
+        let mut result = Ok(());
+        for worker_result in results {
+            match (&result, worker_result) {
+                (_, Ok(())) => (),
+                (Ok(()) | Err(Interrupt), Err(e)) => result = Err(e),
+                (Err(_), Err(_)) => (),
+            }
+        }
+        result?;
+        if self.config.dry_run {
+            return Ok(());
+        }
+
+        if !dual_formats.is_empty() {
+            let manifest: String = dual_formats
+                .iter()
+                .map(|line| line.clone() + "\n")
+                .collect();
+            fs::write(self.root_dir.join(DUAL_FORMAT_MANIFEST), manifest)
+                .map_err(|e| Unspecific(format!("could not write {DUAL_FORMAT_MANIFEST}: {e}")))?;
+        }
+        if self.container == Container::Epub {
+            let extract_dir = get_conversion_root_dir(&self.cbz_path);
+            epub::update_references(&extract_dir, &renamed)
+                .map_err(|e| Unspecific(format!("could not update the book: {e}")))?;
+        }
+        if self.config.dedupe {
+            self.report_duplicates();
+        }
+        let start = Instant::now();
+        let zip_paths = self.compress_cbz(&cancel)?;
+        timings::record("compress", start);
+        // originals kept beside their converted image
+        let images = images + dual_formats.len();
+        if let Err(e) = check_image_count(images, &zip_paths) {
+            zip_paths.iter().for_each(|path| {
+                let _ = fs::remove_file(path);
+            });
+            return Err(e);
+        }
+        let final_path = match (self.config.replace, &zip_paths[..]) {
+            (true, [zip_path]) => {
+                self.replace_original(zip_path)?;
+                self.cbz_path.clone()
+            }
+            (false, [zip_path]) => zip_path.clone(),
+            // the log of split archives goes beside the parts, named like the whole
+            _ => self.config.converted_path(&self.cbz_path, self.container),
+        };
+        if self.config.print_output {
+            let written = match self.config.replace {
+                true => std::slice::from_ref(&self.cbz_path),
+                false => &zip_paths[..],
+            };
+            written.iter().for_each(|path| output::path(path));
+        }
+        if self.config.archive_log {
+            let mut log_path = final_path.into_os_string();
+            log_path.push(".log");
+            archive_log.push(String::new());
+            if let Err(e) = fs::write(&log_path, archive_log.join("\n")) {
+                warn!("could not write the archive log {log_path:?}: {e}");
             }
         }
         Ok(())
     }
+}
+
+/// The plan of an archive, as --dump-plan json prints it
+#[derive(Serialize)]
+struct ArchivePlan {
+    archive: String,
+    converted: usize,
+    skipped: usize,
+    images: Vec<ImagePlan>,
+    tools: BTreeSet<Tool>,
+}
+
+/// The plan of an image within [`ArchivePlan`]
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ImagePlan {
+    Skipped {
+        path: String,
+        format: ImageFormat,
+        skipped: bool,
+        steps: Vec<StepPlan>,
+    },
+    Converted {
+        path: String,
+        format: ImageFormat,
+        target: ImageFormat,
+        skipped: bool,
+        intermediate: Option<ImageFormat>,
+        depends_on_image: bool,
+        steps: Vec<StepPlan>,
+    },
+}
+
+#[derive(Serialize)]
+struct StepPlan {
+    tool: Tool,
+    from: ImageFormat,
+    to: ImageFormat,
+}
+
+/// Tells running jobs to stop, either because the user interrupted or because a job failed
+struct Cancellation<'a> {
+    interrupted: &'a AtomicBool,
+    aborted: AtomicBool,
+    /// Running jobs may go on for this long after an interrupt
+    grace: Option<Duration>,
+    /// When the interrupt was first noticed
+    interrupted_at: OnceLock<Instant>,
+}
+
+impl<'a> Cancellation<'a> {
+    fn new(interrupted: &'a AtomicBool, grace: Option<Duration>) -> Self {
+        Cancellation {
+            interrupted,
+            aborted: AtomicBool::new(false),
+            grace,
+            interrupted_at: OnceLock::new(),
+        }
+    }
 
-    fn jobs_pending(&self) -> bool {
-        let all_done = self
-            .jobs_in_process
-            .iter()
-            .map(|job| job.status)
-            .all(|status| JobStatus::Done == status);
-        !all_done
+    fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Whether no further jobs are to be started
+    fn is_set(&self) -> bool {
+        self.is_interrupted() || self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Whether running jobs have to stop as well, which an interrupt only asks for once the
+    /// grace period is over
+    fn stops_running(&self) -> bool {
+        if self.aborted.load(Ordering::Relaxed) || STOP_NOW.load(Ordering::Relaxed) {
+            return true;
+        }
+        match (self.is_interrupted(), self.grace) {
+            (false, _) => false,
+            (true, None) => true,
+            (true, Some(grace)) => self.interrupted_at.get_or_init(Instant::now).elapsed() >= grace,
+        }
+    }
+}
+
+/// What a worker sends back about a single image
+struct ImageReport {
+    relative_path: PathBuf,
+    from: ImageFormat,
+    to: ImageFormat,
+    outcome: ImageOutcome,
+    tools: Vec<Tool>,
+    elapsed: Duration,
+    /// Bytes of the original and the converted image
+    sizes: Option<(u64, u64)>,
+}
+
+impl std::fmt::Display for ImageReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tools = self.tools.iter().map(Tool::to_string).collect::<Vec<_>>();
+        write!(
+            f,
+            "{}: {} -> {}, {} [{}] in {:.2}s",
+            self.relative_path.display(),
+            self.from,
+            self.to,
+            self.outcome,
+            tools.join(", "),
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+/// Worker loop, takes jobs from the shared queue until it is empty or the run gets cancelled
+fn work_on_queue(
+    job_queue: &Mutex<VecDeque<ConversionJob>>,
+    config: &ConversionConfig,
+    cancel: &Cancellation,
+    root_dir: &Path,
+    sender: mpsc::Sender<ImageReport>,
+) -> Result<(), ConversionError> {
+    loop {
+        let mut job = {
+            let mut job_queue = job_queue.lock().unwrap();
+            // the archive can still be completed in the grace period once all images started,
+            // otherwise the running images may still finish but no more are started
+            if cancel.stops_running() || (cancel.is_set() && !job_queue.is_empty()) {
+                return Err(Interrupt);
+            }
+            match job_queue.pop_front() {
+                Some(job) => job,
+                None => return Ok(()),
+            }
+        };
+
+        let start = Instant::now();
+        // the original is gone after the run, unless the conversion failed
+        let input_size = fs::metadata(&job.image_path).map(|meta| meta.len());
+        let result = job.run(config, cancel);
+        let outcome = match &result {
+            // left as it is, only its commands were printed
+            Ok(()) if config.dry_run => ImageOutcome::Skipped,
+            Ok(()) if job.used_fallback => ImageOutcome::Fallback,
+            Ok(()) => ImageOutcome::Converted,
+            // children get the terminal's SIGINT as well, so their failure is expected
+            Err(_) if cancel.is_interrupted() => return Err(Interrupt),
+            Err(Interrupt) => return Err(Interrupt),
+            Err(Animated(_) | Transparent(_) | Unselected(..)) => ImageOutcome::Skipped,
+            Err(AbnormalExit(_, Failure::UnsupportedInput)) => ImageOutcome::Skipped,
+            Err(_) => {
+                if config.on_error != OnError::SkipImage {
+                    cancel.abort();
+                }
+                ImageOutcome::Failed
+            }
+        };
+        let report = ImageReport {
+            relative_path: job.relative_path(root_dir),
+            from: job.current,
+            to: job.target,
+            outcome,
+            tools: std::mem::take(&mut job.tools),
+            elapsed: start.elapsed(),
+            sizes: match (&result, input_size) {
+                (Ok(()), Ok(input)) if !config.dry_run => {
+                    let converted = job.image_path.with_extension(job.target.ext_for(config));
+                    fs::metadata(converted).ok().map(|meta| (input, meta.len()))
+                }
+                _ => None,
+            },
+        };
+        let sizes = report.sizes;
+        let _ = sender.send(report);
+        let mut reuse_failure = None;
+        for image_path in std::mem::take(&mut job.identical) {
+            let reuse = result.is_ok() && !config.dry_run;
+            let outcome = match reuse.then(|| job.reuse_for(&image_path, config)) {
+                Some(Err(e)) => {
+                    let message =
+                        format!("could not copy the converted image to {image_path:?}: {e}");
+                    error!("{message}");
+                    reuse_failure = Some(Unspecific(message));
+                    ImageOutcome::Failed
+                }
+                _ => outcome,
+            };
+            let _ = sender.send(ImageReport {
+                relative_path: image_path
+                    .strip_prefix(root_dir)
+                    .unwrap_or(&image_path)
+                    .to_path_buf(),
+                from: job.current,
+                to: job.target,
+                outcome,
+                tools: vec![],
+                elapsed: Duration::ZERO,
+                sizes: sizes.filter(|_| outcome != ImageOutcome::Failed),
+            });
+        }
+        let result = match reuse_failure {
+            Some(e) if result.is_ok() && config.on_error != OnError::SkipImage => {
+                cancel.abort();
+                Err(e)
+            }
+            _ => result,
+        };
+        match result {
+            Err(e @ (Animated(_) | Unselected(..))) => info!("keep it as it is: {e}"),
+            Err(e @ Transparent(_)) => {
+                warn!("keep it as it is: {e}, pass --alpha-background to fill it")
+            }
+            Err(e @ AbnormalExit(_, Failure::UnsupportedInput)) => {
+                warn!("keep it as it is: {e}");
+                job.remove_leftovers(config);
+            }
+            Err(e) if config.on_error == OnError::SkipImage => {
+                warn!("keep {:?} as it is: {e}", job.relative_path(root_dir));
+                job.remove_leftovers(config);
+            }
+            result => result?,
+        }
+    }
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} ({})", self.from(), self.to(), self.tool())
     }
 }
 
@@ -590,11 +2109,10 @@ impl std::fmt::Debug for ConversionJob {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut writer = f.debug_struct("ConversionJob");
         writer
-            .field("status", &self.status)
             .field("from", &self.current)
             .field("to", &self.target);
-        if let Some(inter) = self.intermediate {
-            writer.field("over", &inter);
+        if !self.steps.is_empty() {
+            writer.field("steps", &self.steps);
         }
         writer
             .field("image_path", &self.image_path.to_string_lossy())
@@ -618,6 +2136,9 @@ impl Drop for ConversionJob {
 
 impl Drop for WorkUnit {
     fn drop(&mut self) {
+        if !self.extracted {
+            return;
+        }
         debug!("cleanup for {:?}", self.cbz_path);
         let extract_dir = get_conversion_root_dir(&self.cbz_path);
         if extract_dir.exists() {
@@ -628,29 +2149,63 @@ impl Drop for WorkUnit {
 }
 
 fn extract_console_output(child: &mut Child) -> String {
+    let (output, err_out) = read_console_output(child);
+    format!("stdout:\n{output}\nstderr:\n{err_out}")
+}
+
+/// Exit status of the child, or `None` if it was killed for running longer than `timeout`
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Option<Duration>,
+) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            let _ = child.kill();
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(CHILD_POLL_INTERVAL);
+    }
+}
+
+/// What the child printed to stdout and stderr
+fn read_console_output(child: &mut Child) -> (String, String) {
     let stdout = child.stdout.as_mut().unwrap();
     let mut output = String::new();
     stdout.read_to_string(&mut output).unwrap();
     let stderr = child.stderr.as_mut().unwrap();
     let mut err_out = String::new();
     stderr.read_to_string(&mut err_out).unwrap();
-    format!("stdout:\n{output}\nstderr:\n{err_out}")
+    (output, err_out)
 }
 
-fn jxl_is_compressed_jpeg(image_path: &PathBuf) -> Result<bool, ConversionError> {
-    let mut command = Command::new("jxlinfo");
+/// Whether the Jxl image holds a recompressed Jpeg, jxlinfo is killed after `timeout`
+fn jxl_is_compressed_jpeg(
+    image_path: &Path,
+    timeout: Option<Duration>,
+) -> Result<bool, ConversionError> {
+    let program = Tool::Jxlinfo.program();
+    let mut command = Command::new(program);
     command.args(["-v", image_path.to_str().unwrap()]);
     let mut child = command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
-        .map_err(|_| SpawnFailure("jxlinfo".to_string()))?;
+        .map_err(|_| SpawnFailure(program.to_string()))?;
 
-    match child.wait() {
-        Ok(status) if !status.success() => {
+    match wait_with_timeout(&mut child, timeout) {
+        Ok(None) => {
+            debug!("{program} hung on {image_path:?}, killed it");
+            Err(AbnormalExit(image_path.to_path_buf(), Failure::TimedOut))
+        }
+        Ok(Some(status)) if !status.success() => {
             let output = extract_console_output(&mut child);
             debug!("error on process:\n{output}");
-            return Err(AbnormalExit(image_path.clone()));
+            Err(AbnormalExit(image_path.to_path_buf(), Failure::Unknown))
         }
         Ok(_) => {
             let output = extract_console_output(&mut child);
@@ -658,58 +2213,183 @@ fn jxl_is_compressed_jpeg(image_path: &PathBuf) -> Result<bool, ConversionError>
 
             let has_jbrd_box = output
                 .lines()
-                .find(|line| line.starts_with("box: type: \"jbrd\""))
-                .is_some();
+                .any(|line| line.starts_with("box: type: \"jbrd\""));
             Ok(has_jbrd_box)
         }
-        Err(_) => return Err(Unspecific("error during wait".to_string())),
+        Err(_) => Err(Unspecific("error during wait".to_string())),
     }
 }
 
-fn images_in_archive(cbz_path: &PathBuf) -> Result<Vec<(PathBuf, ImageFormat)>, ConversionError> {
+fn images_in_archive(cbz_path: &Path) -> Result<Vec<(PathBuf, ImageFormat)>, ConversionError> {
     trace!("called images_in_archive()");
+    let files = files_in_archive(cbz_path)?;
+    Ok(files
+        .into_iter()
+        .filter_map(|(file, format)| Some((file, format?)))
+        .collect())
+}
+
+/// All files within the archive, with the format for those that are images
+fn files_in_archive(
+    cbz_path: &Path,
+) -> Result<Vec<(PathBuf, Option<ImageFormat>)>, ConversionError> {
+    Ok(files_with_format(archive_entries(cbz_path)?))
+}
+
+/// Extract the archive into `extract_dir`, which has to exist
+///
+/// `root_dir` is either `extract_dir` or its parent, when the entries are below a root
+/// directory named like the archive already.
+fn extract_archive(
+    cbz_path: &Path,
+    container: Container,
+    root_dir: &Path,
+    extract_dir: &Path,
+) -> Result<(), ConversionError> {
+    if container.is_zip() {
+        // entries below a root directory named like the archive end up in the same place
+        match container::extract_zip(cbz_path, root_dir) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                debug!("could not extract {cbz_path:?} as zip, use 7z instead: {e}");
+                fs::remove_dir_all(extract_dir).unwrap();
+                fs::create_dir_all(extract_dir).unwrap();
+            }
+        }
+    }
 
     let mut command = Command::new("7z");
     command.args([
-        "l",
-        "-ba",  // undocumented switch to remove header lines
-        "-slt", // use format that is easier to parse
+        "x",
+        container.seven_zip_type(),
         cbz_path.to_str().unwrap(),
+        "-spe",
+        format!("-o{}", extract_dir.to_str().unwrap()).as_str(),
     ]);
     let child = command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .map_err(|_| SpawnFailure("7z".to_string()))?;
+
     match child.wait_with_output() {
-        Ok(output) => {
-            let files = output
-                .stdout
-                .lines()
-                .into_iter()
-                .filter(|v| v.as_ref().is_ok_and(|line| line.starts_with("Path = ")))
-                .map(|v| v.unwrap().strip_prefix("Path = ").unwrap().to_string())
-                .map(|file_str| PathBuf::from(file_str))
-                .filter_map(|file| {
-                    trace!("found file {file:?}");
-                    match file.extension()?.to_str().unwrap() {
-                        "jpg" => Some((file, Jpeg)),
-                        "jpeg" => Some((file, Jpeg)),
-                        "png" => Some((file, Png)),
-                        "avif" => Some((file, Avif)),
-                        "jxl" => Some((file, Jxl)),
-                        "webp" => Some((file, Webp)),
-                        _ => None,
-                    }
-                })
-                .collect::<Vec<_>>();
-            Ok(files)
-        }
-        Err(e) => Err(ConversionError::Unspecific(format!("{}", e.to_string()))),
+        Ok(output) if output.status.code().is_some_and(|code| code == 0) => Ok(()),
+        Ok(_) => Err(ConversionError::ExtractionError(
+            "Extraction with 7z unsuccessful".to_string(),
+        )),
+        Err(e) => Err(ConversionError::ExtractionError(e.to_string())),
+    }
+}
+
+/// Make sure no image went missing between the listing of the original archive and the
+/// written one, which happens when a file vanishes from the extraction directory
+fn check_image_count(expected: usize, written: &[PathBuf]) -> Result<(), ConversionError> {
+    let mut images = 0;
+    for path in written {
+        images += images_in_archive(path)?.len();
+    }
+    match images == expected {
+        true => Ok(()),
+        false => Err(ImageCountMismatch(written[0].clone(), expected, images)),
+    }
+}
+
+/// Image and target of a marker file like `page_005.jpg.avif-target`
+///
+/// Such a file within the archive converts the image beside it to another format than the one
+/// given for the whole archive, it is left out of the converted archive.
+fn target_override(path: &Path) -> Option<(PathBuf, ImageFormat)> {
+    use clap::ValueEnum;
+
+    let format = path.extension()?.to_str()?.strip_suffix("-target")?;
+    let format = ImageFormat::from_str(format, true).ok()?;
+    let image_path = path.with_extension("");
+    image_format(&image_path).map(|_| (image_path, format))
+}
+
+/// Whether the file was written between two steps of a conversion, like cover.cbzin-tmp.png
+fn is_intermediate(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains(&format!(".{INTERMEDIATE_SUFFIX}.")))
+}
+
+/// Targets of all images with a marker file beside them
+fn target_overrides(files: &[(PathBuf, Option<ImageFormat>)]) -> HashMap<PathBuf, ImageFormat> {
+    files
+        .iter()
+        .filter_map(|(file, _)| target_override(file))
+        .collect()
+}
+
+/// Whether the path is or lies within a file the operating system put into the archive
+///
+/// Those are the `__MACOSX/` resource forks and `.DS_Store` of macOS or the thumbnail cache
+/// `Thumbs.db` of Windows, none of them belong to the comic.
+fn is_junk(path: &Path) -> bool {
+    path.components().any(|component| {
+        let name = component.as_os_str().to_string_lossy();
+        name == "__MACOSX"
+            || name == ".DS_Store"
+            || name.starts_with("._")
+            || name.eq_ignore_ascii_case("Thumbs.db")
+    })
+}
+
+/// Directories are skipped even if they are named like images, some packers produce those
+fn files_with_format(entries: Vec<(PathBuf, bool)>) -> Vec<(PathBuf, Option<ImageFormat>)> {
+    entries
+        .into_iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .map(|(file, _)| {
+            trace!("found file {file:?}");
+            let format = image_format(&file);
+            (file, format)
+        })
+        .collect()
+}
+
+/// Format of the image at `path` according to the magic bytes at its start
+fn probe_format(path: &Path) -> io::Result<Option<ImageFormat>> {
+    let mut header = Vec::with_capacity(16);
+    File::open(path)?.take(16).read_to_end(&mut header)?;
+    let format = match header.as_slice() {
+        [0xFF, 0xD8, 0xFF, ..] => Some(Jpeg),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(Png),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(Webp),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => Some(Avif),
+        // bare codestream or the ISOBMFF container
+        [0xFF, 0x0A, ..] => Some(Jxl),
+        [0, 0, 0, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A, ..] => Some(Jxl),
+        _ => None,
+    };
+    Ok(format)
+}
+
+fn image_format(path: &Path) -> Option<ImageFormat> {
+    match path.extension()?.to_str()? {
+        "jpg" => Some(Jpeg),
+        "jpeg" => Some(Jpeg),
+        "png" => Some(Png),
+        "avif" => Some(Avif),
+        "avifs" => Some(Avif),
+        "jxl" => Some(Jxl),
+        "webp" => Some(Webp),
+        _ => None,
     }
 }
 
-fn get_extraction_root_dir(cbz_path: &PathBuf) -> PathBuf {
+/// All entries of the archive and whether they are directories
+///
+/// Zip archives are read directly, 7z is only needed for anything the zip crate can not handle.
+fn archive_entries(cbz_path: &Path) -> Result<Vec<(PathBuf, bool)>, ConversionError> {
+    if Container::from_path(cbz_path).is_some_and(Container::is_zip) {
+        match container::zip_entries(cbz_path) {
+            Ok(entries) => return Ok(entries),
+            Err(e) => debug!("could not read {cbz_path:?} as zip, use 7z instead: {e}"),
+        }
+    }
+
     let mut command = Command::new("7z");
     command.args([
         "l",
@@ -721,186 +2401,4556 @@ fn get_extraction_root_dir(cbz_path: &PathBuf) -> PathBuf {
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
-        .map_err(|_| SpawnFailure("7z".to_string()))
-        .unwrap();
+        .map_err(|_| SpawnFailure("7z".to_string()))?;
+    match child.wait_with_output() {
+        Ok(output) if output.status.success() => Ok(parse_entries(&output.stdout)),
+        Ok(_) => Err(ListingFailed(cbz_path.to_path_buf())),
+        Err(e) => Err(ConversionError::Unspecific(e.to_string())),
+    }
+}
 
-    let archive_name = cbz_path.file_stem().unwrap();
-    let archive_root_dirs = match child.wait_with_output() {
-        Ok(output) => output
-            .stdout
-            .lines()
-            .into_iter()
-            .filter(|v| v.as_ref().is_ok_and(|line| line.starts_with("Path = ")))
-            .map(|v| v.unwrap().strip_prefix("Path = ").unwrap().to_string())
-            .filter(|file| !file.contains("/"))
-            .collect::<Vec<_>>(),
-        Err(e) => Err(ConversionError::Unspecific(format!("{}", e.to_string()))).unwrap(),
-    };
+/// Entries within the technical listing of 7z, one block of properties per entry
+///
+/// 7z prints names as they are stored, which need not be UTF-8. They are kept byte for byte,
+/// so that they match the extracted files.
+fn parse_entries(listing: &[u8]) -> Vec<(PathBuf, bool)> {
+    use std::os::unix::ffi::OsStrExt;
 
-    let has_root_within = archive_root_dirs.len() == 1 && *archive_root_dirs[0] == *archive_name;
-    let extract_dir = if has_root_within {
-        trace!("extract directly");
-        let parent_dir = cbz_path.parent().unwrap().to_path_buf();
-        assert_eq!(
-            parent_dir.join(archive_name),
-            get_conversion_root_dir(&cbz_path)
-        );
-        parent_dir
-    } else {
+    let mut entries = Vec::new();
+    let mut path = None;
+    let mut is_dir = false;
+    // a trailing empty line makes sure the last entry is handled as well
+    for line in listing.split(|&byte| byte == b'\n').chain([&b""[..]]) {
+        let line = line.trim_ascii_end();
+        if let Some(value) = line.strip_prefix(b"Path = ") {
+            let value = match std::str::from_utf8(value) {
+                Ok(value) => container::normalize_separators(value).into_bytes(),
+                Err(_) => {
+                    let name = String::from_utf8_lossy(value);
+                    warn!("the name of the entry {name:?} is not valid UTF-8");
+                    let separator = |byte: u8| if byte == b'\\' { b'/' } else { byte };
+                    value.iter().map(|&byte| separator(byte)).collect()
+                }
+            };
+            path = Some(PathBuf::from(std::ffi::OsStr::from_bytes(&value)));
+        } else if line == b"Folder = +" || line.starts_with(b"Attributes = D") {
+            is_dir = true;
+        } else if line.is_empty() {
+            if let Some(entry) = path.take() {
+                entries.push((entry, is_dir));
+            }
+            is_dir = false;
+        }
+    }
+    entries
+}
+
+/// Tool invocations needed to get from one format to another
+///
+/// Jxl images are decoded to `jxl_over` before encoding them to another new format, which
+/// should be Jpeg for recompressed Jpegs and Png otherwise.
+fn plan_steps(
+    from: ImageFormat,
+    to: ImageFormat,
+    config: &ConversionConfig,
+    jxl_over: ImageFormat,
+) -> Vec<Step> {
+    use Step::*;
+    let steps = match (from, to) {
+        (from, to) if from == to && config.recompress => vec![Convert(from, to)],
+        (Jpeg, Jpeg) | (Png, Png) | (Avif, Avif) | (Jxl, Jxl) | (Webp, Webp) => vec![],
+        (from @ Jpeg, to @ Png) if config.png_tool == PngTool::Oxipng => {
+            vec![Convert(from, to), Optimize(to)]
+        }
+        // cjpeg cannot fill in transparent areas
+        (from @ Png, to @ Jpeg)
+            if config.jpeg_tool == JpegTool::Mozjpeg && config.alpha_background.is_none() =>
+        {
+            vec![Encode(from, to)]
+        }
+        (from @ Jpeg, to @ Png) | (from @ Png, to @ Jpeg) => vec![Convert(from, to)],
+        (from @ (Jpeg | Png), to @ (Avif | Jxl | Webp)) => vec![Encode(from, to)],
+        (from @ (Avif | Jxl), to @ (Jpeg | Png)) => vec![Decode(from, to)],
+        (from @ Webp, to @ Png) => vec![Decode(from, to)],
+        (from @ Webp, to @ Jpeg) => vec![Decode(from, Png), Convert(Png, to)],
+        (from @ (Avif | Webp), to @ (Avif | Jxl | Webp)) => {
+            vec![Decode(from, Png), Encode(Png, to)]
+        }
+        (from @ Jxl, to @ (Avif | Webp)) => vec![Decode(from, jxl_over), Encode(jxl_over, to)],
+    };
+
+    // only magick can apply the image operations, so make sure it is part of the plan
+    let has_magick = steps.iter().any(|step| matches!(step, Convert(..)));
+    let mut steps = match steps[..] {
+        _ if !config.needs_magick() || has_magick => steps,
+        [Encode(from, to)] => vec![Convert(from, Png), Encode(Png, to)],
+        [Decode(from, to)] => vec![Decode(from, Png), Convert(Png, to)],
+        [Decode(from, over), Encode(_, to)] => {
+            vec![Decode(from, over), Convert(over, Png), Encode(Png, to)]
+        }
+        _ => steps,
+    };
+    // intermediate Png images are gone after the next step, optimizing them is wasted
+    let optimized = matches!(steps.last(), None | Some(Optimize(_)));
+    if config.optimize_png && to == Png && !optimized {
+        steps.push(Optimize(Png));
+    }
+    steps
+}
+
+/// Sort images by their path, comparing numbers by value so that 'page2' precedes 'page10'
+fn sort_reading_order(images: &mut [(PathBuf, ImageFormat)]) {
+    images.sort_by(|(a, _), (b, _)| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+}
+
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(ca), Some(cb)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let end_a = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+            let end_b = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+            let (num_a, num_b) = (
+                a[..end_a].trim_start_matches('0'),
+                b[..end_b].trim_start_matches('0'),
+            );
+            let order = num_a.len().cmp(&num_b.len()).then(num_a.cmp(num_b));
+            if order.is_ne() {
+                return order;
+            }
+            (a, b) = (&a[end_a..], &b[end_b..]);
+        } else {
+            if ca != cb {
+                return ca.cmp(&cb);
+            }
+            (a, b) = (&a[ca.len_utf8()..], &b[cb.len_utf8()..]);
+        }
+    }
+}
+
+/// Whether all entries are below a single root directory called `archive_name`
+fn has_root_within(archive_name: &std::ffi::OsStr, entries: &[(PathBuf, bool)]) -> bool {
+    let archive_root_dirs = entries
+        .iter()
+        .map(|(entry, _)| entry)
+        .filter(|entry| entry.components().count() == 1)
+        .collect::<Vec<_>>();
+    archive_root_dirs.len() == 1 && archive_root_dirs[0] == archive_name
+}
+
+/// Where the archive is extracted to, along with its entries
+fn get_extraction_root_dir(
+    cbz_path: &Path,
+) -> Result<(PathBuf, Vec<(PathBuf, bool)>), ConversionError> {
+    let archive_name = cbz_path.file_stem().unwrap();
+    let entries = archive_entries(cbz_path)?;
+    let root_dir = if has_root_within(archive_name, &entries) {
+        trace!("extract directly");
+        let parent_dir = cbz_path.parent().unwrap().to_path_buf();
+        assert_eq!(
+            parent_dir.join(archive_name),
+            get_conversion_root_dir(cbz_path)
+        );
+        parent_dir
+    } else {
         trace!("extract into new root directory");
-        get_conversion_root_dir(&cbz_path)
+        get_conversion_root_dir(cbz_path)
+    };
+    Ok((root_dir, entries))
+}
+
+/// Fail before extracting when an entry would exceed the path limits of the system
+///
+/// Leaves room for the suffix of intermediate files, which are written beside the images
+/// that are `converted`.
+fn check_path_lengths(
+    root_dir: &Path,
+    entries: &[(PathBuf, bool)],
+    converted: &HashSet<&Path>,
+) -> Result<(), ConversionError> {
+    let intermediate_suffix = format!(".{INTERMEDIATE_SUFFIX}.avifs").len();
+    let root = std::path::absolute(root_dir).unwrap_or(root_dir.to_path_buf());
+    for (entry, _) in entries {
+        let reserved = match converted.contains(entry.as_path()) {
+            true => intermediate_suffix,
+            false => 0,
+        };
+        let path_length = root.join(entry).as_os_str().len() + reserved;
+        let name_length = entry.file_name().map_or(0, |name| name.len()) + reserved;
+        let component_too_long = entry
+            .components()
+            .any(|component| component.as_os_str().len() > NAME_MAX);
+        if path_length >= libc::PATH_MAX as usize || name_length > NAME_MAX || component_too_long {
+            return Err(PathTooLong(entry.clone(), root_dir.to_path_buf()));
+        }
+    }
+    Ok(())
+}
+
+/// A file or directory to put into the converted archive
+struct ArchiveEntry {
+    /// Path within the archive
+    name: String,
+    path: PathBuf,
+    /// Size of files, directories have none
+    size: Option<u64>,
+}
+
+/// Spread the entries over parts that each stay below `limit` bytes, as indices into `entries`
+///
+/// Files are never split, a file larger than the limit gets a part of its own. Every part
+/// holds the directories its files are in.
+fn split_entries(container: Container, entries: &[ArchiveEntry], limit: u64) -> Vec<Vec<usize>> {
+    let dirs = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.size.is_none())
+        .map(|(index, entry)| (entry.name.as_str(), index))
+        .collect::<std::collections::HashMap<_, _>>();
+    let ancestors = |name: &str| {
+        Path::new(name)
+            .ancestors()
+            .skip(1)
+            .filter_map(|dir| dirs.get(dir.to_str().unwrap()).copied())
+            .collect::<Vec<_>>()
+    };
+
+    let has_files = entries
+        .iter()
+        .filter(|entry| entry.size.is_some())
+        .flat_map(|entry| ancestors(&entry.name))
+        .collect::<BTreeSet<_>>();
+
+    let mut parts: Vec<BTreeSet<usize>> = vec![BTreeSet::new()];
+    let mut part_size = container.empty_size();
+    for (index, entry) in entries.iter().enumerate() {
+        // directories come along with their files, only empty ones are placed on their own
+        let size = match entry.size {
+            Some(size) => size,
+            None if has_files.contains(&index) => continue,
+            None => 0,
+        };
+        let entry_size = |part: &BTreeSet<usize>| {
+            let missing_dirs = ancestors(&entry.name)
+                .into_iter()
+                .filter(|dir| !part.contains(dir))
+                .map(|dir| container.stored_size(&entries[dir].name, 0))
+                .sum::<u64>();
+            container.stored_size(&entry.name, size) + missing_dirs
+        };
+        let part = parts.last().unwrap();
+        let is_empty = part.iter().all(|&index| has_files.contains(&index));
+        if !is_empty && part_size + entry_size(part) > limit {
+            parts.push(BTreeSet::new());
+            part_size = container.empty_size();
+        }
+        let part = parts.last_mut().unwrap();
+        part_size += entry_size(part);
+        part.insert(index);
+        part.extend(ancestors(&entry.name));
+    }
+    parts
+        .into_iter()
+        .map(|part| part.into_iter().collect())
+        .collect()
+}
+
+/// Let `write` fill a file at a temporary path and move it to `path` once it succeeded
+///
+/// `write` gets the temporary path as well, to check what it wrote. An interrupt, failure or
+/// crash while writing never leaves a partial file behind at `path`, which would be taken for
+/// a finished conversion.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(File, &Path) -> Result<(), ConversionError>,
+) -> Result<(), ConversionError> {
+    let partial_path = write_partial(path, write)?;
+    move_into_place(&partial_path, path).inspect_err(|_| {
+        let _ = fs::remove_file(&partial_path);
+    })
+}
+
+/// Let `write` fill a file at a temporary path for `path`, which is on disk once this returns
+///
+/// The file is removed again if `write` fails, otherwise its path is returned.
+fn write_partial(
+    path: &Path,
+    write: impl FnOnce(File, &Path) -> Result<(), ConversionError>,
+) -> Result<PathBuf, ConversionError> {
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(format!(".{INTERMEDIATE_SUFFIX}"));
+    let partial_path = PathBuf::from(partial_path);
+    // a read-only or full directory is the user's to fix, so name the path they know
+    let file = File::create(&partial_path).map_err(|e| CannotCreate(path.to_path_buf(), e))?;
+    let result = write(file, &partial_path).and_then(|()| {
+        // the data has to be on disk before the name is, or a crash may leave it truncated
+        File::open(&partial_path)
+            .and_then(|file| file.sync_all())
+            .map_err(|e| Unspecific(format!("could not write archive {path:?}: {e}")))
+    });
+    match result {
+        Ok(()) => Ok(partial_path),
+        Err(e) => {
+            let _ = fs::remove_file(&partial_path);
+            Err(e)
+        }
+    }
+}
+
+/// Rename the file written by [`write_partial`] to its final `path`
+fn move_into_place(partial_path: &Path, path: &Path) -> Result<(), ConversionError> {
+    fs::rename(partial_path, path)
+        .map_err(|e| Unspecific(format!("could not move archive to {path:?}: {e}")))
+}
+
+/// Bytes that can still be written to the filesystem containing `dir`
+fn available_space(dir: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = match dir.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => dir,
+    };
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and statvfs only writes into the provided struct
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
     };
-    extract_dir
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Memory that can still be used without swapping, as the system estimates it
+fn available_memory() -> Option<u64> {
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    let memory = system.available_memory();
+    if !sysinfo::IS_SUPPORTED_SYSTEM || memory == 0 {
+        debug!("could not determine the available memory, not limiting the workers by it");
+        return None;
+    }
+    Some(memory)
 }
 
-fn get_conversion_root_dir(cbz_path: &PathBuf) -> PathBuf {
+/// One worker per core, as long as the memory suffices for all of them
+fn default_workers(cores: usize, memory: Option<u64>, per_worker: u64) -> usize {
+    match memory {
+        Some(memory) => cores.min((memory / per_worker).max(1) as usize),
+        None => cores,
+    }
+}
+
+fn get_conversion_root_dir(cbz_path: &Path) -> PathBuf {
     let dir = cbz_path.parent().unwrap();
     let name = cbz_path.file_stem().unwrap();
-    let root_dir = dir.join(name);
-    root_dir
+    dir.join(name)
 }
 
-fn already_converted(path: &PathBuf, format: ImageFormat) -> bool {
-    let conversion_ending = format!(".{}.cbz", format.to_string());
-
-    let dir = path.parent().unwrap();
-    let name = path.file_stem().unwrap();
-    let zip_path = dir.join(format!("{}{}", name.to_str().unwrap(), conversion_ending));
+fn already_converted(path: &Path, config: &ConversionConfig) -> bool {
+    let container = match Container::from_path(path) {
+        Some(container) => container,
+        None => return false,
+    };
+    let mut zip_path = config.converted_path(path, container);
+    if !zip_path.exists() {
+        // an archive split by size has no file of the plain name
+        zip_path = config.part_path(path, container, 1);
+    }
 
-    let is_converted_archive = path.to_str().unwrap().ends_with(&conversion_ending);
-    let has_converted_archive = zip_path.exists();
+    let is_converted_archive = config.is_converted_archive(path);
+    let has_converted_archive = match config.incremental {
+        true => zip_path.exists() && !is_newer(path, &zip_path),
+        false => zip_path.exists(),
+    };
 
     trace!(" is converted archive? {is_converted_archive}");
     trace!("has converted archive? {has_converted_archive}");
     is_converted_archive || has_converted_archive
 }
 
+/// Whether the file at `path` was modified after the one at `other`
+fn is_newer(path: &Path, other: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified());
+    match (modified(path), modified(other)) {
+        (Ok(modified), Ok(other_modified)) => modified > other_modified,
+        _ => false,
+    }
+}
+
 fn convert_single_cbz(
-    cbz_file: &PathBuf,
-    format: ImageFormat,
+    cbz_file: &Path,
+    config: &ConversionConfig,
     workers: usize,
-    force: bool,
+    reporter: &mut dyn ConversionReporter,
+    interrupted: &AtomicBool,
 ) -> Result<(), ConversionError> {
     trace!("called convert_single_cbz() with {:?}", cbz_file);
-    if already_converted(&cbz_file, format) {
-        return Err(AlreadyDone(cbz_file.to_path_buf()));
-    }
-
-    let work_unit = WorkUnit::new(&cbz_file, format, workers, force)?;
-    work_unit.run()
+    prepare_cbz(cbz_file, config, workers, None, interrupted)?.run(reporter, interrupted)
 }
 
-fn convert_only_when_forced(from: ImageFormat, to: ImageFormat) -> bool {
-    match (from, to) {
-        (Jpeg | Png, _) => false,
-        (_, Jpeg | Png) => false,
-        (_, _) => true,
+/// Convert a directory of images as if it was an archive, writing the converted archive
+/// beside it like book.avif.cbz for a directory named book
+///
+/// The images are packed into an archive in a directory beside it first, which is converted
+/// like any other. The directory itself is left as it is.
+fn convert_folder(
+    dir: &Path,
+    config: &ConversionConfig,
+    workers: usize,
+    reporter: &mut dyn ConversionReporter,
+    interrupted: &AtomicBool,
+) -> Result<(), ConversionError> {
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| Unspecific(format!("could not find {dir:?}: {e}")))?;
+    let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+        return Err(NotAnArchive(dir));
+    };
+    let name = name.to_string_lossy();
+    if config
+        .converted_path(&parent.join(format!("{name}.cbz")), Container::Zip)
+        .exists()
+    {
+        return Err(AlreadyDone(dir.clone()));
     }
+
+    let packing_dir = parent.join(format!("{name}.{INTERMEDIATE_SUFFIX}"));
+    fs::create_dir(&packing_dir).map_err(|e| CannotCreate(packing_dir.clone(), e))?;
+    let packed = packing_dir.join(format!("{name}.cbz"));
+    let result = pack_folder(&dir, &packed)
+        .map_err(|e| CannotCreate(packed.clone(), e))
+        .and_then(|()| convert_single_cbz(&packed, config, workers, reporter, interrupted))
+        .and_then(|()| {
+            // the converted archive and any parts of it
+            let move_out = || -> io::Result<()> {
+                for entry in fs::read_dir(&packing_dir)?.flatten() {
+                    if entry.path() != packed {
+                        fs::rename(entry.path(), parent.join(entry.file_name()))?;
+                    }
+                }
+                Ok(())
+            };
+            move_out().map_err(|e| Unspecific(format!("could not move the archive out: {e}")))
+        });
+    let _ = fs::remove_dir_all(&packing_dir);
+    result
 }
 
-#[derive(Parser)]
-#[command(version, verbatim_doc_comment)]
-/// Convert images within comic archives to newer image formats
+/// Convert all archives in the directory into the single archive at `merge_path`
 ///
-/// Convert images within Zip Comic Book archives, although it also works with normal zip files.
-/// By default only converts Jpeg and Png to the target format or decode any formats to Png and
-/// Jpeg.
-struct Args {
-    #[arg(
-        required = true,
-        help = "All images within the archive(s) are converted to this format"
-    )]
-    format: ImageFormat,
+/// The archives are extracted into directories named after them beside `merge_path`, which
+/// are packed and converted like `convert_folder` does with a directory. Their pages follow
+/// each other by the name of the archive, then in reading order.
+fn merge_archives(
+    dir: &Path,
+    merge_path: &Path,
+    config: &ConversionConfig,
+    workers: usize,
+    reporter: &mut dyn ConversionReporter,
+    interrupted: &AtomicBool,
+) -> Result<(), ConversionError> {
+    // packing writes zip archives
+    if Container::from_path(merge_path) != Some(Container::Zip) {
+        return Err(NotAnArchive(merge_path.to_path_buf()));
+    }
+    if merge_path.exists() {
+        return Err(AlreadyDone(merge_path.to_path_buf()));
+    }
+    let (Some(parent), Some(name)) = (merge_path.parent(), merge_path.file_stem()) else {
+        return Err(NotAnArchive(merge_path.to_path_buf()));
+    };
+    let name = name.to_string_lossy();
+    let staging_dir = parent.join(format!("{name}.{INTERMEDIATE_SUFFIX}"));
+    fs::create_dir(&staging_dir).map_err(|e| CannotCreate(staging_dir.clone(), e))?;
+    let result = merge_into_staging(
+        dir,
+        &staging_dir,
+        &name,
+        config,
+        workers,
+        reporter,
+        interrupted,
+    )
+    .and_then(|converted| {
+        let move_out = || -> io::Result<()> {
+            match converted {
+                Some(converted) => fs::rename(converted, merge_path),
+                // the parts of a split archive keep their names
+                None => {
+                    for entry in fs::read_dir(&staging_dir)?.flatten() {
+                        let is_part = entry.path().extension().is_some_and(|ext| ext == "cbz");
+                        if is_part && entry.path() != staging_dir.join(format!("{name}.cbz")) {
+                            fs::rename(entry.path(), parent.join(entry.file_name()))?;
+                        }
+                    }
+                    Ok(())
+                }
+            }
+        };
+        move_out().map_err(|e| Unspecific(format!("could not move the archive out: {e}")))
+    });
+    let _ = fs::remove_dir_all(&staging_dir);
+    result
+}
 
-    #[arg(
-        default_value = ".",
-        help = "Path to a cbz file or a directory containing cbz files"
-    )]
-    path: PathBuf,
+/// Extract all archives of `dir` below `staging_dir/name`, pack and convert them, giving the
+/// converted archive or `None` for the parts of a split one
+fn merge_into_staging(
+    dir: &Path,
+    staging_dir: &Path,
+    name: &str,
+    config: &ConversionConfig,
+    workers: usize,
+    reporter: &mut dyn ConversionReporter,
+    interrupted: &AtomicBool,
+) -> Result<Option<PathBuf>, ConversionError> {
+    let pages_dir = staging_dir.join(name);
+    let mut archives = archive_paths(dir, config)
+        .into_iter()
+        .filter(|archive| Container::from_path(archive).is_some())
+        .collect::<Vec<_>>();
+    archives.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    for archive in archives.iter() {
+        if interrupted.load(Ordering::Relaxed) {
+            return Err(Interrupt);
+        }
+        info!("Adding {archive:?}");
+        let archive_name = archive.file_stem().unwrap();
+        let extract_dir = pages_dir.join(archive_name);
+        fs::create_dir_all(&extract_dir).map_err(|e| CannotCreate(extract_dir.clone(), e))?;
+        let root_dir = match has_root_within(archive_name, &archive_entries(archive)?) {
+            true => &pages_dir,
+            false => &extract_dir,
+        };
+        let container = Container::from_path(archive).unwrap();
+        extract_archive(archive, container, root_dir, &extract_dir)?;
+    }
+    if archives.is_empty() {
+        return Err(NothingToDo(dir.to_path_buf()));
+    }
 
-    /// Number of processes spawned
-    ///
-    /// Uses as many processes as you have cores by default.
-    /// When used as a flag only spawns a single process at a time.
-    #[arg(short = 'j', long, verbatim_doc_comment)]
-    workers: Option<Option<usize>>,
+    let packed = staging_dir.join(format!("{name}.cbz"));
+    pack_folder(&pages_dir, &packed).map_err(|e| CannotCreate(packed.clone(), e))?;
+    // the packed archive is extracted to the same place again
+    fs::remove_dir_all(&pages_dir).map_err(|e| Unspecific(e.to_string()))?;
+    match convert_single_cbz(&packed, config, workers, reporter, interrupted) {
+        Ok(()) => {
+            let converted = config.converted_path(&packed, Container::Zip);
+            Ok(Some(converted).filter(|converted| converted.exists()))
+        }
+        // all images are in the target format already
+        Err(NothingToDo(_)) => Ok(Some(packed)),
+        Err(e) => Err(e),
+    }
+}
 
-    #[arg(short, long, help = "Convert all images of all formats")]
-    force: bool,
+/// Write all files below `dir` into a zip archive, below a root directory named like `dir`
+fn pack_folder(dir: &Path, zip_path: &Path) -> io::Result<()> {
+    let name = dir.file_name().unwrap().to_string_lossy();
+    let file = File::create(zip_path)?;
+    let mut writer = ContainerWriter::new(Container::Zip, file, EntryModes::default(), false)?;
+    writer.add_directory(&name, dir)?;
+    // in reading order, for archives that are not converted afterwards
+    let walk = WalkDir::new(dir).min_depth(1).sort_by(|a, b| {
+        natural_cmp(
+            &a.file_name().to_string_lossy(),
+            &b.file_name().to_string_lossy(),
+        )
+    });
+    for entry in walk {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dir).unwrap();
+        let entry_name = format!("{name}/{}", relative.to_string_lossy());
+        match entry.file_type().is_dir() {
+            true => writer.add_directory(&entry_name, entry.path())?,
+            false => writer.add_file(&entry_name, entry.path())?,
+        }
+    }
+    writer.finish()
 }
 
-fn main() -> Result<()> {
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
-        .format_timestamp_secs()
-        .parse_env("RUST_LOG")
-        .init();
+/// Plan the conversion of the archive and extract it, or link the `pristine` extraction
+fn prepare_cbz(
+    cbz_file: &Path,
+    config: &ConversionConfig,
+    workers: usize,
+    pristine: Option<&Path>,
+    interrupted: &AtomicBool,
+) -> Result<WorkUnit, ConversionError> {
+    if already_converted(cbz_file, config) {
+        return Err(AlreadyDone(cbz_file.to_path_buf()));
+    }
 
-    let matches = Args::parse();
-    let format = matches.format;
-    let path = matches.path;
-    if !path.exists() {
-        error!("does not exists: {:?}", path);
-        exit(1);
+    let mut work_unit = WorkUnit::new(cbz_file, config, workers)?;
+    // better to fail before extracting than in the middle of converting
+    let inspects_jxl = work_unit.job_queue.iter().any(ConversionJob::inspects_jxl);
+    if inspects_jxl && !config.best_effort && !spawn::is_installed(Tool::Jxlinfo) {
+        return Err(SpawnFailure(Tool::Jxlinfo.program().to_string()));
+    }
+    if config.skip_unsatisfiable {
+        let missing = work_unit
+            .required_tools()
+            .into_iter()
+            .filter(|&tool| !spawn::is_installed(tool))
+            .map(|tool| tool.to_string())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(MissingTools(cbz_file.to_path_buf(), missing.join(", ")));
+        }
     }
+    match pristine {
+        Some(pristine) => work_unit.extract_from(pristine)?,
+        None => work_unit.extract(interrupted)?,
+    }
+    Ok(work_unit)
+}
 
-    let workers = match matches.workers {
-        Some(Some(value)) => value,
-        Some(None) => 1,
-        None => match thread::available_parallelism() {
-            Ok(value) => value.get(),
-            Err(_) => 1,
-        },
+/// The archive at `path` or all entries of the directory at `path`
+fn archive_paths(path: &Path, config: &ConversionConfig) -> Vec<PathBuf> {
+    let excluded = |dir: &Path| {
+        config
+            .exclude_dirs
+            .iter()
+            .any(|exclude| dir.ends_with(exclude))
     };
-
-    let force = matches.force;
-
-    if path.is_dir() {
-        for cbz_file in path.read_dir().expect("could not read dir") {
-            if let Ok(cbz_file) = cbz_file {
-                let cbz_file = cbz_file.path();
-                info!("Converting {:?}", cbz_file);
-                match convert_single_cbz(&cbz_file, format, workers, force) {
-                    Ok(()) => info!("Done"),
-                    Err(NothingToDo(path)) => info!("Nothing to do for {path:?}"),
-                    Err(AlreadyDone(path)) => info!("Already converted {path:?}"),
-                    Err(NotAnArchive(_)) => info!("This is not a Zip archive"),
-                    Err(e) => {
-                        error!("{e}");
-                        break;
-                    }
+    let paths: Vec<PathBuf> = match path.is_dir() {
+        true if config.recursive => WalkDir::new(path)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0
+                    || !(is_extraction_dir(entry.path())
+                        || entry.file_type().is_dir() && excluded(entry.path()))
+            })
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect(),
+        true => path
+            .read_dir()
+            .expect("could not read dir")
+            .flatten()
+            .map(|entry| entry.path())
+            .collect(),
+        false => return vec![path.to_path_buf()],
+    };
+    paths
+        .into_iter()
+        .filter(|file| {
+            if config.retarget {
+                let stem = converted_stem(file);
+                let retargets =
+                    stem.is_some_and(|stem| strip_format_suffix(stem, config).is_some());
+                if !retargets {
+                    debug!("skip {file:?}, only converted archives are converted again");
                 }
+                retargets
+            } else if looks_converted(file, config) && !config.include_converted {
+                debug!("skip {file:?}, it looks like the output of this conversion");
+                false
+            } else {
+                true
             }
+        })
+        .collect()
+}
+
+/// Whether the archive at `path` is named like the output of this conversion, like
+/// book.webp.cbz or one of its parts for webp
+fn looks_converted(path: &Path, config: &ConversionConfig) -> bool {
+    converted_stem(path).is_some_and(|stem| stem.ends_with(&config.converted_suffix()))
+}
+
+/// The stem of the archive at `path` without the number of a part, like book.webp for
+/// book.webp.part01.cbz
+fn converted_stem(path: &Path) -> Option<&str> {
+    Container::from_path(path)?;
+    let stem = path.file_stem()?.to_str()?;
+    match stem.rsplit_once(".part") {
+        Some((name, number))
+            if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            Some(name)
         }
-    } else {
-        if let Err(e) = convert_single_cbz(&path, format, workers, force) {
-            match e {
-                NothingToDo(_) => info!("Nothing to do for {path:?}"),
-                NotAnArchive(_) => info!("This is not a Zip archive"),
-                _ => error!("{e}"),
+        _ => Some(stem),
+    }
+}
+
+/// The stem of a converted archive without the format it was converted to, like book for
+/// book.avif, or `None` if it ends in no format
+fn strip_format_suffix<'a>(stem: &'a str, config: &ConversionConfig) -> Option<&'a str> {
+    use clap::ValueEnum;
+
+    ImageFormat::value_variants()
+        .iter()
+        .flat_map(|format| {
+            let extensions = format.extensions().iter().copied();
+            extensions.chain([format.ext_for(config)])
+        })
+        .find_map(|ext| stem.strip_suffix(ext)?.strip_suffix('.'))
+}
+
+/// Archives of a job spec, each with the config for its conversion
+fn spec_archives(
+    spec_path: &Path,
+    format: Option<ImageFormat>,
+    config: &ConversionConfig,
+) -> Result<Vec<(PathBuf, ConversionConfig)>, String> {
+    let spec = JobSpec::read(spec_path)?;
+    let mut archives = vec![];
+    for (index, archive) in spec.archives.into_iter().enumerate() {
+        let mut config = config.clone();
+        config.target = archive.target.or(format).ok_or_else(|| {
+            format!("archives[{index}]: no target given, neither in the spec nor as argument")
+        })?;
+        if let Some(quality) = archive.quality {
+            config.quality.set(config.target, quality);
+        }
+        archives.push((archive.path, config));
+    }
+    Ok(archives)
+}
+
+/// Convert one archive after the other, skipping those that need no conversion
+///
+/// The next archive is extracted while the current one is converted, so that the disk is
+/// busy while the workers are. The same archive following with another target starts from
+/// a hard linked copy of the extraction instead. Returns the number of archives that failed.
+fn convert_batch(
+    archives: impl IntoIterator<Item = (PathBuf, ConversionConfig), IntoIter: ExactSizeIterator>,
+    workers: usize,
+    reporter: &mut dyn ConversionReporter,
+    interrupted: &AtomicBool,
+) -> usize {
+    let mut archives = archives.into_iter().peekable();
+    let total = archives.len();
+    let mut failed = 0;
+    let mut pristine: Option<PathBuf> = None;
+    thread::scope(|scope| {
+        let prepare = |(cbz_file, config): (PathBuf, ConversionConfig),
+                       pristine: Option<PathBuf>| {
+            let archive = (cbz_file.clone(), config.clone());
+            let preparation = scope.spawn(move || {
+                prepare_cbz(
+                    &cbz_file,
+                    &config,
+                    workers,
+                    pristine.as_deref(),
+                    interrupted,
+                )
+            });
+            (archive, preparation)
+        };
+        let mut next = archives.next().map(|archive| prepare(archive, None));
+        let mut index = 0;
+        while let Some(((cbz_file, config), preparation)) = next.take() {
+            if interrupted.load(Ordering::Relaxed) {
+                break;
+            }
+            index += 1;
+            info!(
+                "Converting archive {index}/{total}: {cbz_file:?} to {}",
+                config.target
+            );
+            let work_unit = preparation.join().expect("extraction thread panicked");
+            let same_next = archives.peek().is_some_and(|(next, _)| *next == cbz_file);
+            if same_next && pristine.is_none() && work_unit.is_ok() {
+                pristine = keep_pristine(&cbz_file)
+                    .inspect_err(|e| warn!("extract {cbz_file:?} again for the next target: {e}"))
+                    .ok();
+            }
+            if work_unit.is_ok() && !same_next {
+                next = archives.next().map(|archive| prepare(archive, None));
+            }
+            let result = work_unit.and_then(|work_unit| work_unit.run(reporter, interrupted));
+            if archive_failed(result, &config) {
+                failed += 1;
+                if config.on_error == OnError::FailFast {
+                    break;
+                }
+            }
+            if !same_next {
+                remove_pristine(&mut pristine);
             }
+            if next.is_none() {
+                next = archives
+                    .next()
+                    .map(|archive| prepare(archive, pristine.clone()));
+            }
+        }
+    });
+    remove_pristine(&mut pristine);
+    failed
+}
+
+/// Log how the conversion of an archive ended, returns whether it failed
+fn archive_failed(result: Result<(), ConversionError>, config: &ConversionConfig) -> bool {
+    match result {
+        Ok(()) => info!("Done"),
+        Err(NothingToDo(path)) => info!("Nothing to do for {path:?}"),
+        Err(AlreadyDone(path)) => info!("Already converted {path:?}"),
+        Err(NotAnArchive(_)) => info!("This is not a supported archive"),
+        Err(e @ (ListingFailed(_) | PathTooLong(..) | MissingTools(..))) => {
+            warn!("Skipping archive: {e}")
+        }
+        Err(e) => {
+            error!("{}", error_report(&e, config.verbose_errors));
+            return true;
+        }
+    }
+    false
+}
+
+/// Convert up to `archive_workers` archives at once, each with `workers` processes of its own
+///
+/// Every archive is reported on its own and added to `reporter` once it is done, so that
+/// the progress of one archive is not mixed up with another. Returns the number of archives
+/// that failed.
+fn convert_parallel(
+    archives: Vec<(PathBuf, ConversionConfig)>,
+    archive_workers: usize,
+    workers: usize,
+    reporter: &mut LogReporter,
+    interrupted: &AtomicBool,
+) -> usize {
+    let total = archives.len();
+    let queue = Mutex::new(archives.into_iter().enumerate());
+    let reporter = Mutex::new(reporter);
+    let failed = AtomicUsize::new(0);
+    // set by a failed archive with --on-error fail-fast, no further archives are started
+    let stopped = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..archive_workers {
+            scope.spawn(|| loop {
+                if interrupted.load(Ordering::Relaxed) || stopped.load(Ordering::Relaxed) {
+                    return;
+                }
+                let Some((index, (cbz_file, config))) = queue.lock().unwrap().next() else {
+                    return;
+                };
+                info!(
+                    "Converting archive {}/{total}: {cbz_file:?} to {}",
+                    index + 1,
+                    config.target
+                );
+                let mut archive_reporter = reporter.lock().unwrap().for_archive();
+                let result = prepare_cbz(&cbz_file, &config, workers, None, interrupted)
+                    .and_then(|work_unit| work_unit.run(&mut archive_reporter, interrupted));
+                if archive_failed(result, &config) {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    if config.on_error == OnError::FailFast {
+                        stopped.store(true, Ordering::Relaxed);
+                    }
+                }
+                reporter.lock().unwrap().merge(&archive_reporter);
+            });
+        }
+    });
+    failed.into_inner()
+}
+
+/// Archive and image workers within `max_processes`, which archive workers give way to first
+fn limit_processes(
+    archive_workers: usize,
+    workers: usize,
+    max_processes: Option<usize>,
+) -> (usize, usize) {
+    let Some(max) = max_processes else {
+        return (archive_workers, workers);
+    };
+    let archive_workers = archive_workers.min(max).max(1);
+    (archive_workers, workers.min(max / archive_workers).max(1))
+}
+
+/// Hard link identical converted archives to each other and print the bytes saved
+fn link_identical_archives(converted: &[PathBuf]) {
+    let written = converted
+        .iter()
+        .filter(|path| path.is_file())
+        .cloned()
+        .collect::<Vec<_>>();
+    let duplicates = match dedupe::duplicates_among(&written) {
+        Ok(duplicates) => duplicates,
+        Err(e) => {
+            warn!("could not look for identical archives: {e}");
+            return;
+        }
+    };
+    if duplicates.is_empty() {
+        return;
+    }
+    output::stdout("identical archives, hard linked");
+    let mut saved = 0;
+    for group in duplicates.iter() {
+        let files = group
+            .files
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect::<Vec<_>>();
+        output::stdout(format!("  {}", files.join(", ")));
+        match dedupe::hard_link(group) {
+            Ok(bytes) => saved += bytes,
+            Err(e) => warn!("could not hard link {:?}: {e}", group.files),
+        }
+    }
+    output::stdout(format!("  {saved} bytes saved"));
+}
+
+/// Hard link the extraction of the archive to a directory beside it, for further targets
+fn keep_pristine(cbz_file: &Path) -> io::Result<PathBuf> {
+    let extract_dir = get_conversion_root_dir(cbz_file);
+    let mut pristine = extract_dir.clone().into_os_string();
+    pristine.push(format!(".{INTERMEDIATE_SUFFIX}"));
+    let pristine = PathBuf::from(pristine);
+    if pristine.exists() {
+        // left over by a run that was killed
+        fs::remove_dir_all(&pristine)?;
+    }
+    link_tree(&extract_dir, &pristine)?;
+    Ok(pristine)
+}
+
+fn remove_pristine(pristine: &mut Option<PathBuf>) {
+    if let Some(pristine) = pristine.take() {
+        let _ = fs::remove_dir_all(pristine);
+    }
+}
+
+/// Recreate the directory tree at `to` with images hard linked and all other files copied
+///
+/// Other files like the documents of a book may be written to in place.
+fn link_tree(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if image_format(entry.path()).is_some() {
+            fs::hard_link(entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
         }
     }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Give the file at `path` its own copy of the data if it shares it with other links
+fn break_hard_link(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
 
-    #[test]
-    fn test_check_for_compressed_jxl() {
-        let compressed_path = PathBuf::from("test_data/compressed.jxl");
-        assert!(compressed_path.exists());
-        let out = jxl_is_compressed_jpeg(&compressed_path).unwrap();
-        assert_eq!(out, true);
+    match fs::metadata(path) {
+        Ok(meta) if meta.nlink() > 1 => {
+            let mut copy = path.as_os_str().to_owned();
+            copy.push(format!(".{INTERMEDIATE_SUFFIX}"));
+            fs::copy(path, &copy)?;
+            fs::rename(&copy, path)
+        }
+        _ => Ok(()),
     }
+}
 
-    #[test]
-    fn test_check_for_encoded_jxl() {
-        let encoded_path = PathBuf::from("test_data/encoded.jxl");
-        assert!(encoded_path.exists());
-        let out = jxl_is_compressed_jpeg(&encoded_path).unwrap();
-        assert_eq!(out, false);
+/// The error as logged for a failed archive, `verbose` adds everything there is to know
+fn error_report(e: &ConversionError, verbose: bool) -> String {
+    use std::error::Error;
+
+    if !verbose {
+        return e.to_string();
+    }
+    let mut report = format!("{e}\n  {e:?}");
+    let mut source = e.source();
+    while let Some(cause) = source {
+        report.push_str(&format!("\n  caused by: {cause:?}"));
+        source = cause.source();
+    }
+    report
+}
+
+/// Whether the directory is where an archive beside it gets extracted to
+///
+/// Such a directory is left over when a conversion was killed, the archives within belong
+/// to the extracted one and must not be picked up on their own.
+fn is_extraction_dir(dir: &Path) -> bool {
+    dir.is_dir()
+        && container::ARCHIVE_EXTENSIONS.iter().any(|ext| {
+            let mut archive = dir.as_os_str().to_owned();
+            archive.push(format!(".{ext}"));
+            Path::new(&archive).is_file()
+        })
+}
+
+/// Whether the file is neither an archive nor a file written beside one by a conversion
+fn is_unknown_file(path: &Path) -> bool {
+    let is_sidecar = |path: &Path| {
+        let name = path.to_string_lossy();
+        let archive = name
+            .strip_suffix(".log")
+            .or_else(|| name.strip_suffix(&format!(".{INTERMEDIATE_SUFFIX}")));
+        archive.is_some_and(|archive| Container::from_path(Path::new(archive)).is_some())
+    };
+    path.is_file() && Container::from_path(path).is_none() && !is_sidecar(path) && !is_junk(path)
+}
+
+/// Number of images that would be converted within all archives at `path`
+fn images_to_convert(path: &Path, config: &ConversionConfig) -> usize {
+    archive_paths(path, config)
+        .iter()
+        .filter(|cbz_file| !already_converted(cbz_file, config))
+        .filter_map(|cbz_file| WorkUnit::new(cbz_file, config, 1).ok())
+        .map(|work_unit| work_unit.job_queue.len())
+        .sum()
+}
+
+/// Ask the user on the terminal, refusing when there is nobody to answer
+fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        error!("{question} Not asking without a terminal, pass --yes to continue anyway");
+        return false;
+    }
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Print every file within the archive at `path` with the format it is treated as
+fn list(path: &Path, json: bool) -> Result<(), ConversionError> {
+    output::stdout(listing(path, json)?);
+    Ok(())
+}
+
+/// Every file of the archive with its format, and the size of images
+fn listing(path: &Path, json: bool) -> Result<String, ConversionError> {
+    let Some(container) = Container::from_path(path) else {
+        return Err(NotAnArchive(path.to_path_buf()));
+    };
+    let mut files = files_in_archive(path)?;
+    files.sort_by(|(a, _), (b, _)| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    let dimensions = archive_dimensions(path, container);
+    if !json {
+        let lines = files.iter().map(|(file, format)| {
+            let format = format.map_or("non-image".to_string(), |format| format.to_string());
+            match dimensions.get(file) {
+                Some((width, height)) => format!("{}: {format} {width}x{height}", file.display()),
+                None => format!("{}: {format}", file.display()),
+            }
+        });
+        return Ok(lines.collect::<Vec<_>>().join("\n"));
+    }
+    let entries = files
+        .iter()
+        .map(|(file, format)| {
+            let dimensions = dimensions.get(file);
+            ListedFile {
+                path: file.to_string_lossy().to_string(),
+                format: *format,
+                width: dimensions.map(|(width, _)| *width),
+                height: dimensions.map(|(_, height)| *height),
+            }
+        })
+        .collect::<Vec<_>>();
+    Ok(output::json(&entries))
+}
+
+/// A file within the archive, as --list --json prints it
+#[derive(Serialize)]
+struct ListedFile {
+    path: String,
+    format: Option<ImageFormat>,
+    width: Option<u64>,
+    height: Option<u64>,
+}
+
+/// Width and height of the images in the archive, as far as their headers can be read
+///
+/// The headers are read from the archive itself, which is left as it is. Archives that only
+/// 7z can read are listed without dimensions.
+fn archive_dimensions(path: &Path, container: Container) -> HashMap<PathBuf, (u64, u64)> {
+    match container.file_headers(path, dimensions::HEADER_LENGTH) {
+        Ok(headers) => headers
+            .into_iter()
+            .filter_map(|(name, header)| Some((name, dimensions::dimensions(&header)?)))
+            .collect(),
+        Err(e) => {
+            debug!("could not read the images of {path:?} for their dimensions: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Formats a sample archive is converted to by --bench
+const BENCH_FORMATS: [ImageFormat; 3] = [Avif, Jxl, Webp];
+
+/// Convert copies of the sample archive to every candidate format and compare the results
+fn bench(
+    sample: &Path,
+    config: &ConversionConfig,
+    workers: usize,
+    interrupted: &AtomicBool,
+) -> Result<(), ConversionError> {
+    let io_error = |e: io::Error| Unspecific(format!("could not prepare the benchmark: {e}"));
+    if Container::from_path(sample).is_none() {
+        return Err(NotAnArchive(sample.to_path_buf()));
+    }
+    let original_size = fs::metadata(sample).map_err(io_error)?.len();
+    let bench_dir = std::env::temp_dir().join(format!("cbz_in_bench_{}", std::process::id()));
+
+    let mut rows = vec![];
+    for target in BENCH_FORMATS {
+        if interrupted.load(Ordering::Relaxed) {
+            break;
+        }
+        info!("Converting {sample:?} to {target}");
+        let dir = bench_dir.join(target.to_string());
+        fs::create_dir_all(&dir).map_err(io_error)?;
+        let copy = dir.join(sample.file_name().unwrap());
+        fs::copy(sample, &copy).map_err(io_error)?;
+        let config = ConversionConfig {
+            target,
+            replace: false,
+            incremental: false,
+            ..config.clone()
+        };
+        let mut reporter = LogReporter::default();
+        let start = Instant::now();
+        let result = convert_single_cbz(&copy, &config, workers, &mut reporter, interrupted);
+        let elapsed = start.elapsed();
+        let container = Container::from_path(&copy).unwrap();
+        let size = match result {
+            Ok(()) => fs::metadata(config.converted_path(&copy, container))
+                .map_err(io_error)?
+                .len(),
+            // every image is in this format already
+            Err(NothingToDo(_)) => original_size,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&bench_dir);
+                return Err(e);
+            }
+        };
+        rows.push((target, size, elapsed, reporter.failed));
+    }
+    let _ = fs::remove_dir_all(&bench_dir);
+
+    output::stdout(format!(
+        "{:<8}{:>12}{:>10}{:>10}{:>8}",
+        "format", "size", "of orig.", "time", "failed"
+    ));
+    output::stdout(format!(
+        "{:<8}{:>12}",
+        "original",
+        human_size(original_size)
+    ));
+    for (target, size, elapsed, failed) in rows {
+        output::stdout(format!(
+            "{:<8}{:>12}{:>9.1}%{:>9.1}s{:>8}",
+            target.to_string(),
+            human_size(size),
+            size as f64 * 100.0 / original_size.max(1) as f64,
+            elapsed.as_secs_f64(),
+            failed
+        ));
+    }
+    Ok(())
+}
+
+/// Smallest Jpeg that the tools accept, a gray square of 8x8 pixels in a single block
+#[rustfmt::skip]
+const SELF_TEST_JPEG: &[u8] = &[
+    0xFF, 0xD8,
+    // quantization table of all ones
+    0xFF, 0xDB, 0x00, 0x43, 0x00,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    // baseline frame of 8x8 pixels with one component
+    0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x00, 0x08, 0x00, 0x08, 0x01, 0x01, 0x11, 0x00,
+    // Huffman tables that only know a difference of zero and the end of block
+    0xFF, 0xC4, 0x00, 0x14, 0x00, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00,
+    0xFF, 0xC4, 0x00, 0x14, 0x10, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00,
+    0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00,
+    // both codes, padded with ones
+    0x3F,
+    0xFF, 0xD9,
+];
+
+/// Formats the built-in Jpeg of `--self-test` is converted to and back from
+const SELF_TEST_FORMATS: [ImageFormat; 4] = [Png, Avif, Jxl, Webp];
+
+/// A conversion of the self test with the tools used for it or what went wrong
+type SelfTestResult = (ImageFormat, ImageFormat, Result<Vec<Tool>, String>);
+
+/// Directory of the self test, removed with everything in it once the test is over
+struct SelfTestDir(PathBuf);
+
+impl Drop for SelfTestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Convert a built-in Jpeg to every other format and back to Jpeg with the installed tools
+///
+/// Gives the tools used for every conversion or what went wrong, a conversion back is only
+/// tried when the one to its format succeeded.
+fn self_test(config: &ConversionConfig) -> Vec<SelfTestResult> {
+    // removed again on every return
+    let guard = SelfTestDir(self_test_dir());
+    let dir = &guard.0;
+    let original = dir.join("original.jpg");
+    if let Err(e) = fs::create_dir_all(dir).and_then(|()| fs::write(&original, SELF_TEST_JPEG)) {
+        let error = format!("could not write {original:?}: {e}");
+        return vec![(Jpeg, Jpeg, Err(error))];
+    }
+
+    let mut results = vec![];
+    for format in SELF_TEST_FORMATS {
+        let converted = dir.join(format!("converted.{}", format.ext_for(config)));
+        let result = self_test_conversion(Jpeg, format, &original, &converted, config);
+        let converted_fine = result.is_ok();
+        results.push((Jpeg, format, result));
+        if converted_fine {
+            let back = dir.join(format!("back_from_{format}.jpg"));
+            let result = self_test_conversion(format, Jpeg, &converted, &back, config);
+            results.push((format, Jpeg, result));
+        }
+    }
+    results
+}
+
+/// Directory the self test works in, one per process
+fn self_test_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("cbz_in_self_test_{}", std::process::id()))
+}
+
+/// Run the planned steps from `input` to `output`, giving the tools used or the error of
+/// the one that failed
+fn self_test_conversion(
+    from: ImageFormat,
+    to: ImageFormat,
+    input: &Path,
+    output: &Path,
+    config: &ConversionConfig,
+) -> Result<Vec<Tool>, String> {
+    // the Jxl image of the self test is a recompressed Jpeg
+    let steps = plan_steps(from, to, config, Jpeg);
+    let mut tools = vec![];
+    let mut input = input.to_path_buf();
+    for (index, step) in steps.iter().enumerate() {
+        let step_output = match index + 1 == steps.len() {
+            true => output.to_path_buf(),
+            false => output.with_extension(format!("{INTERMEDIATE_SUFFIX}{index}.{}", step.to())),
+        };
+        let invocation = step
+            .invocation(&input, &step_output, config)
+            .map_err(|e| e.to_string())?;
+        let tool = invocation.tool();
+        let mut child = invocation.spawn().map_err(|e| e.to_string())?;
+        let status = wait_with_timeout(&mut child, config.tool_timeout)
+            .map_err(|e| format!("could not wait for {tool}: {e}"))?;
+        let (stdout, stderr) = read_console_output(&mut child);
+        match status {
+            Some(status) if status.success() => (),
+            Some(status) => {
+                let failure = spawn::classify_failure(tool, &format!("{stdout}\n{stderr}"));
+                let message = stderr
+                    .lines()
+                    .chain(stdout.lines())
+                    .map(str::trim)
+                    .rfind(|line| !line.is_empty())
+                    .unwrap_or("no output");
+                return Err(format!(
+                    "{tool} failed with {status} ({failure}): {message}"
+                ));
+            }
+            None => return Err(format!("{tool} {}", Failure::TimedOut)),
+        }
+        if !fs::metadata(&step_output).is_ok_and(|meta| meta.len() > 0) {
+            return Err(format!("{tool} {}", Failure::EmptyOutput));
+        }
+        tools.push(tool);
+        input = step_output;
+    }
+    Ok(tools)
+}
+
+/// Print how every conversion of the self test went, returns the number that failed
+fn report_self_test(results: &[SelfTestResult]) -> usize {
+    let mut failed = 0;
+    for (from, to, result) in results {
+        let outcome = match result {
+            Ok(tools) => {
+                let tools = tools.iter().map(Tool::to_string).collect::<Vec<_>>();
+                format!("ok ({})", tools.join(", "))
+            }
+            Err(e) => {
+                failed += 1;
+                format!("FAILED: {e}")
+            }
+        };
+        output::stdout(format!("{from} -> {to}: {outcome}"));
+    }
+    failed
+}
+
+/// Size in bytes with a binary unit, like 12.3 MiB
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", units[unit]),
+    }
+}
+
+/// Every conversion between two formats with the tools the planner uses for it
+fn format_matrix(config: &ConversionConfig) -> Vec<String> {
+    use clap::ValueEnum;
+
+    let mut lines = vec![];
+    for &from in ImageFormat::value_variants() {
+        for &to in ImageFormat::value_variants()
+            .iter()
+            .filter(|&&to| to != from)
+        {
+            let steps = plan_steps(from, to, config, Png);
+            if steps.is_empty() {
+                lines.push(format!("{from} -> {to}: not supported"));
+                continue;
+            }
+            let tools = steps.iter().map(|step| step.tool().to_string());
+            let mut line = format!("{from} -> {to}: {}", tools.collect::<Vec<_>>().join(", "));
+            let mut over = steps
+                .iter()
+                .map(|step| step.to())
+                .filter(|&format| format != to)
+                .map(|format| format.to_string())
+                .collect::<Vec<_>>();
+            over.dedup();
+            if !over.is_empty() {
+                line.push_str(&format!(" (via {})", over.join(", ")));
+            }
+            if convert_only_when_forced(from, to) {
+                line.push_str(" *");
+            }
+            lines.push(line);
+        }
+    }
+    lines.push("* only converted with --force".to_string());
+    lines.push("jxl goes via jpeg instead of png for recompressed jpegs".to_string());
+    lines
+}
+
+/// Print the conversion plan of every archive at `path` and all tools needed for it
+fn analyze(path: &Path, config: &ConversionConfig) {
+    let mut tools = BTreeSet::new();
+    for cbz_file in archive_paths(path, config) {
+        if already_converted(&cbz_file, config) {
+            continue;
+        }
+        match WorkUnit::new(&cbz_file, config, 1) {
+            Ok(work_unit) => work_unit.analyze(&mut tools),
+            Err(NotAnArchive(_)) => {}
+            Err(e) => output::stdout(format!("{}: {e}", cbz_file.display())),
+        }
+    }
+    let tools = tools.iter().map(Tool::to_string).collect::<Vec<_>>();
+    match tools.is_empty() {
+        true => output::stdout("no tools required"),
+        false => output::stdout(format!("required tools: {}", tools.join(", "))),
+    }
+}
+
+/// The plan of every archive at `path` and all tools needed for it as a JSON document
+fn analyze_json(path: &Path, config: &ConversionConfig) -> String {
+    let mut tools = BTreeSet::new();
+    let mut archives = vec![];
+    for cbz_file in archive_paths(path, config) {
+        if already_converted(&cbz_file, config) {
+            continue;
+        }
+        match WorkUnit::new(&cbz_file, config, 1) {
+            Ok(work_unit) => {
+                let plan = work_unit.dump_plan();
+                tools.extend(plan.tools.iter().copied());
+                archives.push(ArchiveAnalysis::Planned(plan));
+            }
+            Err(NotAnArchive(_)) => {}
+            Err(e) => archives.push(ArchiveAnalysis::Failed {
+                archive: cbz_file.to_string_lossy().to_string(),
+                error: e.to_string(),
+            }),
+        }
+    }
+    output::json(&Analysis { archives, tools })
+}
+
+/// The plans of all archives, as --analyze --json prints them
+#[derive(Serialize)]
+struct Analysis {
+    archives: Vec<ArchiveAnalysis>,
+    tools: BTreeSet<Tool>,
+}
+
+/// An archive within [`Analysis`], which may not be planned at all
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ArchiveAnalysis {
+    Planned(ArchivePlan),
+    Failed { archive: String, error: String },
+}
+
+/// Archives at `path` that have no converted archive next to them yet
+fn unconverted(path: &Path, config: &ConversionConfig) -> Vec<PathBuf> {
+    let mut archives = archive_paths(path, config)
+        .into_iter()
+        .filter(|file| file.is_file() && Container::from_path(file).is_some())
+        .filter(|file| !already_converted(file, config))
+        .collect::<Vec<_>>();
+    archives.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    archives
+}
+
+/// Print every archive at `path` that still needs to be converted
+fn list_unconverted(path: &Path, config: &ConversionConfig, json: bool) {
+    let archives = unconverted(path, config);
+    if !json {
+        archives
+            .iter()
+            .for_each(|archive| output::stdout(archive.display().to_string()));
+        return;
+    }
+    let archives = archives
+        .iter()
+        .map(|archive| archive.to_string_lossy())
+        .collect::<Vec<_>>();
+    output::stdout(output::json(&archives));
+}
+
+fn convert_only_when_forced(from: ImageFormat, to: ImageFormat) -> bool {
+    match (from, to) {
+        (Jpeg | Png, _) => false,
+        (_, Jpeg | Png) => false,
+        (_, _) => true,
+    }
+}
+
+/// What happens when an image cannot be converted
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum OnError {
+    /// Stop the whole run, the archive with the image is not written
+    #[default]
+    FailFast,
+    /// Do not write the archive with the image, but go on with the next archive
+    SkipArchive,
+    /// Keep the image as it is and write the archive with all other images converted
+    SkipImage,
+}
+
+/// When to log a line for every converted image
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default)]
+enum Progress {
+    /// Only when stderr is a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Progress {
+    fn per_image(self) -> bool {
+        match self {
+            Progress::Auto => io::stderr().is_terminal(),
+            Progress::Always => true,
+            Progress::Never => false,
+        }
+    }
+}
+
+/// How the progress of a run is presented
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+enum ProgressStyle {
+    /// A line for every image or every archive, as --progress decides
+    #[default]
+    Lines,
+    /// A line for every archive and at most every ten seconds in between
+    Plain,
+    /// No progress lines, only the archives and any problems are logged
+    None,
+}
+
+/// Machine readable formats the plan can be printed in
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum DumpFormat {
+    Json,
+}
+
+#[derive(Parser)]
+#[command(version, verbatim_doc_comment)]
+#[command(group(
+    clap::ArgGroup::new("json_output")
+        .args(["list", "analyze", "list_unconverted"])
+        .multiple(true)
+))]
+/// Convert images within comic archives to newer image formats
+///
+/// Convert images within Zip or Tar Comic Book archives, although it also works with normal zip
+/// and tar files.
+/// Images within Epub books are converted as well, with the documents updated to refer to them.
+/// By default only converts Jpeg and Png to the target format or decode any formats to Png and
+/// Jpeg.
+struct Args {
+    #[arg(
+        required_unless_present_any = [
+            "job_spec",
+            "bench",
+            "list_formats",
+            "self_test",
+            "repack_only",
+            "list"
+        ],
+        value_parser = FormatArgParser,
+        help = "All images within the archive(s) are converted to this format"
+    )]
+    format: Option<FormatArg>,
+
+    #[arg(
+        default_value = ".",
+        help = "Path to a cbz/cbt/epub file or a directory containing such files"
+    )]
+    path: PathBuf,
+
+    /// Convert the archives listed in this JSON file instead of the ones at the path
+    ///
+    /// The spec lists the archives, each with an optional target format and quality:
+    /// {"target": "avif", "archives": ["a.cbz", {"path": "b.cbz", "target": "webp", "quality": 75}]}
+    /// Archives without a target use the top level one or the format given on the command line.
+    /// Relative paths of archives start from the directory of the spec.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["list", "analyze", "dump_plan"],
+        verbatim_doc_comment
+    )]
+    job_spec: Option<PathBuf>,
+
+    /// Number of processes spawned
+    ///
+    /// Uses as many processes as you have cores by default, fewer when the available memory
+    /// does not suffice for all of them.
+    /// When used as a flag only spawns a single process at a time.
+    #[arg(short = 'j', long, verbatim_doc_comment)]
+    workers: Option<Option<usize>>,
+
+    /// Number of archives converted at once, each with the processes of --workers
+    ///
+    /// The processes multiply: 2 archive workers with 4 workers each spawn up to 8 processes
+    /// at a time. One archive at a time by default, which suits most runs as the images of
+    /// an archive already keep all cores busy. Only for directories and job specs.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = parse_count,
+        conflicts_with = "also",
+        verbatim_doc_comment
+    )]
+    archive_workers: usize,
+
+    /// Number of processes spawned at most over all archives converted at once
+    ///
+    /// Fewer archives are converted at once when there are more than processes allowed,
+    /// then each archive gets an equal share of them.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = parse_count,
+        verbatim_doc_comment
+    )]
+    max_processes: Option<usize>,
+
+    #[arg(short, long, help = "Convert all images of all formats")]
+    force: bool,
+
+    /// Encode images already in the target format again, at the given quality
+    ///
+    /// Images in the target format are left as they are otherwise. Together with --force
+    /// this applies to Avif, Jpeg XL and WebP as well.
+    #[arg(long, verbatim_doc_comment)]
+    recompress: bool,
+
+    /// Replace the original archive once the converted one was written and verified
+    #[arg(long)]
+    replace: bool,
+
+    /// Split converted archives larger than this into parts, like 100M
+    ///
+    /// Parts are named like book.avif.part01.cbz and never split a page. Epub books are
+    /// always written as a whole.
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with = "replace",
+        verbatim_doc_comment
+    )]
+    split_size: Option<u64>,
+
+    /// Leave the first page (in reading order) in its original format
+    #[arg(long)]
+    keep_cover: bool,
+
+    /// Encode the first pages (in reading order) at this quality instead, from 1 to 100
+    ///
+    /// Covers are looked at more closely than the pages after them. Applies to the quality
+    /// of the target format, like --avif-quality for avif.
+    #[arg(
+        long,
+        value_name = "1-100",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        verbatim_doc_comment
+    )]
+    cover_quality: Option<u8>,
+
+    /// Number of pages encoded at --cover-quality
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        requires = "cover_quality",
+        value_parser = parse_count
+    )]
+    cover_pages: usize,
+
+    /// Scale images down so that their longest side is at most this many pixels
+    ///
+    /// Keeps the aspect ratio and never scales images up. Needs magick for all conversions.
+    #[arg(long, value_name = "PIXELS", verbatim_doc_comment)]
+    max_dimension: Option<u32>,
+
+    /// Keep every original image beside its converted one, for readers that lack support
+    ///
+    /// Archives grow by the size of the converted images, roughly doubling in size. Readers
+    /// show both as pages of their own. The pairs are listed in dual-format.txt.
+    #[arg(long, conflicts_with = "repack_only", verbatim_doc_comment)]
+    dual_format: bool,
+
+    /// Convert the directory at the path as a single comic, into an archive named after it
+    ///
+    /// For a directory named book, the converted images are written to book.<FORMAT>.cbz
+    /// beside it. The directory itself is left as it is.
+    #[arg(
+        long,
+        conflicts_with_all = ["replace", "recursive", "also", "job_spec", "bench"],
+        verbatim_doc_comment
+    )]
+    as_archive: bool,
+
+    /// Convert all archives in the directory into the single archive at PATH
+    ///
+    /// The pages of every archive are placed in a directory named after it, ordered by the
+    /// name of the archive and then by page. The archives themselves are left as they are.
+    /// PATH has to end in .cbz or .zip.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["replace", "as_archive", "also", "job_spec", "bench"],
+        verbatim_doc_comment
+    )]
+    merge_into: Option<PathBuf>,
+
+    /// Write an archive for each of these formats as well, separated by commas
+    ///
+    /// Every archive is extracted once, the conversion to each further format starts from a
+    /// hard linked copy of the extraction. Like book.avif.cbz, the archives are named by format.
+    #[arg(
+        long,
+        value_name = "FORMATS",
+        value_delimiter = ',',
+        conflicts_with_all = ["replace", "suffix", "repack_only", "job_spec", "bench"],
+        verbatim_doc_comment
+    )]
+    also: Vec<ImageFormat>,
+
+    /// Refuse to convert images with more pixels than this, as stated in their header
+    ///
+    /// Guards against images crafted to exhaust the memory of the tools. Images whose header
+    /// cannot be read are converted anyway.
+    #[arg(long, value_name = "PIXELS", verbatim_doc_comment)]
+    max_pixels: Option<u64>,
+
+    /// Convert all images to grayscale, which saves space for line art stored as color
+    ///
+    /// Needs magick for all conversions.
+    #[arg(long, verbatim_doc_comment)]
+    grayscale: bool,
+
+    /// Transform the colors of all images to sRGB, for readers without color management
+    ///
+    /// Needs magick for all conversions.
+    #[arg(long, verbatim_doc_comment)]
+    to_srgb: bool,
+
+    /// Transform the colors with this sRGB ICC profile, taking embedded profiles into account
+    ///
+    /// Without it only the colorspace is changed, embedded profiles are not applied.
+    #[arg(long, value_name = "FILE", requires = "to_srgb", verbatim_doc_comment)]
+    srgb_profile: Option<PathBuf>,
+
+    /// Convert archives again if they were modified after their converted archive
+    #[arg(long)]
+    incremental: bool,
+
+    /// Also convert the archives within subdirectories of the directory
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Skip subdirectories with this name or path when looking for archives, repeatable
+    ///
+    /// A name like previews skips every directory called so, a path like series/previews
+    /// only the directories ending in it.
+    #[arg(long, value_name = "DIR", requires = "recursive", verbatim_doc_comment)]
+    exclude_dir: Vec<PathBuf>,
+
+    /// Convert archives in a directory that are named like converted ones as well
+    ///
+    /// Archives named like the output of the conversion, like book.webp.cbz for webp, are
+    /// skipped by default, so that another run over the same directory does not convert them.
+    /// An archive given by its path is always converted.
+    #[arg(long, verbatim_doc_comment)]
+    include_converted: bool,
+
+    /// Convert archives converted earlier to the new format, instead of their originals
+    ///
+    /// book.avif.cbz becomes book.jxl.cbz, not book.avif.jxl.cbz. Only archives named like
+    /// converted ones are taken from a directory.
+    #[arg(long, conflicts_with_all = ["replace", "suffix"], verbatim_doc_comment)]
+    retarget: bool,
+
+    /// Detect the format of every image from its content instead of trusting its extension
+    ///
+    /// Helps with archives where, for example, PNGs were saved with a .jpg extension.
+    /// Images are only probed once extracted, so --analyze and --dump-plan still go by extension.
+    #[arg(long, verbatim_doc_comment)]
+    probe_format: bool,
+
+    /// Name converted archives like book<SUFFIX>.cbz instead of book.<FORMAT>.cbz
+    #[arg(long, value_name = "SUFFIX", value_parser = parse_suffix)]
+    suffix: Option<String>,
+
+    /// Only convert these pages in reading order, like 1-10,50,90-
+    #[arg(long, value_name = "RANGES")]
+    pages: Option<PageRanges>,
+
+    /// Only convert images below this directory within the archives, like content/
+    ///
+    /// The path is the one listed within the archive, all other files are kept as they are.
+    #[arg(long, value_name = "PATH", verbatim_doc_comment)]
+    archive_subpath: Option<PathBuf>,
+
+    /// Only convert images of these formats, separated by commas
+    #[arg(long, value_name = "FORMATS", value_delimiter = ',')]
+    only: Vec<ImageFormat>,
+
+    /// Never convert images of these formats, not even with --force, separated by commas
+    #[arg(long, value_name = "FORMATS", value_delimiter = ',')]
+    exclude_format: Vec<ImageFormat>,
+
+    /// Report identical pages after the conversion and how much space they take up
+    ///
+    /// With --recursive, identical converted archives are hard linked to each other as well.
+    #[arg(long, verbatim_doc_comment)]
+    dedupe: bool,
+
+    /// Convert identical images within an archive only once, the others get a copy
+    ///
+    /// Pays off for archives that repeat pages like recaps or chapter headers. Every image
+    /// is read once before the conversion to find those.
+    #[arg(long, verbatim_doc_comment)]
+    reuse_identical: bool,
+
+    /// Encoder for Avif images, the first one installed is used by default
+    #[arg(long, value_name = "ENCODER")]
+    avif_encoder: Option<spawn::AvifEncoder>,
+
+    /// Tool for converting Jpeg to Png
+    #[arg(long, value_name = "TOOL", default_value = "magick")]
+    png_tool: PngTool,
+
+    /// Optimize every image converted to Png with oxipng afterwards
+    ///
+    /// Only the final images are optimized, not the Png images some conversions go over.
+    #[arg(long, verbatim_doc_comment)]
+    optimize_png: bool,
+
+    /// Tool for converting Png to Jpeg
+    #[arg(long, value_name = "TOOL", default_value = "magick")]
+    jpeg_tool: JpegTool,
+
+    /// Set an environment variable for every tool converting images, may be repeated
+    ///
+    /// Useful for settings the tools only take from the environment, like
+    /// --encoder-env OMP_NUM_THREADS=2 or --encoder-env TMPDIR=/mnt/ramdisk.
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_env_var,
+        verbatim_doc_comment
+    )]
+    encoder_env: Vec<(String, String)>,
+
+    /// Quality of Jpeg images written by magick or avifdec
+    ///
+    /// Without it magick writes at 92 and avifdec at 80.
+    #[arg(
+        long,
+        value_name = "1-100",
+        env = "CBZ_IN_JPEG_QUALITY",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        verbatim_doc_comment
+    )]
+    jpeg_quality: Option<u8>,
+
+    /// Quality of Avif images written by cavif or avifenc
+    #[arg(
+        long,
+        value_name = "1-100",
+        env = "CBZ_IN_AVIF_QUALITY",
+        default_value_t = Quality::default().avif,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    avif_quality: u8,
+
+    /// Quality of Webp images written by cwebp
+    #[arg(
+        long,
+        value_name = "1-100",
+        env = "CBZ_IN_WEBP_QUALITY",
+        default_value_t = Quality::default().webp,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    webp_quality: u8,
+
+    /// Space that has to remain free after extracting an archive, like 500M or 2G
+    #[arg(long, value_name = "SIZE", default_value = "0", value_parser = parse_size)]
+    min_free_space: u64,
+
+    /// Unix permissions of files within the converted archive, in octal
+    #[arg(long, value_name = "MODE", default_value = "644", value_parser = parse_mode)]
+    file_mode: u32,
+
+    /// Unix permissions of directories within the converted archive, in octal
+    #[arg(long, value_name = "MODE", default_value = "755", value_parser = parse_mode)]
+    dir_mode: u32,
+
+    /// Deflate files like ComicInfo.xml within converted zip archives, images stay stored
+    #[arg(long)]
+    compress_metadata: bool,
+
+    /// Leave files that are no images out of converted archives, except for epub books
+    #[arg(long)]
+    no_copy_unconvertible: bool,
+
+    /// Keep __MACOSX/, .DS_Store and Thumbs.db in converted archives
+    ///
+    /// Those are left out by default, as they only take up space and the resource forks in
+    /// __MACOSX/ are named like images without being any.
+    #[arg(long, verbatim_doc_comment)]
+    keep_junk: bool,
+
+    /// Write the pages in the order the ComicInfo.xml of an archive lists them
+    ///
+    /// Pages are written in the order of their names otherwise. Pages missing from the list
+    /// follow the listed ones, archives without a ComicInfo.xml are written as usual.
+    #[arg(long, verbatim_doc_comment)]
+    use_comicinfo: bool,
+
+    /// Leave out pages the ComicInfo.xml marks as Deleted or Advertisement
+    ///
+    /// The ComicInfo.xml itself is kept as it is.
+    #[arg(long, requires = "use_comicinfo", verbatim_doc_comment)]
+    drop_deleted_pages: bool,
+
+    /// Extension for written images of a format instead of its default, like jpeg=jpg
+    ///
+    /// Only the extensions a format is recognized by can be chosen: jpeg or jpg for jpeg
+    /// and avif or avifs for avif. Separate several with commas.
+    #[arg(
+        long,
+        value_name = "FORMAT=EXT",
+        value_delimiter = ',',
+        value_parser = parse_extension,
+        verbatim_doc_comment
+    )]
+    extension: Vec<(ImageFormat, String)>,
+
+    /// Do not ask for confirmation before converting many images with --replace or --force
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Only print the files within a single archive and the image format detected for them
+    ///
+    /// Images are listed with the width and height their headers state, read without extracting
+    /// the archive. No format is needed, as in cbz_in --list book.cbz.
+    #[arg(long, verbatim_doc_comment)]
+    list: bool,
+
+    /// Print the output of --list, --analyze or --list-unconverted as JSON
+    ///
+    /// With --analyze, every archive is listed with the number of images to convert and to
+    /// skip, the steps of every image and the tools required, as --dump-plan does for one.
+    #[arg(long, requires = "json_output", verbatim_doc_comment)]
+    json: bool,
+
+    /// Only print the plan of a single archive in a machine readable format
+    ///
+    /// Lists every image with its steps and the tools required. Jxl images marked with
+    /// "depends_on_image" go over jpeg instead of png if they are recompressed Jpegs.
+    #[arg(long, value_name = "FORMAT", verbatim_doc_comment)]
+    dump_plan: Option<DumpFormat>,
+
+    /// Only print the planned steps per image and the tools required, without converting
+    #[arg(long)]
+    analyze: bool,
+
+    /// Only print the archives that have no converted archive next to them yet
+    ///
+    /// Archives are skipped just like a conversion would skip them, so with --incremental
+    /// those changed after their conversion are listed again. Nothing is written.
+    #[arg(
+        long,
+        conflicts_with_all = ["list", "analyze", "dump_plan", "also", "job_spec", "bench"],
+        verbatim_doc_comment
+    )]
+    list_unconverted: bool,
+
+    /// Only print every supported conversion between two formats and the tools it takes
+    ///
+    /// Options choosing tools or adding image operations are taken into account.
+    #[arg(long, verbatim_doc_comment)]
+    list_formats: bool,
+
+    /// Only convert a built-in image from Jpeg to every format and back with the tools
+    ///
+    /// Every conversion is reported as it went, with the error of the tool that failed.
+    /// Options choosing tools or adding image operations are taken into account. Exits with
+    /// an error code when any conversion failed.
+    #[arg(long, verbatim_doc_comment)]
+    self_test: bool,
+
+    /// Convert copies of a sample archive to avif, jxl and webp and compare size and time
+    ///
+    /// The copies are converted in a temporary directory, the sample itself is left alone.
+    /// All other options apply to each conversion.
+    #[arg(
+        long,
+        value_name = "ARCHIVE",
+        conflicts_with_all = ["list", "analyze", "dump_plan", "job_spec"],
+        verbatim_doc_comment
+    )]
+    bench: Option<PathBuf>,
+
+    /// Write the outcome, tools and time of every page to a log file beside the converted archive
+    #[arg(long)]
+    archive_log: bool,
+
+    /// Print the time spent extracting, per conversion step and compressing at the end
+    #[arg(long)]
+    timings: bool,
+
+    /// Print the command line of every tool invocation
+    ///
+    /// Together with --analyze or --dry-run the commands are only printed and never run.
+    #[arg(long, verbatim_doc_comment)]
+    print_commands: bool,
+
+    /// Extract the archives and print the tools the images would be converted with
+    ///
+    /// Unlike --analyze, the steps are planned for the extracted images. No tool is run and
+    /// no archive is written.
+    #[arg(long, requires = "print_commands", verbatim_doc_comment)]
+    dry_run: bool,
+
+    /// Print the path of every written archive to stdout, one per line
+    ///
+    /// The paths are printed even with --output, so scripts can pick them up.
+    #[arg(long, verbatim_doc_comment)]
+    print_output: bool,
+
+    /// Log the command line and version of every tool invocation
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Convert animated Avif images as well, which keeps only their first frame
+    ///
+    /// By default they are kept as they are.
+    #[arg(long, verbatim_doc_comment)]
+    flatten_animated: bool,
+
+    /// Fill transparent areas with this color when converting Png to Jpeg, like white
+    ///
+    /// Jpeg has no transparency, so by default Png images with any are kept as they are.
+    /// Takes any color magick knows, which then converts all Png images to Jpeg.
+    #[arg(long, value_name = "COLOR", verbatim_doc_comment)]
+    alpha_background: Option<String>,
+
+    /// Keep the directory an archive was extracted to when its conversion fails
+    ///
+    /// It holds the images converted so far next to the ones that were not, to find out
+    /// what went wrong with a page. Delete it before converting the archive again.
+    #[arg(long, verbatim_doc_comment)]
+    keep_temp_on_error: bool,
+
+    /// Keep the files written between the steps of a conversion, like the png an avif image
+    /// is decoded to before it is encoded to webp
+    ///
+    /// They are named like cover.cbzin-tmp.png and never go into the converted archive, so
+    /// they are only left to inspect in the directory kept by --keep-temp-on-error.
+    #[arg(long, requires = "keep_temp_on_error", verbatim_doc_comment)]
+    keep_intermediates: bool,
+
+    /// Describe errors in full with all their causes, for bug reports
+    ///
+    /// Errors are printed with the names of their variants and every field, like the
+    /// kind and code of an OS error, followed by the errors that caused them.
+    #[arg(long, verbatim_doc_comment)]
+    verbose_errors: bool,
+
+    /// Run the tools at a lower priority, from 1 to 19 like nice -n
+    ///
+    /// Keeps the system responsive while a large library is converted in the background.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(i32).range(1..=19),
+        verbatim_doc_comment
+    )]
+    nice: Option<i32>,
+
+    /// Let running images finish for up to this long after Ctrl+C
+    ///
+    /// No further images are started. An archive whose images all finish in time is still
+    /// written, any other one is given up right away. A second Ctrl+C stops at once.
+    #[arg(long, value_name = "SECONDS", verbatim_doc_comment)]
+    shutdown_grace: Option<u64>,
+
+    /// Kill a tool that takes longer than this for a single step of a conversion
+    ///
+    /// Some encoders hang on malformed images. A killed tool counts as failed, so magick
+    /// gets to try the image instead. No limit by default, large images take their time.
+    #[arg(long, value_name = "SECONDS", verbatim_doc_comment)]
+    image_tool_timeout: Option<u64>,
+
+    /// Kill a tool that takes longer than this to inspect an image, like jxlinfo
+    ///
+    /// Inspecting is quick, a tool that takes longer is stuck and would hold up the image.
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 30,
+        verbatim_doc_comment
+    )]
+    probe_timeout: u64,
+
+    /// Exit with an error code when any image required the magick fallback
+    #[arg(long)]
+    strict: bool,
+
+    /// What to do when an image cannot be converted
+    ///
+    /// The exit code is 1 whenever an image or archive failed, whatever the policy.
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "fail-fast",
+        verbatim_doc_comment
+    )]
+    on_error: OnError,
+
+    /// Only write the archive(s) anew, with sorted entries below a single directory
+    ///
+    /// No image is converted, so no format is needed, but options like --compress-metadata,
+    /// --keep-junk or --no-copy-unconvertible apply. Archives are named like book.repacked.cbz
+    /// by default.
+    #[arg(
+        long,
+        conflicts_with_all = ["recompress", "bench", "job_spec"],
+        verbatim_doc_comment
+    )]
+    repack_only: bool,
+
+    /// Convert Jpeg XL to Avif or WebP over png when jxlinfo is missing, instead of failing
+    ///
+    /// jxlinfo tells recompressed Jpegs apart, which convert better over jpeg.
+    #[arg(long, verbatim_doc_comment)]
+    best_effort: bool,
+
+    /// Skip archives that need a tool which is not installed, converting all others
+    ///
+    /// The tools are checked for every archive before it is extracted, the skipped archives
+    /// are logged with the tools they lack.
+    #[arg(long, verbatim_doc_comment)]
+    skip_unsatisfiable: bool,
+
+    /// When to log every converted image instead of one line per archive
+    ///
+    /// By default every image is only logged when stderr is a terminal, so logs of
+    /// unattended runs are not flooded.
+    #[arg(
+        long,
+        value_name = "WHEN",
+        default_value = "auto",
+        verbatim_doc_comment
+    )]
+    progress: Progress,
+
+    /// How to present the progress of the run
+    #[arg(long, value_name = "STYLE", default_value = "lines")]
+    progress_style: ProgressStyle,
+
+    /// Refuse to convert a directory that contains files other than archives
+    ///
+    /// Catches archives with a mistyped extension, which are skipped otherwise.
+    #[arg(long, verbatim_doc_comment)]
+    strict_extension: bool,
+
+    /// Write the summary of the run to this file instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+impl Args {
+    /// The target format and the path to convert, or the path given in place of the format
+    fn format_and_path(&self) -> Result<(Option<ImageFormat>, PathBuf), PathBuf> {
+        match &self.format {
+            Some(FormatArg::Format(format)) => Ok((Some(*format), self.path.clone())),
+            // the format may be left out when nothing is converted, the path moves up then
+            Some(FormatArg::Path(path))
+                if (self.repack_only || self.list) && self.path == Path::new(".") =>
+            {
+                Ok((None, path.clone()))
+            }
+            Some(FormatArg::Path(path)) => Err(path.clone()),
+            None => Ok((None, self.path.clone())),
+        }
+    }
+}
+
+/// The first positional argument, which is the path when the mode needs no format
+#[derive(Clone, Debug)]
+enum FormatArg {
+    Format(ImageFormat),
+    Path(PathBuf),
+}
+
+/// Parses a [`FormatArg`], with the errors and possible values of an image format
+#[derive(Clone)]
+struct FormatArgParser;
+
+impl clap::builder::TypedValueParser for FormatArgParser {
+    type Value = FormatArg;
+
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<FormatArg, clap::Error> {
+        let formats = clap::builder::EnumValueParser::<ImageFormat>::new();
+        match formats.parse_ref(cmd, arg, value) {
+            Ok(format) => Ok(FormatArg::Format(format)),
+            Err(_) if Path::new(value).exists() => Ok(FormatArg::Path(PathBuf::from(value))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn possible_values(
+        &self,
+    ) -> Option<Box<dyn Iterator<Item = clap::builder::PossibleValue> + '_>> {
+        use clap::ValueEnum;
+
+        let formats = ImageFormat::value_variants().iter();
+        Some(Box::new(formats.filter_map(ImageFormat::to_possible_value)))
+    }
+}
+
+fn parse_extension(value: &str) -> Result<(ImageFormat, String), String> {
+    use clap::ValueEnum;
+
+    let Some((format, ext)) = value.split_once('=') else {
+        return Err(format!("'{value}' is not like FORMAT=EXT"));
+    };
+    let format = ImageFormat::from_str(format, true)?;
+    match format.extensions().contains(&ext) {
+        true => Ok((format, ext.to_string())),
+        false => Err(format!(
+            "{format} images can have the extensions {}",
+            format.extensions().join(", ")
+        )),
+    }
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err("expected a number of at least 1".to_string()),
+    }
+}
+
+fn parse_suffix(value: &str) -> Result<String, String> {
+    match value.is_empty() || value.contains('/') {
+        true => Err("the suffix has to be part of a file name".to_string()),
+        false => Ok(value.to_string()),
+    }
+}
+
+fn parse_env_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("expected a variable like KEY=VALUE".to_string()),
+    }
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, ""),
+    };
+    let factor = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(format!("'{unit}' is not a unit, use K, M, G or T")),
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+    {
+        Some(size) => Ok(size),
+        None => Err(format!("'{value}' is not a size like 500M")),
+    }
+}
+
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "'{value}' is not an octal permission mode like 644"
+        )),
+    }
+}
+
+fn main() -> Result<()> {
+    let mut logger = env_logger::builder();
+    logger
+        .filter_level(log::LevelFilter::Info)
+        .format_timestamp_secs()
+        .parse_env("RUST_LOG");
+    // see https://no-color.org, only a non-empty value disables colors
+    if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        logger.write_style(env_logger::WriteStyle::Never);
+    }
+    logger.init();
+
+    let matches = Args::parse();
+    let (format, path) = match matches.format_and_path() {
+        Ok(format_and_path) => format_and_path,
+        Err(path) => {
+            error!("expected an image format instead of {path:?}");
+            exit(1);
+        }
+    };
+    if matches.job_spec.is_none() && !path.exists() {
+        error!("does not exists: {:?}", path);
+        exit(1);
+    }
+
+    let workers = match matches.workers {
+        Some(Some(value)) => value,
+        Some(None) => 1,
+        None => {
+            let cores = thread::available_parallelism().map_or(1, |value| value.get());
+            // archives of a job spec may be converted to any format
+            let target = format.filter(|_| matches.job_spec.is_none());
+            let memory = available_memory();
+            let workers = default_workers(cores, memory, target.unwrap_or(Jxl).memory_estimate());
+            if workers < cores {
+                info!(
+                    "Using {workers} instead of {cores} workers, as only {} of memory are available",
+                    human_size(memory.unwrap_or_default())
+                );
+            }
+            workers
+        }
+    };
+
+    let (archive_workers, workers) =
+        limit_processes(matches.archive_workers, workers, matches.max_processes);
+
+    let config = ConversionConfig {
+        target: format.unwrap_or_default(),
+        force: matches.force,
+        recompress: matches.recompress,
+        replace: matches.replace,
+        split_size: matches.split_size,
+        keep_cover: matches.keep_cover,
+        cover_quality: matches.cover_quality,
+        cover_pages: matches.cover_pages,
+        max_dimension: matches.max_dimension,
+        grayscale: matches.grayscale,
+        to_srgb: matches.to_srgb,
+        srgb_profile: matches.srgb_profile,
+        dedupe: matches.dedupe,
+        only: matches.only,
+        exclude: matches.exclude_format,
+        incremental: matches.incremental,
+        recursive: matches.recursive,
+        exclude_dirs: matches.exclude_dir,
+        include_converted: matches.include_converted,
+        retarget: matches.retarget,
+        print_commands: matches.print_commands,
+        dry_run: matches.dry_run,
+        print_output: matches.print_output,
+        on_error: matches.on_error,
+        best_effort: matches.best_effort,
+        skip_unsatisfiable: matches.skip_unsatisfiable,
+        use_comic_info: matches.use_comicinfo,
+        drop_deleted_pages: matches.drop_deleted_pages,
+        repack_only: matches.repack_only,
+        max_pixels: matches.max_pixels,
+        dual_format: matches.dual_format,
+        reuse_identical: matches.reuse_identical,
+        keep_temp_on_error: matches.keep_temp_on_error,
+        keep_intermediates: matches.keep_intermediates,
+        flatten_animated: matches.flatten_animated,
+        alpha_background: matches.alpha_background,
+        verbose_errors: matches.verbose_errors,
+        shutdown_grace: matches.shutdown_grace.map(Duration::from_secs),
+        tool_timeout: matches.image_tool_timeout.map(Duration::from_secs),
+        probe_timeout: Some(Duration::from_secs(matches.probe_timeout)),
+        extra_targets: matches.also.clone(),
+        min_free_space: matches.min_free_space,
+        suffix: matches.suffix,
+        probe_format: matches.probe_format,
+        compress_metadata: matches.compress_metadata,
+        drop_unconvertible: matches.no_copy_unconvertible,
+        keep_junk: matches.keep_junk,
+        extensions: matches.extension,
+        png_tool: matches.png_tool,
+        optimize_png: matches.optimize_png,
+        jpeg_tool: matches.jpeg_tool,
+        encoder_env: matches.encoder_env,
+        archive_log: matches.archive_log,
+        pages: matches.pages,
+        subpath: matches.archive_subpath,
+        modes: EntryModes {
+            file: matches.file_mode,
+            dir: matches.dir_mode,
+        },
+        quality: {
+            let mut quality = Quality {
+                avif: matches.avif_quality,
+                webp: matches.webp_quality,
+                ..Quality::default()
+            };
+            if let Some(jpeg) = matches.jpeg_quality {
+                quality.set(Jpeg, jpeg);
+            }
+            quality
+        },
+    };
+    let spec_archives = match &matches.job_spec {
+        Some(spec_path) => match spec_archives(spec_path, format, &config) {
+            Ok(archives) => Some(archives),
+            Err(e) => {
+                error!("job spec {spec_path:?}: {e}");
+                exit(1);
+            }
+        },
+        None => None,
+    };
+    let targets_avif = match &spec_archives {
+        Some(archives) => archives.iter().any(|(_, config)| config.target == Avif),
+        None => {
+            config.target == Avif
+                || config.extra_targets.contains(&Avif)
+                || matches.bench.is_some()
+                || matches.list_formats
+                || matches.self_test
+        }
+    };
+    spawn::set_verbose(matches.verbose);
+    spawn::set_niceness(matches.nice.unwrap_or(0));
+    let chosen_tools = [
+        (
+            config.png_tool == PngTool::Oxipng || config.optimize_png,
+            Tool::Oxipng,
+        ),
+        (config.jpeg_tool == JpegTool::Mozjpeg, Tool::Cjpeg),
+    ];
+    // only printing the plan works without the tools, they show up as required there, the
+    // self test reports them as failed
+    let converts = !(matches.list
+        || matches.analyze
+        || matches.list_unconverted
+        || matches.dump_plan.is_some()
+        || matches.list_formats
+        || matches.self_test);
+    for (_, tool) in chosen_tools
+        .into_iter()
+        .filter(|(chosen, _)| *chosen && converts)
+    {
+        if spawn::tool_version(tool).is_none() {
+            error!("{tool} was chosen but is not installed");
+            exit(1);
+        }
+    }
+    if targets_avif {
+        spawn::set_avif_encoder(matches.avif_encoder);
+    }
+    if matches.timings {
+        timings::enable();
+    }
+    if let Some(output_path) = &matches.output {
+        if let Err(e) = output::redirect(output_path) {
+            error!("could not create output file {output_path:?}: {e}");
+            exit(1);
+        }
+    }
+    if matches.list {
+        if let Err(e) = list(&path, matches.json) {
+            error!("{}", error_report(&e, matches.verbose_errors));
+            exit(1);
+        }
+        return Ok(());
+    }
+    if matches.list_unconverted {
+        list_unconverted(&path, &config, matches.json);
+        return Ok(());
+    }
+    if matches.analyze && matches.json {
+        output::stdout(analyze_json(&path, &config));
+        return Ok(());
+    }
+    if matches.analyze {
+        analyze(&path, &config);
+        return Ok(());
+    }
+    if matches.list_formats {
+        format_matrix(&config).into_iter().for_each(output::stdout);
+        return Ok(());
+    }
+    if matches.self_test {
+        if report_self_test(&self_test(&config)) > 0 {
+            exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(DumpFormat::Json) = matches.dump_plan {
+        if path.is_dir() {
+            error!("the plan can only be dumped for a single archive");
+            exit(1);
+        }
+        match WorkUnit::new(&path, &config, 1) {
+            Ok(work_unit) => output::stdout(output::json(&work_unit.dump_plan())),
+            Err(e) => {
+                error!("{}", error_report(&e, matches.verbose_errors));
+                exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Some(sample) = &matches.bench {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGINT, Arc::clone(&interrupted))?;
+        if let Err(e) = bench(sample, &config, workers, &interrupted) {
+            error!("{}", error_report(&e, matches.verbose_errors));
+            exit(1);
+        }
+        timings::report();
+        return Ok(());
+    }
+    if matches.strict_extension && spec_archives.is_none() && path.is_dir() {
+        let unknown = archive_paths(&path, &config)
+            .into_iter()
+            .filter(|file| is_unknown_file(file))
+            .collect::<Vec<_>>();
+        for file in unknown.iter() {
+            error!("not a supported archive: {file:?}");
+        }
+        if !unknown.is_empty() {
+            exit(1);
+        }
+    }
+    if (config.replace || config.force) && !matches.yes {
+        let images = match &spec_archives {
+            Some(archives) => archives
+                .iter()
+                .map(|(archive, config)| images_to_convert(archive, config))
+                .sum(),
+            None => images_to_convert(&path, &config),
+        };
+        let replace = match config.replace {
+            true => " and replace the original archives",
+            false => "",
+        };
+        if images >= CONFIRMATION_THRESHOLD
+            && !confirm(&format!(
+                "About to convert {images} images{replace}, continue?"
+            ))
+        {
+            exit(1);
+        }
+    }
+    let mut reporter = LogReporter {
+        per_image: matches.progress_style == ProgressStyle::Lines && matches.progress.per_image(),
+        style: matches.progress_style,
+        ..Default::default()
+    };
+
+    // conversions are cancelled through this flag, children get the signal on their own
+    let interrupted = Arc::new(AtomicBool::new(false));
+    match config.shutdown_grace {
+        None => {
+            signal_hook::flag::register(SIGINT, Arc::clone(&interrupted))?;
+        }
+        Some(_) => {
+            // children have to be spared the signal to finish, they are killed when needed
+            spawn::set_detached(true);
+            let first = Arc::clone(&interrupted);
+            // SAFETY: the handler only touches atomics
+            unsafe {
+                signal_hook::low_level::register(SIGINT, move || {
+                    if first.swap(true, Ordering::SeqCst) {
+                        STOP_NOW.store(true, Ordering::SeqCst);
+                    }
+                })?;
+            }
+        }
+    }
+
+    let failed_archives = if let Some(archives) = spec_archives {
+        match archive_workers {
+            1 => convert_batch(archives, workers, &mut reporter, &interrupted),
+            _ => convert_parallel(
+                archives,
+                archive_workers,
+                workers,
+                &mut reporter,
+                &interrupted,
+            ),
+        }
+    } else if let Some(merge_path) = &matches.merge_into {
+        let merged = match path.is_dir() {
+            true => merge_archives(
+                &path,
+                merge_path,
+                &config,
+                workers,
+                &mut reporter,
+                &interrupted,
+            ),
+            false => Err(Unspecific(format!("{path:?} is not a directory"))),
+        };
+        match merged {
+            Ok(()) => 0,
+            Err(e @ (NothingToDo(_) | AlreadyDone(_))) => {
+                info!("{e}");
+                0
+            }
+            Err(e) => {
+                error!("{}", error_report(&e, matches.verbose_errors));
+                1
+            }
+        }
+    } else if matches.as_archive {
+        match convert_folder(&path, &config, workers, &mut reporter, &interrupted) {
+            Ok(()) => 0,
+            Err(e @ (NothingToDo(_) | AlreadyDone(_))) => {
+                info!("{e}");
+                0
+            }
+            Err(e) => {
+                error!("{}", error_report(&e, matches.verbose_errors));
+                1
+            }
+        }
+    } else if path.is_dir() || !config.extra_targets.is_empty() {
+        let targets = config.per_target();
+        let archives = archive_paths(&path, &config)
+            .into_iter()
+            .flat_map(|archive| {
+                targets
+                    .iter()
+                    .map(move |config| (archive.clone(), config.clone()))
+            })
+            .collect::<Vec<_>>();
+        let converted = archives
+            .iter()
+            .filter_map(|(archive, config)| {
+                let container = Container::from_path(archive)?;
+                Some(config.converted_path(archive, container))
+            })
+            .collect::<Vec<_>>();
+        let failed = match archive_workers {
+            1 => convert_batch(archives, workers, &mut reporter, &interrupted),
+            _ => convert_parallel(
+                archives,
+                archive_workers,
+                workers,
+                &mut reporter,
+                &interrupted,
+            ),
+        };
+        // archives replacing their originals may not have been converted at all
+        if config.dedupe && config.recursive && !config.replace {
+            link_identical_archives(&converted);
+        }
+        failed
+    } else {
+        match convert_single_cbz(&path, &config, workers, &mut reporter, &interrupted) {
+            Ok(()) => 0,
+            Err(NothingToDo(_)) => {
+                info!("Nothing to do for {path:?}");
+                0
+            }
+            Err(NotAnArchive(_)) => {
+                info!("This is not a supported archive");
+                0
+            }
+            Err(e) => {
+                error!("{}", error_report(&e, matches.verbose_errors));
+                1
+            }
+        }
+    };
+    reporter.summary();
+    timings::report();
+    if matches.strict && reporter.fallbacks > 0 {
+        exit(1);
+    }
+    if failed_archives > 0 || reporter.failed > 0 {
+        exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A directory of its own for a test, removed with everything in it when dropped
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// Named after the test, unique to the process so that parallel runs do not collide
+        pub(crate) fn new(name: &str) -> TempDir {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let count = COUNT.fetch_add(1, Ordering::Relaxed);
+            let unique = format!("cbz_in_test_{name}_{}_{count}", std::process::id());
+            let path = std::env::temp_dir().join(unique);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Write a zip archive holding the files, named like book/1.png, with the given content
+    ///
+    /// The directories of the files become entries of their own, as in most archives.
+    pub(crate) fn write_zip(zip_path: &Path, files: &[(&str, &[u8])]) {
+        let staging = zip_path.with_extension("entries");
+        fs::create_dir_all(&staging).unwrap();
+        let file = File::create(zip_path).unwrap();
+        let mut writer =
+            ContainerWriter::new(Container::Zip, file, EntryModes::default(), false).unwrap();
+        let dirs = files
+            .iter()
+            .flat_map(|(name, _)| Path::new(name).ancestors().skip(1))
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect::<BTreeSet<_>>();
+        for dir in dirs {
+            fs::create_dir_all(staging.join(dir)).unwrap();
+            writer
+                .add_directory(dir.to_str().unwrap(), &staging.join(dir))
+                .unwrap();
+        }
+        for (name, content) in files {
+            fs::write(staging.join(name), content).unwrap();
+            writer.add_file(name, &staging.join(name)).unwrap();
+        }
+        writer.finish().unwrap();
+        fs::remove_dir_all(&staging).unwrap();
+    }
+
+    #[test]
+    fn test_check_for_compressed_jxl() {
+        let compressed_path = PathBuf::from("test_data/compressed.jxl");
+        assert!(compressed_path.exists());
+        let out = jxl_is_compressed_jpeg(&compressed_path, None).unwrap();
+        assert!(out);
+    }
+
+    #[test]
+    fn test_check_for_encoded_jxl() {
+        let encoded_path = PathBuf::from("test_data/encoded.jxl");
+        assert!(encoded_path.exists());
+        let out = jxl_is_compressed_jpeg(&encoded_path, None).unwrap();
+        assert!(!out);
+    }
+
+    #[test]
+    fn test_hung_tools_are_killed() {
+        let sleep = || {
+            Command::new("sleep")
+                .arg("10")
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        };
+        let timeout = Some(Duration::from_millis(50));
+        let start = Instant::now();
+        assert!(wait_with_timeout(&mut sleep(), timeout).unwrap().is_none());
+
+        let interrupted = AtomicBool::new(false);
+        let cancel = Cancellation::new(&interrupted, None);
+        let mut job = ConversionJob::new(PathBuf::from("1.png"), Png, Avif).unwrap();
+        job.child = Some(sleep());
+        assert!(matches!(
+            job.wait_for_child(Tool::Cavif, &cancel, timeout),
+            Err(AbnormalExit(_, Failure::TimedOut))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_best_effort_without_jxlinfo() {
+        let config = ConversionConfig {
+            best_effort: true,
+            ..Default::default()
+        };
+        let job = ConversionJob::new(PathBuf::from("test_data/compressed.jxl"), Jxl, Avif).unwrap();
+        assert!(job.inspects_jxl());
+        let over = match spawn::is_installed(Tool::Jxlinfo) {
+            true => Jpeg,
+            false => Png,
+        };
+        assert_eq!(
+            job.plan(&config).unwrap(),
+            [Step::Decode(Jxl, over), Step::Encode(over, Avif)]
+        );
+        assert!(!ConversionJob::new(PathBuf::from("1.jxl"), Jxl, Png)
+            .unwrap()
+            .inspects_jxl());
+    }
+
+    #[test]
+    fn test_plan_resize_goes_through_magick() {
+        use Step::*;
+        let config = ConversionConfig {
+            max_dimension: Some(2000),
+            ..Default::default()
+        };
+        let plan = |from, to| {
+            let job = ConversionJob::new(PathBuf::from("page.img"), from, to).unwrap();
+            job.plan(&config).unwrap()
+        };
+        assert_eq!(plan(Png, Jpeg), [Convert(Png, Jpeg)]);
+        assert_eq!(plan(Jpeg, Avif), [Convert(Jpeg, Png), Encode(Png, Avif)]);
+        assert_eq!(plan(Avif, Png), [Decode(Avif, Png), Convert(Png, Png)]);
+        assert_eq!(plan(Webp, Jpeg), [Decode(Webp, Png), Convert(Png, Jpeg)]);
+        assert_eq!(
+            plan(Avif, Webp),
+            [Decode(Avif, Png), Convert(Png, Png), Encode(Png, Webp)]
+        );
+
+        let job = ConversionJob::new(PathBuf::from("page.png"), Png, Avif).unwrap();
+        assert_eq!(
+            job.plan(&ConversionConfig::default()).unwrap(),
+            [Encode(Png, Avif)]
+        );
+    }
+
+    #[test]
+    fn test_listing_keeps_names_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let listing = b"Path = 1.png\nFolder = -\n\nPath = 2\xe9\\3.png\nFolder = -\n";
+        let name = std::ffi::OsStr::from_bytes(b"2\xe9/3.png");
+        assert_eq!(
+            files_with_format(parse_entries(listing)),
+            [
+                (PathBuf::from("1.png"), Some(Png)),
+                (PathBuf::from(name), Some(Png))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_not_utf8_images_are_kept() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TempDir::new("not_utf8");
+        let mut builder = tar::Builder::new(File::create(dir.join("book.cbt")).unwrap());
+        for name in [&b"book/1.png"[..], b"book/2\xe9.png"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_path(std::ffi::OsStr::from_bytes(name)).unwrap();
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, io::empty()).unwrap();
+        }
+        builder.finish().unwrap();
+        drop(builder);
+
+        let config = ConversionConfig {
+            target: Webp,
+            ..Default::default()
+        };
+        // listing tar archives takes 7z
+        if let Ok(work_unit) = WorkUnit::new(&dir.join("book.cbt"), &config, 1) {
+            let name = std::ffi::OsStr::from_bytes(b"book/2\xe9.png");
+            assert_eq!(work_unit.skipped, [(PathBuf::from(name), Png)]);
+            assert_eq!(work_unit.job_queue.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_listing_skips_directories_named_like_images() {
+        let listing = "\
+Path = cover.png
+Folder = +
+Size = 0
+Attributes = D drwxr-xr-x
+
+Path = cover.png/1.png
+Folder = -
+Size = 1024
+
+Path = notes.txt
+Folder = -
+Size = 12
+
+Path = 2.JPG.d
+Folder = -
+Size = 12
+
+Path = 3.jpg
+Folder = -
+Size = 2048
+";
+        assert_eq!(
+            files_with_format(parse_entries(listing.as_bytes())),
+            [
+                (PathBuf::from("cover.png/1.png"), Some(Png)),
+                (PathBuf::from("notes.txt"), None),
+                (PathBuf::from("2.JPG.d"), None),
+                (PathBuf::from("3.jpg"), Some(Jpeg))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_grayscale_goes_through_magick() {
+        use Step::*;
+        let config = ConversionConfig {
+            grayscale: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_steps(Jpeg, Jxl, &config, Png),
+            [Convert(Jpeg, Png), Encode(Png, Jxl)]
+        );
+        assert_eq!(
+            plan_steps(Jxl, Webp, &config, Jpeg),
+            [Decode(Jxl, Jpeg), Convert(Jpeg, Png), Encode(Png, Webp)]
+        );
+    }
+
+    #[test]
+    fn test_srgb_goes_through_magick() {
+        use Step::*;
+        let config = ConversionConfig {
+            to_srgb: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_steps(Png, Avif, &config, Png),
+            [Convert(Png, Png), Encode(Png, Avif)]
+        );
+        let invocation = |config: &ConversionConfig| {
+            Convert(Png, Png)
+                .invocation(Path::new("1.png"), Path::new("1.cbzin-tmp.png"), config)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            invocation(&config),
+            "magick 1.png -colorspace sRGB 1.cbzin-tmp.png"
+        );
+
+        let config = ConversionConfig {
+            srgb_profile: Some(PathBuf::from("/usr/share/color/icc/sRGB.icc")),
+            ..config
+        };
+        assert_eq!(
+            invocation(&config),
+            "magick 1.png -profile /usr/share/color/icc/sRGB.icc 1.cbzin-tmp.png"
+        );
+    }
+
+    #[test]
+    fn test_plain_progress_is_throttled() {
+        let mut reporter = LogReporter {
+            style: ProgressStyle::Plain,
+            ..Default::default()
+        };
+        reporter.start(Path::new("book.cbz"), 3);
+        reporter.report(Path::new("1.png"), Png, Avif, ImageOutcome::Converted);
+        let first = reporter.last_progress.unwrap();
+        reporter.report(Path::new("2.png"), Png, Avif, ImageOutcome::Converted);
+        assert_eq!(reporter.last_progress, Some(first));
+        // the last image of an archive is always logged
+        reporter.report(Path::new("3.png"), Png, Avif, ImageOutcome::Converted);
+        assert!(reporter.last_progress.unwrap() > first);
+
+        reporter.style = ProgressStyle::None;
+        reporter.last_progress = None;
+        reporter.start(Path::new("book.cbz"), 1);
+        reporter.report(Path::new("1.png"), Png, Avif, ImageOutcome::Converted);
+        assert_eq!(reporter.last_progress, None);
+    }
+
+    #[test]
+    fn test_size_breakdown_per_format() {
+        let mut reporter = LogReporter::default();
+        reporter.sizes(Jpeg, Avif, 3000, 1000);
+        reporter.sizes(Png, Webp, 2048, 3072);
+        reporter.sizes(Jpeg, Avif, 1000, 500);
+        assert_eq!(
+            reporter.size_breakdown(),
+            [
+                "jpeg -> avif saved 62% (3.9 KiB -> 1.5 KiB over 2 images)",
+                "png -> webp grew by 50% (2.0 KiB -> 3.0 KiB over 1 images)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_matrix() {
+        let lines = format_matrix(&ConversionConfig::default());
+        // every pair of different formats, and the notes
+        assert_eq!(lines.len(), 5 * 4 + 2);
+        assert!(lines.contains(&"jpeg -> png: magick".to_string()));
+        assert!(lines.contains(&"png -> jxl: cjxl".to_string()));
+        assert!(lines.contains(&"webp -> jpeg: dwebp, magick (via png)".to_string()));
+        assert!(lines.contains(&"jxl -> webp: djxl, cwebp (via png) *".to_string()));
+
+        let config = ConversionConfig {
+            png_tool: PngTool::Oxipng,
+            ..Default::default()
+        };
+        let lines = format_matrix(&config);
+        assert!(lines.contains(&"jpeg -> png: magick, oxipng".to_string()));
+    }
+
+    #[test]
+    fn test_only_accepts_multiple_formats() {
+        let args = Args::try_parse_from(["cbz_in", "avif", "--only", "jpeg,png"]).unwrap();
+        assert_eq!(args.only, [Jpeg, Png]);
+        let config = ConversionConfig {
+            only: args.only,
+            ..Default::default()
+        };
+        assert!(config.selects(Png));
+        assert!(!config.selects(Webp));
+        assert!(ConversionConfig::default().selects(Webp));
+    }
+
+    #[test]
+    fn test_exclude_format() {
+        let args =
+            Args::try_parse_from(["cbz_in", "jxl", "-f", "--exclude-format", "webp,avif"]).unwrap();
+        assert_eq!(args.exclude_format, [Webp, Avif]);
+        let config = ConversionConfig {
+            target: Jxl,
+            force: true,
+            exclude: args.exclude_format,
+            ..Default::default()
+        };
+        assert!(config.selects(Jpeg));
+        assert!(!config.selects(Webp));
+        assert!(!config.selects(Avif));
+    }
+
+    #[test]
+    fn test_incremental_reconverts_newer_archives() {
+        let dir = TempDir::new("incremental");
+        let (source, converted) = (dir.join("book.cbz"), dir.join("book.avif.cbz"));
+        fs::write(&source, b"").unwrap();
+        fs::write(&converted, b"").unwrap();
+        let set_modified = |path: &Path, secs| {
+            let time = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+        set_modified(&converted, 1_000_000);
+        set_modified(&source, 2_000_000);
+
+        let mut config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        assert!(already_converted(&source, &config));
+        config.incremental = true;
+        assert!(!already_converted(&source, &config));
+        set_modified(&converted, 3_000_000);
+        assert!(already_converted(&source, &config));
+    }
+
+    #[test]
+    fn test_custom_suffix() {
+        let dir = TempDir::new("custom_suffix");
+        let source = dir.join("book.cbz");
+        File::create(&source).unwrap();
+
+        let config = ConversionConfig {
+            target: Webp,
+            suffix: Some("-small".to_string()),
+            ..Default::default()
+        };
+        let converted = config.converted_path(&source, Container::Zip);
+        assert_eq!(converted, dir.join("book-small.cbz"));
+        assert!(!already_converted(&source, &config));
+        File::create(&converted).unwrap();
+        assert!(already_converted(&source, &config));
+        assert!(already_converted(&converted, &config));
+        // archives converted without the suffix are not mistaken for this conversion
+        File::create(dir.join("other.webp.cbz")).unwrap();
+        assert!(!already_converted(&dir.join("other.cbz"), &config));
+    }
+
+    #[test]
+    fn test_names_with_several_dots() {
+        let config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        let source = Path::new("lib/My.Comic.v01.cbz");
+        assert_eq!(
+            config.converted_path(source, Container::Zip),
+            Path::new("lib/My.Comic.v01.avif.cbz")
+        );
+        assert_eq!(
+            config.part_path(source, Container::Zip, 2),
+            Path::new("lib/My.Comic.v01.avif.part02.cbz")
+        );
+        assert_eq!(
+            get_conversion_root_dir(source),
+            Path::new("lib/My.Comic.v01")
+        );
+        assert_eq!(
+            config.converted_path(Path::new("lib/My.Comic.v01.tar"), Container::Tar),
+            Path::new("lib/My.Comic.v01.avif.cbt")
+        );
+
+        // converted ones are not converted again, whatever their container is named
+        for converted in [
+            "lib/book.avif.cbz",
+            "lib/book.avif.zip",
+            "lib/book.avif.cbt",
+            "lib/My.Comic.v01.avif.part01.cbz",
+        ] {
+            assert!(
+                already_converted(Path::new(converted), &config),
+                "{converted}"
+            );
+        }
+        // a format in the middle of the name is just part of it
+        for unconverted in [
+            "lib/My.avif.Comic.cbz",
+            "lib/book.avifs.cbz",
+            "lib/bookavif.cbz",
+        ] {
+            assert!(
+                !already_converted(Path::new(unconverted), &config),
+                "{unconverted}"
+            );
+        }
+        // converting to another format keeps the name of the first conversion
+        let webp = ConversionConfig {
+            target: Webp,
+            ..Default::default()
+        };
+        let converted = Path::new("lib/book.avif.cbz");
+        assert!(!already_converted(converted, &webp));
+        assert_eq!(
+            webp.converted_path(converted, Container::Zip),
+            Path::new("lib/book.avif.webp.cbz")
+        );
+    }
+
+    #[test]
+    fn test_probe_mislabeled_images() {
+        let dir = TempDir::new("probe_format");
+        let probe = |name: &str, content: &[u8]| {
+            fs::write(dir.join(name), content).unwrap();
+            probe_format(&dir.join(name)).unwrap()
+        };
+        assert_eq!(
+            probe("scan.jpg", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some(Png)
+        );
+        assert_eq!(
+            probe("photo.png", b"\xff\xd8\xff\xe0\0\x10JFIF"),
+            Some(Jpeg)
+        );
+        assert_eq!(probe("a.jpg", b"\0\0\0\x1cftypavif\0\0\0\0"), Some(Avif));
+        assert_eq!(probe("w.png", b"RIFF\x10\0\0\0WEBPVP8 "), Some(Webp));
+        assert_eq!(probe("j.jpg", b"\xff\x0a\xfa"), Some(Jxl));
+        assert_eq!(probe("c.png", b"\0\0\0\x0cJXL \r\n\x87\n"), Some(Jxl));
+        assert_eq!(probe("text.jpg", b"not an image"), None);
+        assert_eq!(probe("empty.jpg", b""), None);
+
+        let mut job = ConversionJob::new(dir.join("scan.jpg"), Jpeg, Avif).unwrap();
+        job.probe_format().unwrap();
+        assert_eq!(job.current, Png);
+
+        // --only goes by the probed format
+        let config = ConversionConfig {
+            target: Avif,
+            probe_format: true,
+            only: vec![Jpeg],
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        let cancel = Cancellation::new(&interrupted, None);
+        let mut job = ConversionJob::new(dir.join("scan.jpg"), Jpeg, Avif).unwrap();
+        assert!(matches!(job.run(&config, &cancel), Err(Unselected(_, Png))));
+    }
+
+    #[test]
+    fn test_interrupted_write_leaves_no_archive() {
+        let dir = TempDir::new("write_atomically");
+        let zip_path = dir.join("book.avif.cbz");
+
+        let result = write_atomically(&zip_path, |mut file, _| {
+            file.write_all(b"first half").unwrap();
+            Err(Interrupt)
+        });
+        assert!(matches!(result, Err(Interrupt)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        // nor does an archive that cannot be read back
+        let result = write_atomically(&zip_path, |mut file, partial_path| {
+            file.write_all(b"not a zip").unwrap();
+            Container::Zip
+                .verify(partial_path)
+                .map_err(|_| VerificationFailed(zip_path.clone()))
+        });
+        assert!(matches!(result, Err(VerificationFailed(_))));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        write_atomically(&zip_path, |mut file, _| {
+            assert!(!zip_path.exists());
+            file.write_all(b"complete")
+                .map_err(|e| Unspecific(e.to_string()))
+        })
+        .unwrap();
+        assert_eq!(fs::read(&zip_path).unwrap(), b"complete");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_uncreatable_archive_names_its_path() {
+        // permissions do not stop root, a missing directory fails the same way for everyone
+        let dir = TempDir::new("missing_dir");
+        let zip_path = dir.join("missing/book.avif.cbz");
+        let result = write_atomically(&zip_path, |_, _| unreachable!());
+        let Err(e @ CannotCreate(..)) = result else {
+            panic!("unexpected result {result:?}");
+        };
+        assert!(e
+            .to_string()
+            .starts_with(&format!("could not create '{}': ", zip_path.display())));
+    }
+
+    #[test]
+    fn test_recursive_archive_paths() {
+        let dir = TempDir::new("recursive");
+        fs::create_dir_all(dir.join("series/volume 2")).unwrap();
+        for archive in ["a.cbz", "series/b.cbz", "series/volume 2/c.cbt"] {
+            File::create(dir.join(archive)).unwrap();
+        }
+
+        // left over from an interrupted conversion of a.cbz, which contained another archive
+        fs::create_dir_all(dir.join("a/extras")).unwrap();
+        File::create(dir.join("a/extras/bonus.cbz")).unwrap();
+
+        let mut config = ConversionConfig::default();
+        let mut top_level = archive_paths(&dir, &config);
+        top_level.sort();
+        assert_eq!(
+            top_level,
+            [dir.join("a"), dir.join("a.cbz"), dir.join("series")]
+        );
+        config.recursive = true;
+        assert_eq!(
+            archive_paths(&dir, &config),
+            [
+                dir.join("a.cbz"),
+                dir.join("series/b.cbz"),
+                dir.join("series/volume 2/c.cbt")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skip_earlier_conversions() {
+        let dir = TempDir::new("skip_earlier_conversions");
+        fs::create_dir_all(dir.join("series")).unwrap();
+        // left by an earlier run to webp
+        for archive in [
+            "book.cbz",
+            "book.webp.cbz",
+            "My.Comic.v01.cbz",
+            "Vol.1 webp.cbz",
+            "series/c.webp.part01.cbz",
+            "series/c.avif.cbz",
+            "series/c.cbz",
+        ] {
+            File::create(dir.join(archive)).unwrap();
+        }
+
+        let mut config = ConversionConfig {
+            target: Webp,
+            recursive: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            archive_paths(&dir, &config),
+            [
+                dir.join("My.Comic.v01.cbz"),
+                dir.join("Vol.1 webp.cbz"),
+                dir.join("book.cbz"),
+                // written by a run to another format
+                dir.join("series/c.avif.cbz"),
+                dir.join("series/c.cbz"),
+            ]
+        );
+        // given by its path, an archive is converted anyway
+        let converted = dir.join("book.webp.cbz");
+        assert_eq!(archive_paths(&converted, &config), [converted]);
+        config.include_converted = true;
+        assert_eq!(archive_paths(&dir, &config).len(), 7);
+    }
+
+    #[test]
+    fn test_retarget_names() {
+        let dir = TempDir::new("retarget");
+        for archive in [
+            "book.cbz",
+            "book.avif.cbz",
+            "My.Comic.v01.webp.cbz",
+            "other.cbz",
+        ] {
+            File::create(dir.join(archive)).unwrap();
+        }
+
+        let config = ConversionConfig {
+            target: Jxl,
+            retarget: true,
+            ..Default::default()
+        };
+        let converted = |name: &str| config.converted_path(&dir.join(name), Container::Zip);
+        assert_eq!(converted("book.avif.cbz"), dir.join("book.jxl.cbz"));
+        assert_eq!(
+            converted("My.Comic.v01.webp.cbz"),
+            dir.join("My.Comic.v01.jxl.cbz")
+        );
+        // names without a format are kept whole
+        assert_eq!(
+            converted("My.Comic.v01.cbz"),
+            dir.join("My.Comic.v01.jxl.cbz")
+        );
+        assert_eq!(
+            config.part_path(&dir.join("book.avif.cbz"), Container::Zip, 1),
+            dir.join("book.jxl.part01.cbz")
+        );
+        assert_eq!(
+            archive_paths(&dir, &config),
+            [dir.join("book.avif.cbz"), dir.join("My.Comic.v01.webp.cbz")]
+        );
+
+        assert!(!already_converted(&dir.join("book.avif.cbz"), &config));
+        File::create(dir.join("book.jxl.cbz")).unwrap();
+        assert!(already_converted(&dir.join("book.avif.cbz"), &config));
+        assert!(already_converted(&dir.join("book.jxl.cbz"), &config));
+    }
+
+    #[test]
+    fn test_exclude_dirs() {
+        let dir = TempDir::new("exclude_dirs");
+        for archive in [
+            "a.cbz",
+            "previews/a.cbz",
+            "series/b.cbz",
+            "series/previews/b.cbz",
+            "other/previews/c.cbz",
+        ] {
+            fs::create_dir_all(dir.join(archive).parent().unwrap()).unwrap();
+            File::create(dir.join(archive)).unwrap();
+        }
+        // a file named like an excluded directory is no directory to prune
+        File::create(dir.join("series/previews.cbz")).unwrap();
+
+        let mut config = ConversionConfig {
+            recursive: true,
+            exclude_dirs: vec![PathBuf::from("series/previews")],
+            ..Default::default()
+        };
+        assert_eq!(
+            archive_paths(&dir, &config),
+            [
+                dir.join("a.cbz"),
+                dir.join("other/previews/c.cbz"),
+                dir.join("previews/a.cbz"),
+                dir.join("series/b.cbz"),
+                dir.join("series/previews.cbz"),
+            ]
+        );
+        config.exclude_dirs = vec![PathBuf::from("previews")];
+        assert_eq!(
+            archive_paths(&dir, &config),
+            [
+                dir.join("a.cbz"),
+                dir.join("series/b.cbz"),
+                dir.join("series/previews.cbz"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_intermediate_does_not_collide_with_pages() {
+        let job = ConversionJob::new(PathBuf::from("book/cover.avif"), Avif, Webp).unwrap();
+        let config = ConversionConfig::default();
+        let steps = job.plan(&config).unwrap();
+        assert_eq!(steps, [Step::Decode(Avif, Png), Step::Encode(Png, Webp)]);
+        // the archive may well contain a genuine book/cover.png as well
+        let intermediate = job.output_path(steps[0], false, &config);
+        assert_eq!(intermediate, PathBuf::from("book/cover.cbzin-tmp.png"));
+        assert_eq!(
+            job.output_path(steps[1], true, &config),
+            PathBuf::from("book/cover.webp")
+        );
+        // kept intermediates stay out of the converted archive
+        let config = ConversionConfig {
+            keep_intermediates: true,
+            ..config
+        };
+        assert!(!config.keeps_file(Container::Zip, &intermediate));
+        assert!(config.keeps_file(Container::Zip, Path::new("book/cover.png")));
+    }
+
+    #[test]
+    fn test_configured_extensions() {
+        let config = ConversionConfig {
+            target: Avif,
+            extensions: vec![(Jpeg, "jpg".to_string()), (Avif, "avifs".to_string())],
+            ..Default::default()
+        };
+        let job = ConversionJob::new(PathBuf::from("book/1.jxl"), Jxl, Avif).unwrap();
+        let steps = plan_steps(Jxl, Avif, &config, Jpeg);
+        assert_eq!(
+            job.output_path(steps[0], false, &config),
+            PathBuf::from("book/1.cbzin-tmp.jpg")
+        );
+        assert_eq!(
+            job.output_path(steps[1], true, &config),
+            PathBuf::from("book/1.avifs")
+        );
+        assert_eq!(image_format(Path::new("book/1.avifs")), Some(Avif));
+        assert_eq!(
+            config.converted_path(Path::new("book.cbz"), Container::Zip),
+            PathBuf::from("book.avifs.cbz")
+        );
+        assert_eq!(Png.ext_for(&config), "png");
+
+        assert_eq!(parse_extension("JPEG=jpg"), Ok((Jpeg, "jpg".to_string())));
+        assert!(parse_extension("png=jpg").is_err());
+        assert!(parse_extension("jxl").is_err());
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(12_900_000), "12.3 MiB");
+        assert_eq!(human_size(3 << 30), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_path_length_limits() {
+        let root = Path::new("/comics/book");
+        let entry = |path: String| (PathBuf::from(path), false);
+        let converted = |entries: &[(PathBuf, bool)]| {
+            let paths = entries.iter().map(|(path, _)| path.as_path()).collect();
+            check_path_lengths(root, entries, &paths)
+        };
+        let fine = [entry("book/chapter 1/001.png".to_string())];
+        assert!(converted(&fine).is_ok());
+
+        let long_name = [entry(format!("{}.png", "a".repeat(245)))];
+        assert!(matches!(
+            converted(&long_name),
+            Err(PathTooLong(path, _)) if path == long_name[0].0
+        ));
+        // no intermediate files are written beside what is not converted
+        assert!(check_path_lengths(root, &long_name, &HashSet::new()).is_ok());
+        let deep = [entry(format!("{}001.png", "chapter/".repeat(600)))];
+        assert!(matches!(converted(&deep), Err(PathTooLong(..))));
+    }
+
+    #[test]
+    fn test_plan_alternative_png_and_jpeg_tools() {
+        let config = ConversionConfig {
+            png_tool: PngTool::Oxipng,
+            jpeg_tool: JpegTool::Mozjpeg,
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_steps(Jpeg, Png, &config, Png),
+            [Step::Convert(Jpeg, Png), Step::Optimize(Png)]
+        );
+        assert_eq!(
+            plan_steps(Png, Jpeg, &config, Png),
+            [Step::Encode(Png, Jpeg)]
+        );
+        assert_eq!(Step::Encode(Png, Jpeg).tool(), Tool::Cjpeg);
+        // other conversions to png and jpeg stay as they are
+        assert_eq!(
+            plan_steps(Avif, Png, &config, Png),
+            [Step::Decode(Avif, Png)]
+        );
+        let optimized = ConversionConfig {
+            optimize_png: true,
+            ..config.clone()
+        };
+        assert_eq!(
+            plan_steps(Avif, Png, &optimized, Png),
+            [Step::Decode(Avif, Png), Step::Optimize(Png)]
+        );
+        assert_eq!(
+            plan_steps(Jpeg, Png, &optimized, Png),
+            [Step::Convert(Jpeg, Png), Step::Optimize(Png)]
+        );
+        // the png in between is not worth optimizing
+        assert_eq!(
+            plan_steps(Avif, Webp, &optimized, Png),
+            [Step::Decode(Avif, Png), Step::Encode(Png, Webp)]
+        );
+
+        // cjpeg cannot resize, magick takes care of that first
+        let config = ConversionConfig {
+            max_dimension: Some(2000),
+            ..config
+        };
+        assert_eq!(
+            plan_steps(Png, Jpeg, &config, Png),
+            [Step::Convert(Png, Png), Step::Encode(Png, Jpeg)]
+        );
+    }
+
+    #[test]
+    fn test_recompress_only_when_requested() {
+        let mut config = ConversionConfig {
+            target: Jpeg,
+            ..Default::default()
+        };
+        let job = |config: &ConversionConfig| {
+            ConversionJob::for_config(PathBuf::from("1.jpg"), Jpeg, Jpeg, config)
+        };
+        assert!(job(&config).is_err());
+        assert_eq!(plan_steps(Jpeg, Jpeg, &config, Png), []);
+
+        config.recompress = true;
+        let job = job(&config).unwrap();
+        assert_eq!(job.plan(&config).unwrap(), [Step::Convert(Jpeg, Jpeg)]);
+        // other formats are converted as usual
+        assert_eq!(
+            ConversionJob::for_config(PathBuf::from("2.png"), Png, Jpeg, &config)
+                .unwrap()
+                .target,
+            Jpeg
+        );
+    }
+
+    #[test]
+    fn test_unknown_files() {
+        let dir = TempDir::new("unknown_files");
+        fs::create_dir_all(dir.join("series")).unwrap();
+        let names = [
+            "a.cbz",
+            "a.avif.cbz",
+            "a.avif.cbz.log",
+            "a.avif.cbz.cbzin-tmp",
+            "b.cbx",
+            "notes.txt",
+        ];
+        for name in names {
+            File::create(dir.join(name)).unwrap();
+        }
+        let unknown = names
+            .into_iter()
+            .filter(|name| is_unknown_file(&dir.join(name)))
+            .collect::<Vec<_>>();
+        assert_eq!(unknown, ["b.cbx", "notes.txt"]);
+        assert!(!is_unknown_file(&dir.join("series")));
+    }
+
+    #[test]
+    fn test_split_entries() {
+        let entry = |name: &str, size| ArchiveEntry {
+            name: name.to_string(),
+            path: PathBuf::from(name),
+            size,
+        };
+        let entries = [
+            entry("book", None),
+            entry("book/1.avif", Some(1000)),
+            entry("book/2.avif", Some(1000)),
+            entry("book/extra", None),
+            entry("book/extra/3.avif", Some(5000)),
+            entry("book/extra/4.avif", Some(500)),
+            entry("book/empty", None),
+        ];
+        // headers add a bit on top of every file
+        let parts = split_entries(Container::Zip, &entries, 2500);
+        assert_eq!(parts, [vec![0, 1, 2], vec![0, 3, 4], vec![0, 3, 5, 6]]);
+        // only the part with the file too large on its own exceeds the limit
+        for part in [&parts[0], &parts[2]] {
+            let size = part
+                .iter()
+                .map(|&index| &entries[index])
+                .map(|entry| Container::Zip.stored_size(&entry.name, entry.size.unwrap_or(0)))
+                .sum::<u64>();
+            assert!(Container::Zip.empty_size() + size <= 2500);
+        }
+
+        let parts = split_entries(Container::Tar, &entries, 1 << 20);
+        assert_eq!(parts, [(0..entries.len()).collect::<Vec<_>>()]);
+    }
+
+    #[test]
+    fn test_converted_archives_convert_to_other_formats() {
+        let dir = TempDir::new("convert_converted");
+        let avif_archive = dir.join("book.avif.cbz");
+        File::create(&avif_archive).unwrap();
+
+        let to = |target| ConversionConfig {
+            target,
+            ..Default::default()
+        };
+        assert!(already_converted(&avif_archive, &to(Avif)));
+        assert!(!already_converted(&avif_archive, &to(Webp)));
+        assert!(!already_converted(&avif_archive, &to(Jxl)));
+        // the result of converting it again is detected like any other
+        File::create(dir.join("book.avif.webp.cbz")).unwrap();
+        assert!(already_converted(&avif_archive, &to(Webp)));
+        assert!(already_converted(
+            &dir.join("book.avif.webp.cbz"),
+            &to(Webp)
+        ));
+    }
+
+    #[test]
+    fn test_split_archives_count_as_converted() {
+        let dir = TempDir::new("split_converted");
+        let source = dir.join("book.cbz");
+        File::create(&source).unwrap();
+        let config = ConversionConfig {
+            target: Avif,
+            split_size: Some(1 << 20),
+            ..Default::default()
+        };
+        let part = config.part_path(&source, Container::Zip, 1);
+        assert_eq!(part, dir.join("book.avif.part01.cbz"));
+        assert!(!already_converted(&source, &config));
+        File::create(&part).unwrap();
+        assert!(already_converted(&source, &config));
+        assert!(already_converted(&part, &config));
+        assert!(!already_converted(&dir.join("book.part01.cbz"), &config));
+    }
+
+    #[test]
+    fn test_parts_move_into_place_together() {
+        let dir = TempDir::new("parts_together");
+        let source = dir.join("book.cbz");
+        write_zip(
+            &source,
+            &[("book/1.avif", &[1; 100]), ("book/2.avif", &[2; 100])],
+        );
+        let config = ConversionConfig {
+            target: Avif,
+            repack_only: true,
+            split_size: Some(1),
+            ..Default::default()
+        };
+        let parts = [1, 2].map(|part| config.part_path(&source, Container::Zip, part));
+        let interrupted = AtomicBool::new(false);
+
+        // the second part cannot take its name, so the first one must not be left over
+        fs::create_dir(&parts[1]).unwrap();
+        let work_unit = WorkUnit::new(&source, &config, 1).unwrap();
+        assert!(work_unit
+            .run(&mut LogReporter::default(), &interrupted)
+            .is_err());
+        assert!(!parts[0].exists());
+        assert!(!already_converted(&source, &config));
+        let mut names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["book.cbz", "book.repacked.part02.cbz"]);
+
+        fs::remove_dir(&parts[1]).unwrap();
+        let work_unit = WorkUnit::new(&source, &config, 1).unwrap();
+        work_unit
+            .run(&mut LogReporter::default(), &interrupted)
+            .unwrap();
+        assert!(parts.iter().all(|part| part.is_file()));
+    }
+
+    #[test]
+    fn test_leave_out_unconvertible_files() {
+        let mut config = ConversionConfig::default();
+        let kept = |config: &ConversionConfig, container| {
+            [
+                "book/1.avif",
+                "book/ComicInfo.xml",
+                "book/2.jpg",
+                "notes.txt",
+            ]
+            .into_iter()
+            .filter(|name| config.keeps_file(container, Path::new(name)))
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(kept(&config, Container::Zip).len(), 4);
+        config.drop_unconvertible = true;
+        assert_eq!(kept(&config, Container::Zip), ["book/1.avif", "book/2.jpg"]);
+        assert_eq!(kept(&config, Container::Epub).len(), 4);
+    }
+
+    #[test]
+    fn test_strip_macos_junk() {
+        let listing = "\
+Path = __MACOSX
+Folder = +
+Size = 0
+
+Path = __MACOSX/._1.jpg
+Folder = -
+Size = 4096
+
+Path = __MACOSX/chapter/._2.jpg
+Folder = -
+Size = 4096
+
+Path = 1.jpg
+Folder = -
+Size = 2048
+
+Path = chapter/2.jpg
+Folder = -
+Size = 2048
+
+Path = chapter/.DS_Store
+Folder = -
+Size = 6148
+
+Path = Thumbs.db
+Folder = -
+Size = 1024
+
+Path = ComicInfo.xml
+Folder = -
+Size = 512
+";
+        let mut config = ConversionConfig::default();
+        let kept = |config: &ConversionConfig| {
+            files_with_format(parse_entries(listing.as_bytes()))
+                .into_iter()
+                .filter(|(name, _)| config.keeps_file(Container::Zip, name))
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kept(&config),
+            ["1.jpg", "chapter/2.jpg", "ComicInfo.xml"].map(PathBuf::from)
+        );
+        config.keep_junk = true;
+        assert_eq!(kept(&config).len(), 7);
+    }
+
+    #[test]
+    fn test_limit_processes() {
+        assert_eq!(limit_processes(2, 4, None), (2, 4));
+        assert_eq!(limit_processes(2, 4, Some(8)), (2, 4));
+        assert_eq!(limit_processes(2, 4, Some(6)), (2, 3));
+        // every archive needs a process at least
+        assert_eq!(limit_processes(4, 4, Some(2)), (2, 1));
+        assert_eq!(limit_processes(1, 16, Some(4)), (1, 4));
+    }
+
+    #[test]
+    fn test_merge_archive_reports() {
+        let mut reporter = LogReporter::default();
+        reporter.sizes(Jpeg, Avif, 100, 40);
+        let mut archive = reporter.for_archive();
+        archive.start(Path::new("book.cbz"), 3);
+        archive.report(Path::new("1.jpg"), Jpeg, Avif, ImageOutcome::Converted);
+        archive.report(Path::new("2.png"), Png, Avif, ImageOutcome::Fallback);
+        archive.report(Path::new("3.avif"), Avif, Avif, ImageOutcome::Skipped);
+        archive.sizes(Jpeg, Avif, 100, 60);
+        archive.sizes(Png, Avif, 200, 50);
+        reporter.merge(&archive);
+        assert_eq!((reporter.converted, reporter.fallbacks), (2, 1));
+        assert_eq!((reporter.skipped, reporter.failed), (1, 0));
+        assert_eq!(
+            reporter.size_breakdown(),
+            [
+                "jpeg -> avif saved 50% (200 B -> 100 B over 2 images)",
+                "png -> avif saved 75% (200 B -> 50 B over 1 images)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_workers_limited_by_memory() {
+        let memory = Some(4 << 30);
+        assert_eq!(default_workers(16, memory, Jxl.memory_estimate()), 4);
+        assert_eq!(default_workers(16, memory, Jpeg.memory_estimate()), 16);
+        assert_eq!(default_workers(16, Some(1 << 20), Jxl.memory_estimate()), 1);
+        assert_eq!(default_workers(16, None, Jxl.memory_estimate()), 16);
+    }
+
+    #[test]
+    fn test_archive_subpath() {
+        let config = ConversionConfig {
+            subpath: Some(PathBuf::from("omnibus/content/")),
+            ..Default::default()
+        };
+        let selected = [
+            "omnibus/content/1.jpg",
+            "omnibus/content/chapter 2/1.jpg",
+            "omnibus/extras/sketch.jpg",
+            "omnibus/contents.jpg",
+            "omnibus/cover.jpg",
+        ]
+        .into_iter()
+        .filter(|path| config.selects_path(Path::new(path)))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            selected,
+            ["omnibus/content/1.jpg", "omnibus/content/chapter 2/1.jpg"]
+        );
+        assert!(ConversionConfig::default().selects_path(Path::new("omnibus/cover.jpg")));
+    }
+
+    #[test]
+    fn test_failed_job_leaves_only_the_original() {
+        let dir = TempDir::new("failed_job_leftovers");
+        for name in ["1.webp", "1.cbzin-tmp.png", "1.avif", "2.png"] {
+            File::create(dir.join(name)).unwrap();
+        }
+
+        let config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        let mut job = ConversionJob::for_config(dir.join("1.webp"), Webp, Avif, &config).unwrap();
+        job.steps = job.plan(&config).unwrap();
+        job.remove_leftovers(&config);
+        let mut left = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["1.webp", "2.png"]);
+    }
+
+    #[test]
+    fn test_image_count_of_written_archive() {
+        let dir = TempDir::new("image_count");
+        let zip_path = dir.join("book.avif.cbz");
+        let files = ["book/1.avif", "book/2.avif", "book/ComicInfo.xml"];
+        write_zip(&zip_path, &files.map(|name| (name, &b""[..])));
+
+        assert!(check_image_count(2, std::slice::from_ref(&zip_path)).is_ok());
+        assert!(matches!(
+            check_image_count(3, &[zip_path]),
+            Err(ImageCountMismatch(_, 3, 2))
+        ));
+    }
+
+    #[test]
+    fn test_repack_only() {
+        use std::ffi::OsStr;
+
+        let dir = TempDir::new("repack_only");
+        let zip_path = dir.join("book.cbz");
+        write_zip(&zip_path, &[("book/2.avif", b""), ("book/1.avif", b"")]);
+
+        let mut config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        assert!(matches!(
+            WorkUnit::new(&zip_path, &config, 1),
+            Err(NothingToDo(_))
+        ));
+        config.repack_only = true;
+        let work_unit = WorkUnit::new(&zip_path, &config, 1).unwrap();
+        assert!(work_unit.job_queue.is_empty());
+        assert_eq!(work_unit.skipped.len(), 2);
+        assert_eq!(
+            config.converted_path(&zip_path, Container::Zip),
+            dir.join("book.repacked.cbz")
+        );
+        drop(work_unit);
+
+        let parse = |args: &[&OsStr]| {
+            let args = Args::try_parse_from([OsStr::new("cbz_in")].iter().chain(args)).unwrap();
+            (args.repack_only, args.format_and_path())
+        };
+        let repack_only = OsStr::new("--repack-only");
+        assert_eq!(
+            parse(&[repack_only, zip_path.as_os_str()]),
+            (true, Ok((None, zip_path.clone())))
+        );
+        assert_eq!(
+            parse(&[repack_only, dir.as_os_str(), zip_path.as_os_str()]),
+            (true, Err(dir.to_path_buf()))
+        );
+        assert_eq!(
+            parse(&[zip_path.as_os_str()]),
+            (false, Err(zip_path.clone()))
+        );
+    }
+
+    #[test]
+    fn test_comic_info_page_order() {
+        let dir = TempDir::new("comic_info");
+        let zip_path = dir.join("book.cbz");
+        let comic_info = r#"<ComicInfo><Pages>
+            <Page Image="2" Type="FrontCover" />
+            <Page Image="0" />
+            <Page Image="1" Type="Advertisement" />
+        </Pages></ComicInfo>"#;
+        write_zip(
+            &zip_path,
+            &[
+                ("book/1.png", b""),
+                ("book/2.png", b""),
+                ("book/3.png", b""),
+                ("book/4.png", b""),
+                ("book/ComicInfo.xml", comic_info.as_bytes()),
+            ],
+        );
+
+        let written_order = |config: &ConversionConfig| {
+            let mut work_unit = WorkUnit::new(&zip_path, config, 1).unwrap();
+            work_unit.extract(&AtomicBool::new(false)).unwrap();
+            work_unit.apply_comic_info().unwrap();
+            let entries = work_unit.entries_to_compress().unwrap();
+            entries
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect::<Vec<_>>()
+        };
+        let mut config = ConversionConfig {
+            target: Webp,
+            use_comic_info: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            written_order(&config),
+            [
+                "book",
+                "book/ComicInfo.xml",
+                "book/3.png",
+                "book/1.png",
+                "book/2.png",
+                "book/4.png"
+            ]
+        );
+        config.drop_deleted_pages = true;
+        assert_eq!(
+            written_order(&config),
+            [
+                "book",
+                "book/ComicInfo.xml",
+                "book/3.png",
+                "book/1.png",
+                "book/4.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reuse_identical_images() {
+        let dir = TempDir::new("reuse_identical");
+        let zip_path = dir.join("book.cbz");
+        write_zip(
+            &zip_path,
+            &[
+                ("book/1.png", b"recap"),
+                ("book/2.png", b"page"),
+                ("book/3.png", b"recap"),
+            ],
+        );
+
+        let config = ConversionConfig {
+            target: Avif,
+            reuse_identical: true,
+            ..Default::default()
+        };
+        let mut work_unit = WorkUnit::new(&zip_path, &config, 1).unwrap();
+        work_unit.extract(&AtomicBool::new(false)).unwrap();
+        work_unit.group_identical_jobs().unwrap();
+        let jobs = work_unit
+            .job_queue
+            .iter()
+            .map(|job| (job.image_path.file_name().unwrap(), job.identical.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(jobs, [("1.png".as_ref(), 1), ("2.png".as_ref(), 0)]);
+
+        // as if the first one was converted
+        let job = &work_unit.job_queue[0];
+        fs::remove_file(&job.image_path).unwrap();
+        fs::write(dir.join("book/1.avif"), "converted recap").unwrap();
+        job.reuse_for(&job.identical[0], &config).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("book/3.avif")).unwrap(),
+            "converted recap"
+        );
+        assert!(!dir.join("book/3.png").exists());
+        drop(work_unit);
+    }
+
+    #[test]
+    fn test_keep_zip_comment() {
+        let dir = TempDir::new("keep_zip_comment");
+        let zip_path = dir.join("book.cbz");
+        write_zip(&zip_path, &[("book/1.avif", b"")]);
+        let file = File::options().read(true).write(true).open(&zip_path);
+        let mut zipper = zip::ZipWriter::new_append(file.unwrap()).unwrap();
+        zipper.set_comment("Series: Example, Vol. 1");
+        zipper.finish().unwrap();
+
+        let config = ConversionConfig {
+            target: Avif,
+            repack_only: true,
+            ..Default::default()
+        };
+        let work_unit = WorkUnit::new(&zip_path, &config, 1).unwrap();
+        let mut reporter = LogReporter::default();
+        work_unit
+            .run(&mut reporter, &AtomicBool::new(false))
+            .unwrap();
+        assert_eq!(
+            container::zip_comment(&dir.join("book.repacked.cbz")).unwrap(),
+            b"Series: Example, Vol. 1"
+        );
+    }
+    #[test]
+    fn test_keep_temp_on_error() {
+        let dir = TempDir::new("keep_temp_on_error");
+        let zip_path = dir.join("book.cbz");
+        write_zip(&zip_path, &[("book/1.png", b"not really a png")]);
+
+        let mut config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        for keep in [false, true] {
+            config.keep_temp_on_error = keep;
+            let work_unit = WorkUnit::new(&zip_path, &config, 1).unwrap();
+            let mut reporter = LogReporter::default();
+            assert!(work_unit.run(&mut reporter, &interrupted).is_err());
+            assert_eq!(dir.join("book").is_dir(), keep);
+        }
+    }
+
+    #[test]
+    fn test_animated_avif_is_kept() {
+        let dir = TempDir::new("animated_avif");
+        let sequence = b"\0\0\0\x20ftypavis\0\0\0\0avifavismsf1miaf";
+        fs::write(dir.join("1.avif"), sequence).unwrap();
+
+        let mut config = ConversionConfig {
+            target: Webp,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        let cancel = Cancellation::new(&interrupted, None);
+        let mut job = ConversionJob::new(dir.join("1.avif"), Avif, Webp).unwrap();
+        assert!(matches!(job.run(&config, &cancel), Err(Animated(_))));
+        assert_eq!(fs::read(dir.join("1.avif")).unwrap(), sequence);
+        // flattened, it gets as far as the tools
+        config.flatten_animated = true;
+        assert!(!matches!(job.run(&config, &cancel), Err(Animated(_))));
+    }
+
+    #[test]
+    fn test_listing_dimensions() {
+        use std::ffi::OsStr;
+
+        let dir = TempDir::new("listing_dimensions");
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend([0, 0, 3, 0x20, 0, 0, 4, 0xb0, 8, 2, 0, 0, 0]);
+        write_zip(
+            &dir.join("book.cbz"),
+            &[("book/1.png", &png), ("book/notes.txt", b"")],
+        );
+        // where an extraction would go, which is of no concern to the listing
+        fs::create_dir(dir.join("book")).unwrap();
+
+        assert_eq!(
+            listing(&dir.join("book.cbz"), false).unwrap(),
+            "book/1.png: png 800x1200\nbook/notes.txt: non-image"
+        );
+        assert_eq!(
+            listing(&dir.join("book.cbz"), true).unwrap(),
+            concat!(
+                r#"[{"path":"book/1.png","format":"png","width":800,"height":1200},"#,
+                r#"{"path":"book/notes.txt","format":null,"width":null,"height":null}]"#
+            )
+        );
+        assert_eq!(fs::read_dir(dir.join("book")).unwrap().count(), 0);
+
+        let args = Args::try_parse_from([
+            OsStr::new("cbz_in"),
+            OsStr::new("--list"),
+            dir.join("book.cbz").as_os_str(),
+        ])
+        .unwrap();
+        assert_eq!(args.format_and_path(), Ok((None, dir.join("book.cbz"))));
+    }
+
+    #[test]
+    fn test_transparent_png_is_kept() {
+        let dir = TempDir::new("transparent_png");
+        let mut rgba = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        rgba.extend([0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+        fs::write(dir.join("1.png"), &rgba).unwrap();
+
+        let mut config = ConversionConfig {
+            target: Jpeg,
+            jpeg_tool: JpegTool::Mozjpeg,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        let cancel = Cancellation::new(&interrupted, None);
+        let mut job = ConversionJob::new(dir.join("1.png"), Png, Jpeg).unwrap();
+        assert!(matches!(job.run(&config, &cancel), Err(Transparent(_))));
+        assert_eq!(fs::read(dir.join("1.png")).unwrap(), rgba);
+
+        // filled in by magick, as cjpeg cannot
+        config.alpha_background = Some("white".to_string());
+        let steps = job.plan(&config).unwrap();
+        assert_eq!(steps, [Step::Convert(Png, Jpeg)]);
+        let command = job.invocations(&steps, &config).unwrap()[0].to_string();
+        assert!(
+            command.contains(" -background white -alpha remove -alpha off "),
+            "{command}"
+        );
+    }
+
+    #[test]
+    fn test_shutdown_grace() {
+        let interrupted = AtomicBool::new(true);
+        let cancel = Cancellation::new(&interrupted, Some(Duration::from_secs(60)));
+        assert!(cancel.is_set());
+        assert!(!cancel.stops_running());
+
+        let config = ConversionConfig::default();
+        let (sender, _receiver) = mpsc::channel();
+        let root_dir = Path::new("book");
+        // all images started, so the archive can still be completed
+        let empty = Mutex::new(VecDeque::new());
+        assert!(work_on_queue(&empty, &config, &cancel, root_dir, sender.clone()).is_ok());
+        // the archive is given up with images left, the running ones may still finish
+        let job = ConversionJob::new(PathBuf::from("book/1.png"), Png, Avif).unwrap();
+        let queue = Mutex::new(VecDeque::from([job]));
+        assert!(matches!(
+            work_on_queue(&queue, &config, &cancel, root_dir, sender),
+            Err(Interrupt)
+        ));
+        assert_eq!(queue.lock().unwrap().len(), 1);
+        assert!(!cancel.stops_running());
+
+        assert!(Cancellation::new(&interrupted, None).stops_running());
+    }
+
+    #[test]
+    fn test_verbose_error_report() {
+        let error = CannotCreate(
+            PathBuf::from("book.avif.cbz"),
+            io::Error::from(io::ErrorKind::PermissionDenied),
+        );
+        assert_eq!(
+            error_report(&error, false),
+            "could not create 'book.avif.cbz': permission denied"
+        );
+        assert_eq!(
+            error_report(&error, true),
+            "could not create 'book.avif.cbz': permission denied\n  \
+            CannotCreate(\"book.avif.cbz\", Kind(PermissionDenied))\n  \
+            caused by: Kind(PermissionDenied)"
+        );
+    }
+
+    #[test]
+    fn test_merge_archives() {
+        let dir = TempDir::new("merge_archives");
+        fs::create_dir_all(dir.join("series")).unwrap();
+        for (archive, names) in [
+            ("vol2", ["10.png", "2.png"]),
+            ("vol1", ["vol1/1.png", "vol1/2.png"]),
+        ] {
+            let zip_path = dir.join(format!("series/{archive}.cbz"));
+            write_zip(&zip_path, &names.map(|name| (name, &b"png"[..])));
+        }
+
+        let config = ConversionConfig {
+            target: Png,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        let merge = |merge_path: &Path| {
+            let mut reporter = LogReporter::default();
+            let series = dir.join("series");
+            merge_archives(&series, merge_path, &config, 1, &mut reporter, &interrupted)
+        };
+        let merged = dir.join("merged.cbz");
+        merge(&merged).unwrap();
+        let entries = container::zip_entries(&merged).unwrap();
+        let names = entries
+            .iter()
+            .map(|(name, _)| name.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "merged",
+                "merged/vol1",
+                "merged/vol1/1.png",
+                "merged/vol1/2.png",
+                "merged/vol2",
+                "merged/vol2/2.png",
+                "merged/vol2/10.png"
+            ]
+        );
+        assert!(!dir.join(format!("merged.{INTERMEDIATE_SUFFIX}")).exists());
+        assert!(matches!(merge(&merged), Err(AlreadyDone(_))));
+        assert!(matches!(
+            merge(&dir.join("merged.cbt")),
+            Err(NotAnArchive(_))
+        ));
+    }
+
+    #[test]
+    fn test_folder_as_archive() {
+        let dir = TempDir::new("folder_as_archive");
+        fs::create_dir_all(dir.join("book/extras")).unwrap();
+        for name in ["book/1.avif", "book/2.avif", "book/extras/3.avif"] {
+            fs::write(dir.join(name), b"avif").unwrap();
+        }
+
+        let packed = dir.join("packed.cbz");
+        pack_folder(&dir.join("book"), &packed).unwrap();
+        let entries = container::zip_entries(&packed).unwrap();
+        let names = entries
+            .iter()
+            .map(|(name, _)| name.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "book",
+                "book/1.avif",
+                "book/2.avif",
+                "book/extras",
+                "book/extras/3.avif"
+            ]
+        );
+
+        let config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        let convert = || {
+            let mut reporter = LogReporter::default();
+            convert_folder(&dir.join("book"), &config, 1, &mut reporter, &interrupted)
+        };
+        assert!(matches!(convert(), Err(NothingToDo(_))));
+        // nothing is left behind but the directory itself
+        assert!(!dir.join(format!("book.{INTERMEDIATE_SUFFIX}")).exists());
+        assert!(dir.join("book/extras/3.avif").is_file());
+        File::create(dir.join("book.avif.cbz")).unwrap();
+        assert!(matches!(convert(), Err(AlreadyDone(_))));
+    }
+
+    #[test]
+    fn test_empty_output_is_a_failure() {
+        let dir = TempDir::new("empty_output");
+        fs::write(dir.join("1.png"), b"png").unwrap();
+        File::create(dir.join("1.avif")).unwrap();
+
+        let job = ConversionJob::new(dir.join("1.png"), Png, Avif).unwrap();
+        let failure = |path: &Path| match job.check_output(path) {
+            Err(AbnormalExit(image_path, failure)) => {
+                assert_eq!(image_path, dir.join("1.png"));
+                Some(failure)
+            }
+            _ => None,
+        };
+        assert_eq!(failure(&dir.join("1.avif")), Some(Failure::EmptyOutput));
+        assert_eq!(failure(&dir.join("1.webp")), Some(Failure::EmptyOutput));
+        fs::write(dir.join("1.avif"), b"avif").unwrap();
+        assert_eq!(failure(&dir.join("1.avif")), None);
+    }
+
+    #[test]
+    fn test_targets_share_the_extraction() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = TempDir::new("shared_extraction");
+        fs::create_dir_all(dir.join("book/text")).unwrap();
+        fs::write(dir.join("book/1.png"), b"png").unwrap();
+        fs::write(dir.join("book/text/1.xhtml"), b"<img src=\"../1.png\"/>").unwrap();
+        File::create(dir.join("book.epub")).unwrap();
+
+        let pristine = keep_pristine(&dir.join("book.epub")).unwrap();
+        assert_eq!(pristine, dir.join("book.cbzin-tmp"));
+        let nlink = |path: &Path| fs::metadata(path).unwrap().nlink();
+        assert_eq!(nlink(&dir.join("book/1.png")), 2);
+        // documents are copied, as they are rewritten in place
+        assert_eq!(nlink(&dir.join("book/text/1.xhtml")), 1);
+        assert!(pristine.join("text/1.xhtml").is_file());
+
+        break_hard_link(&dir.join("book/1.png")).unwrap();
+        fs::write(dir.join("book/1.png"), b"optimized").unwrap();
+        assert_eq!(fs::read(pristine.join("1.png")).unwrap(), b"png");
+        assert_eq!(nlink(&pristine.join("1.png")), 1);
+
+        let config = ConversionConfig {
+            target: Avif,
+            extra_targets: vec![Webp, Jxl],
+            ..Default::default()
+        };
+        let targets = config
+            .per_target()
+            .iter()
+            .map(|config| (config.target, config.extra_targets.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(targets, [(Avif, 0), (Webp, 0), (Jxl, 0)]);
+    }
+
+    #[test]
+    fn test_analyze_json() {
+        let dir = TempDir::new("analyze_json");
+        write_zip(
+            &dir.join("book.cbz"),
+            &[("book/1.png", b""), ("book/2.avif", b"")],
+        );
+        fs::write(dir.join("broken.cbz"), b"").unwrap();
+
+        let config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        let json = analyze_json(&dir, &config);
+        let expected = format!(
+            concat!(
+                r#"{{"archives":[{{"archive":{},"converted":1,"skipped":1,"images":["#,
+                r#"{{"path":"book/2.avif","format":"avif","skipped":true,"steps":[]}},"#,
+                r#"{{"path":"book/1.png","format":"png","target":"avif","skipped":false,"#,
+                r#""intermediate":null,"depends_on_image":false,"#,
+                r#""steps":[{{"tool":"cavif","from":"png","to":"avif"}}]}}],"#,
+                r#""tools":["cavif"]}},{{"archive":{},"error":"#
+            ),
+            output::json(&dir.join("book.cbz").to_string_lossy()),
+            output::json(&dir.join("broken.cbz").to_string_lossy()),
+        );
+        assert!(json.starts_with(&expected), "{json}");
+        assert!(json.ends_with(r#"],"tools":["cavif"]}"#), "{json}");
+    }
+
+    #[test]
+    fn test_skip_unsatisfiable() {
+        let dir = TempDir::new("skip_unsatisfiable");
+        write_zip(&dir.join("book.cbz"), &[("book/1.png", b"")]);
+
+        let config = ConversionConfig {
+            target: Jxl,
+            skip_unsatisfiable: true,
+            ..Default::default()
+        };
+        let work_unit = WorkUnit::new(&dir.join("book.cbz"), &config, 1).unwrap();
+        assert_eq!(work_unit.required_tools(), BTreeSet::from([Tool::Cjxl]));
+        drop(work_unit);
+
+        let interrupted = AtomicBool::new(false);
+        let result = prepare_cbz(&dir.join("book.cbz"), &config, 1, None, &interrupted);
+        if !spawn::is_installed(Tool::Cjxl) {
+            assert!(matches!(&result, Err(MissingTools(path, tools))
+                if *path == dir.join("book.cbz") && tools == "cjxl"));
+            // skipped archives do not count as failed
+            assert!(!archive_failed(result.map(|_| ()), &config));
+            assert!(!get_conversion_root_dir(&dir.join("book.cbz")).exists());
+        }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = TempDir::new("dry_run");
+        let zip_path = dir.join("book.cbz");
+        write_zip(&zip_path, &[("book/1.png", b"not really a png")]);
+
+        // running cavif would fail on the image, as would not finding it
+        let config = ConversionConfig {
+            target: Avif,
+            print_commands: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        let work_unit = WorkUnit::new(&zip_path, &config, 1).unwrap();
+        let mut reporter = LogReporter::default();
+        assert!(work_unit.run(&mut reporter, &interrupted).is_ok());
+        assert_eq!((reporter.converted, reporter.skipped), (0, 1));
+        assert!(!config.converted_path(&zip_path, Container::Zip).exists());
+        assert!(!dir.join("book").exists());
+    }
+
+    #[test]
+    fn test_avifdec_quality() {
+        let mut quality = Quality::default();
+        assert_eq!((quality.jpeg, quality.avifdec), (92, 80));
+        quality.set(Jpeg, 70);
+        assert_eq!((quality.jpeg, quality.avifdec), (70, 70));
+    }
+
+    #[test]
+    fn test_cover_quality() {
+        let dir = TempDir::new("cover_quality");
+        let files = ["book/10.png", "book/2.png", "book/1.png"];
+        write_zip(&dir.join("book.cbz"), &files.map(|name| (name, &b""[..])));
+
+        let config = ConversionConfig {
+            target: Webp,
+            cover_quality: Some(95),
+            cover_pages: 2,
+            ..Default::default()
+        };
+        let work_unit = WorkUnit::new(&dir.join("book.cbz"), &config, 1).unwrap();
+        let qualities = work_unit
+            .job_queue
+            .iter()
+            .map(|job| (job.relative_path(&dir), job.quality))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            qualities,
+            [
+                (PathBuf::from("book/1.png"), Some(95)),
+                (PathBuf::from("book/2.png"), Some(95)),
+                (PathBuf::from("book/10.png"), None),
+            ]
+        );
+        let command = |job: &ConversionJob| {
+            let steps = job.plan(&config).unwrap();
+            job.invocations(&steps, &config).unwrap()[0].to_string()
+        };
+        assert!(command(&work_unit.job_queue[0]).starts_with("cwebp -q 95 "));
+        assert!(command(&work_unit.job_queue[2]).starts_with("cwebp -q 90 "));
+    }
+
+    #[test]
+    fn test_unconverted() {
+        let dir = TempDir::new("unconverted");
+        fs::create_dir_all(dir.join("series")).unwrap();
+        for name in [
+            "a.cbz",
+            "a.avif.cbz",
+            "b.cbz",
+            "b.webp.cbz",
+            "notes.txt",
+            "series/c.cbz",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let config = ConversionConfig {
+            target: Avif,
+            ..Default::default()
+        };
+        // written by a run to webp, a run to avif converts it as well
+        assert_eq!(
+            unconverted(&dir, &config),
+            [dir.join("b.cbz"), dir.join("b.webp.cbz")]
+        );
+        let config = ConversionConfig {
+            recursive: true,
+            ..config
+        };
+        assert_eq!(
+            unconverted(&dir, &config),
+            [
+                dir.join("b.cbz"),
+                dir.join("b.webp.cbz"),
+                dir.join("series/c.cbz")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_target_override_markers() {
+        let files = [
+            "book/cover.jxl",
+            "book/cover.jxl.jxl-target",
+            "book/page_005.jpg",
+            "book/page_005.jpg.AVIF-target",
+            "book/notes.txt.webp-target",
+            "book/page_006.jpg.gif-target",
+        ]
+        .map(|name| (PathBuf::from(name), image_format(Path::new(name))));
+        let targets = target_overrides(&files);
+        assert_eq!(
+            targets,
+            HashMap::from([
+                (PathBuf::from("book/cover.jxl"), Jxl),
+                (PathBuf::from("book/page_005.jpg"), Avif),
+            ])
+        );
+
+        let config = ConversionConfig::default();
+        let marker = Path::new("book/page_005.jpg.AVIF-target");
+        assert!(!config.keeps_file(Container::Zip, marker));
+        assert!(config.keeps_file(Container::Zip, Path::new("book/page_005.jpg")));
+    }
+
+    #[test]
+    fn test_self_test() {
+        let dir = TempDir::new("self_test");
+        let image = dir.join("image.jpg");
+        fs::write(&image, SELF_TEST_JPEG).unwrap();
+        assert_eq!(dimensions::image_dimensions(&image).unwrap(), Some((8, 8)));
+        assert_eq!(probe_format(&image).unwrap(), Some(Jpeg));
+
+        let results = self_test(&ConversionConfig::default());
+        assert!(!self_test_dir().exists());
+        let jxl = results.iter().position(|(_, to, _)| *to == Jxl).unwrap();
+        if !spawn::is_installed(Tool::Cjxl) {
+            assert!(matches!(&results[jxl].2, Err(e) if e.contains("cjxl")));
+            // nothing to convert back from
+            assert_ne!(results.get(jxl + 1).map(|(from, _, _)| *from), Some(Jxl));
+        }
+    }
+
+    #[test]
+    fn test_parse_env_var() {
+        let var = |key: &str, value: &str| Ok((key.to_string(), value.to_string()));
+        assert_eq!(
+            parse_env_var("OMP_NUM_THREADS=2"),
+            var("OMP_NUM_THREADS", "2")
+        );
+        assert_eq!(parse_env_var("A=b=c"), var("A", "b=c"));
+        assert_eq!(parse_env_var("EMPTY="), var("EMPTY", ""));
+        assert!(parse_env_var("=1").is_err());
+        assert!(parse_env_var("TMPDIR").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_size("3KB"), Ok(3 << 10));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("5X").is_err());
+        assert!(parse_size("1.5G").is_err());
+    }
+
+    #[test]
+    fn test_reading_order() {
+        let mut images = [
+            "b/10.png",
+            "b/9.png",
+            "a/page 2.jpg",
+            "a/page 010.jpg",
+            "a/cover.jpg",
+        ]
+        .map(|p| (PathBuf::from(p), Png))
+        .to_vec();
+        sort_reading_order(&mut images);
+        let order = images
+            .iter()
+            .map(|(p, _)| p.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                "a/cover.jpg",
+                "a/page 2.jpg",
+                "a/page 010.jpg",
+                "b/9.png",
+                "b/10.png"
+            ]
+        );
     }
 }
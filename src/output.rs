@@ -0,0 +1,38 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+/// File the report lines go to instead of the terminal, set up once at startup
+static REDIRECT: Mutex<Option<File>> = Mutex::new(None);
+
+/// Send all further report lines to the given file
+pub fn redirect(path: &Path) -> io::Result<()> {
+    let file = File::create(path)?;
+    *REDIRECT.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Write a line of the report, which is kept apart from logging and progress on stderr
+pub fn stdout(line: impl Display) {
+    match REDIRECT.lock().unwrap().as_mut() {
+        Some(file) => {
+            // a report that cannot be written is no reason to abort the conversion
+            let _ = writeln!(file, "{line}");
+        }
+        None => println!("{line}"),
+    }
+}
+
+/// Write the path of a written archive, always to stdout and without any decoration
+pub fn path(path: &Path) {
+    println!("{}", path.display());
+}
+
+/// A report as a JSON document, reports only hold strings, numbers and lists of them
+pub fn json(report: &impl Serialize) -> String {
+    serde_json::to_string(report).expect("reports can always be written as JSON")
+}
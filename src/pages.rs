@@ -0,0 +1,68 @@
+/// Selection of pages by their number in reading order, starting at 1
+///
+/// Written like `1-10,50,90-`, where a range without an end reaches to the last page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageRanges(Vec<(usize, Option<usize>)>);
+
+impl PageRanges {
+    pub fn contains(&self, page: usize) -> bool {
+        self.0
+            .iter()
+            .any(|&(first, last)| first <= page && last.is_none_or(|last| page <= last))
+    }
+}
+
+impl std::str::FromStr for PageRanges {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let page = |number: &str| match number.trim().parse::<usize>() {
+            Ok(0) | Err(_) => Err(format!("'{number}' is not a page number, pages start at 1")),
+            Ok(page) => Ok(page),
+        };
+        let mut ranges = vec![];
+        for range in value.split(',') {
+            let (first, last) = match range.split_once('-') {
+                Some((first, "")) => (page(first)?, None),
+                Some((first, last)) => (page(first)?, Some(page(last)?)),
+                None => (page(range)?, Some(page(range)?)),
+            };
+            if last.is_some_and(|last| last < first) {
+                return Err(format!("range '{range}' ends before it starts"));
+            }
+            ranges.push((first, last));
+        }
+        Ok(PageRanges(ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comma_separated_ranges() {
+        let pages = "1-3,5,8-9".parse::<PageRanges>().unwrap();
+        let selected = (1..=10).filter(|&page| pages.contains(page));
+        assert_eq!(selected.collect::<Vec<_>>(), [1, 2, 3, 5, 8, 9]);
+    }
+
+    #[test]
+    fn test_open_ended_range() {
+        let pages = "2,90-".parse::<PageRanges>().unwrap();
+        assert!(!pages.contains(1));
+        assert!(pages.contains(2));
+        assert!(!pages.contains(89));
+        assert!(pages.contains(90));
+        assert!(pages.contains(10_000));
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        assert!("0-3".parse::<PageRanges>().is_err());
+        assert!("5-2".parse::<PageRanges>().is_err());
+        assert!("1,,2".parse::<PageRanges>().is_err());
+        assert!("-3".parse::<PageRanges>().is_err());
+        assert!("a".parse::<PageRanges>().is_err());
+    }
+}
@@ -1,182 +1,713 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::Path;
 use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{LazyLock, Mutex};
 
 use anyhow::Result;
+use log::{log, log_enabled, Level};
 
 use crate::ConversionError::{self, *};
+use crate::{ConversionConfig, ImageFormat};
 
-pub fn convert_jpeg_to_png(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
-) -> Result<Child, ConversionError> {
-    let mut command = Command::new("magick");
-    command.args([input_path.to_str().unwrap(), output_path.to_str().unwrap()]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("magick".to_string()))?;
-    Ok(child)
-}
-
-pub fn convert_png_to_jpeg(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
-) -> Result<Child, ConversionError> {
-    let mut command = Command::new("magick");
-    command.args([
-        input_path.to_str().unwrap(),
-        "-quality",
-        "92",
-        output_path.to_str().unwrap(),
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("magick".to_string()))?;
-    Ok(child)
-}
-
-pub fn encode_avif(input_path: &PathBuf, output_path: &PathBuf) -> Result<Child, ConversionError> {
-    let mut command = Command::new("cavif");
-    command.args([
-        "--speed=3",
-        "--threads=1",
-        "--quality=88",
-        input_path.to_str().unwrap(),
-        "-o",
-        output_path.to_str().unwrap(),
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("cavif".to_string()))?;
-    Ok(child)
-}
-
-pub fn encode_jxl(input_path: &PathBuf, output_path: &PathBuf) -> Result<Child, ConversionError> {
-    let mut command = Command::new("cjxl");
-    command.args([
-        "--effort=9",
-        "--num_threads=1",
-        "--distance=0",
-        input_path.to_str().unwrap(),
-        output_path.to_str().unwrap(),
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("cjxl".to_string()))?;
-    Ok(child)
-}
-
-pub fn encode_webp(input_path: &PathBuf, output_path: &PathBuf) -> Result<Child, ConversionError> {
-    let mut command = Command::new("cwebp");
-    command.args([
-        "-q",
-        "90",
-        input_path.to_str().unwrap(),
-        "-o",
-        output_path.to_str().unwrap(),
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("cwebp".to_string()))?;
-    Ok(child)
-}
-
-pub fn decode_webp(input_path: &PathBuf, output_path: &PathBuf) -> Result<Child, ConversionError> {
-    let mut command = Command::new("dwebp");
-    command.args([
-        input_path.to_str().unwrap(),
-        "-o",
-        output_path.to_str().unwrap(),
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("dwebp".to_string()))?;
-    Ok(child)
-}
-
-pub fn decode_jxl_to_png(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
-) -> Result<Child, ConversionError> {
-    let mut command = Command::new("djxl");
-    command.args([
-        input_path.to_str().unwrap(),
-        output_path.to_str().unwrap(),
-        "--num_threads=1",
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("djxl".to_string()))?;
-    Ok(child)
-}
-
-pub fn decode_jxl_to_jpeg(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
-) -> Result<Child, ConversionError> {
-    let mut command = Command::new("djxl");
-    command.args([
-        input_path.to_str().unwrap(),
-        output_path.to_str().unwrap(),
-        "--num_threads=1",
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("djxl".to_string()))?;
-    Ok(child)
-}
-
-pub fn decode_avif_to_png(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
-) -> Result<Child, ConversionError> {
-    let mut command = Command::new("avifdec");
-    command.args([
-        "--jobs",
-        "1",
-        input_path.to_str().unwrap(),
-        output_path.to_str().unwrap(),
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("avifdec".to_string()))?;
-    Ok(child)
-}
-
-pub fn decode_avif_to_jpeg(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
-) -> Result<Child, ConversionError> {
-    let mut command = Command::new("avifdec");
-    command.args([
-        "--jobs",
-        "1",
-        "--quality",
-        "80",
-        input_path.to_str().unwrap(),
-        output_path.to_str().unwrap(),
-    ]);
-    let child = command
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| SpawnFailure("avifdec".to_string()))?;
-    Ok(child)
+/// Log tool invocations at info instead of debug level
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Added to the niceness of every spawned tool
+static NICENESS: AtomicI32 = AtomicI32::new(0);
+
+/// Start every tool in a process group of its own, out of reach of the terminal's SIGINT
+static DETACHED: AtomicBool = AtomicBool::new(false);
+
+/// Tool used for `encode_avif`
+static AVIF_ENCODER: Mutex<Tool> = Mutex::new(Tool::Cavif);
+
+/// Versions of the tools in use, queried once per tool
+static TOOL_VERSIONS: LazyLock<Mutex<HashMap<Tool, Option<String>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Formats magick can write as listed by `magick -list format`, queried once
+static MAGICK_WRITABLE: LazyLock<Vec<String>> = LazyLock::new(|| {
+    let output = Command::new(Tool::Magick.program())
+        .args(["-list", "format"])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            writable_formats(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => vec![],
+    }
+});
+
+/// External programs used for the conversion of images
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Tool {
+    Magick,
+    Cavif,
+    Avifenc,
+    Cjxl,
+    Djxl,
+    Cwebp,
+    Dwebp,
+    Avifdec,
+    Oxipng,
+    Cjpeg,
+    Jxlinfo,
+}
+
+impl Tool {
+    pub fn program(self) -> &'static str {
+        match self {
+            Tool::Magick => "magick",
+            Tool::Cavif => "cavif",
+            Tool::Avifenc => "avifenc",
+            Tool::Cjxl => "cjxl",
+            Tool::Djxl => "djxl",
+            Tool::Cwebp => "cwebp",
+            Tool::Dwebp => "dwebp",
+            Tool::Avifdec => "avifdec",
+            Tool::Oxipng => "oxipng",
+            Tool::Cjpeg => "cjpeg",
+            Tool::Jxlinfo => "jxlinfo",
+        }
+    }
+
+    fn version_arg(self) -> &'static str {
+        match self {
+            Tool::Cwebp | Tool::Dwebp | Tool::Cjpeg => "-version",
+            _ => "--version",
+        }
+    }
+}
+
+impl std::fmt::Display for Tool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program())
+    }
+}
+
+impl serde::Serialize for Tool {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Run all tools with their niceness increased by `increment`, like `nice -n`
+pub fn set_niceness(increment: i32) {
+    if cfg!(not(unix)) && increment != 0 {
+        log::warn!("process priorities are not supported on this platform, ignore --nice");
+        return;
+    }
+    NICENESS.store(increment, Ordering::Relaxed);
+}
+
+/// Keep Ctrl+C in the terminal from reaching the tools, which are then only stopped by
+/// killing them
+pub fn set_detached(detached: bool) {
+    if cfg!(not(unix)) && detached {
+        log::warn!("process groups are not supported on this platform, tools stop on Ctrl+C");
+        return;
+    }
+    DETACHED.store(detached, Ordering::Relaxed);
+}
+
+/// Encoders that can write Avif images
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum AvifEncoder {
+    Cavif,
+    Avifenc,
+}
+
+/// Use the given encoder, or the first one installed when there is no preference
+pub fn set_avif_encoder(encoder: Option<AvifEncoder>) {
+    let tool = match encoder {
+        Some(AvifEncoder::Cavif) => Tool::Cavif,
+        Some(AvifEncoder::Avifenc) => Tool::Avifenc,
+        None => [Tool::Cavif, Tool::Avifenc]
+            .into_iter()
+            .find(|tool| tool_version(*tool).is_some())
+            .unwrap_or(Tool::Cavif),
+    };
+    *AVIF_ENCODER.lock().unwrap() = tool;
+}
+
+pub fn avif_encoder() -> Tool {
+    *AVIF_ENCODER.lock().unwrap()
+}
+
+/// Tools that can write Png images converted from Jpeg
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum PngTool {
+    #[default]
+    Magick,
+    /// Convert with magick, then optimize with oxipng
+    Oxipng,
+}
+
+/// Tools that can write Jpeg images converted from Png
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum JpegTool {
+    #[default]
+    Magick,
+    /// The cjpeg of mozjpeg, which needs to be built with Png support
+    Mozjpeg,
+}
+
+/// Whether the program of the tool can be started at all, even if it has no version to tell
+pub fn is_installed(tool: Tool) -> bool {
+    Command::new(tool.program())
+        .arg(tool.version_arg())
+        .output()
+        .is_ok()
+}
+
+/// First line the tool prints about its version, cached after the first call
+pub fn tool_version(tool: Tool) -> Option<String> {
+    let mut versions = TOOL_VERSIONS.lock().unwrap();
+    versions
+        .entry(tool)
+        .or_insert_with(|| {
+            let output = Command::new(tool.program())
+                .arg(tool.version_arg())
+                .output()
+                .ok()
+                .filter(|output| output.status.success())?;
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            stdout
+                .lines()
+                .chain(stderr.lines())
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .map(str::to_string)
+        })
+        .clone()
+}
+
+/// Whether magick was built with a delegate that writes the format, builds without the
+/// libraries for Avif, Jxl or Webp lack one
+pub fn magick_can_write(format: ImageFormat) -> bool {
+    MAGICK_WRITABLE.contains(&format.to_string().to_uppercase())
+}
+
+/// Names of the formats with write support in lines like `     AVIF* rw+   AV1 Image File Format`
+fn writable_formats(listing: &str) -> Vec<String> {
+    listing
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let name = columns.next()?.trim_end_matches('*');
+            let mode = columns.next()?;
+            let writes = mode.len() == 3 && mode.chars().nth(1) == Some('w');
+            writes.then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// Why a tool failed, as far as can be told from its output
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    OutOfMemory,
+    UnsupportedInput,
+    MissingInput,
+    /// Exited successfully, but the output is missing or empty
+    EmptyOutput,
+    /// Ran for longer than allowed and was killed
+    TimedOut,
+    Unknown,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            Failure::OutOfMemory => "out of memory",
+            Failure::UnsupportedInput => "unsupported input",
+            Failure::MissingInput => "missing input",
+            Failure::EmptyOutput => "empty output",
+            Failure::TimedOut => "timed out",
+            Failure::Unknown => "unknown cause",
+        };
+        write!(f, "{description}")
+    }
+}
+
+/// Lowercase messages the tools print for failures, patterns without a tool apply to all
+const FAILURE_PATTERNS: &[(Option<Tool>, &str, Failure)] = &[
+    (None, "out of memory", Failure::OutOfMemory),
+    (None, "cannot allocate memory", Failure::OutOfMemory),
+    (None, "memory allocation failed", Failure::OutOfMemory),
+    (None, "bad_alloc", Failure::OutOfMemory),
+    (None, "no such file or directory", Failure::MissingInput),
+    (
+        Some(Tool::Magick),
+        "no decode delegate",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Magick),
+        "improper image header",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Magick),
+        "insufficient image data",
+        Failure::UnsupportedInput,
+    ),
+    // cavif reads everything that is no Png as Jpeg
+    (
+        Some(Tool::Cavif),
+        "invalid jpeg format",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Avifenc),
+        "unrecognized file format",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Avifenc),
+        "cannot read input file",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Cjxl),
+        "getting pixel data failed",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Cjxl),
+        "unknown image format",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Djxl),
+        "failed to decode",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Cwebp),
+        "unsupported image format",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Cwebp),
+        "could not process file",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Dwebp),
+        "unsupported_feature",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Dwebp),
+        "bitstream_error",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Avifdec),
+        "failed to decode",
+        Failure::UnsupportedInput,
+    ),
+    (
+        Some(Tool::Avifdec),
+        "unsupported",
+        Failure::UnsupportedInput,
+    ),
+];
+
+/// Find out why the tool failed from what it printed
+pub fn classify_failure(tool: Tool, output: &str) -> Failure {
+    let output = output.to_lowercase();
+    FAILURE_PATTERNS
+        .iter()
+        .filter(|(pattern_tool, _, _)| pattern_tool.is_none_or(|pattern_tool| pattern_tool == tool))
+        .find(|(_, pattern, _)| output.contains(pattern))
+        .map_or(Failure::Unknown, |(_, _, failure)| *failure)
+}
+
+/// Lowercase markers of lines in which a tool warns about its result, despite succeeding
+const WARNING_MARKERS: &[&str] = &["warning", "ignored", "ignoring", "not supported"];
+
+/// Lines of the output in which the tool warns, leaving out banners and progress
+pub fn tool_warnings(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| {
+            let line = line.to_lowercase();
+            WARNING_MARKERS.iter().any(|marker| line.contains(marker))
+        })
+        .collect()
+}
+
+/// A tool with all its arguments, ready to be run
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invocation {
+    tool: Tool,
+    args: Vec<String>,
+    /// Environment variables set for the tool on top of the inherited ones
+    env: Vec<(String, String)>,
+}
+
+impl Invocation {
+    pub fn tool(&self) -> Tool {
+        self.tool
+    }
+
+    fn new(tool: Tool, args: &[&str]) -> Invocation {
+        let args = args.iter().map(|arg| arg.to_string()).collect();
+        Invocation {
+            tool,
+            args,
+            env: vec![],
+        }
+    }
+
+    pub fn with_env(mut self, env: &[(String, String)]) -> Invocation {
+        self.env = env.to_vec();
+        self
+    }
+
+    pub fn spawn(&self) -> Result<Child, ConversionError> {
+        let level = match is_verbose() {
+            true => Level::Info,
+            false => Level::Debug,
+        };
+        if log_enabled!(level) {
+            let version = tool_version(self.tool).unwrap_or("unknown version".to_string());
+            log!(level, "{self} [{version}]");
+        }
+        let mut command = Command::new(self.tool.program());
+        command
+            .args(&self.args)
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+
+            let increment = NICENESS.load(Ordering::Relaxed);
+            let detached = DETACHED.load(Ordering::Relaxed);
+            if increment != 0 || detached {
+                // SAFETY: nice and setpgid are async-signal-safe and nothing else runs before
+                // the exec
+                unsafe {
+                    command.pre_exec(move || {
+                        // a tool at normal priority is no reason to fail the conversion
+                        if increment != 0 {
+                            libc::nice(increment);
+                        }
+                        if detached {
+                            libc::setpgid(0, 0);
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+        command
+            .spawn()
+            .map_err(|_| SpawnFailure(self.tool.program().to_string()))
+    }
+}
+
+impl std::fmt::Display for Invocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in self.env.iter() {
+            match value.contains(char::is_whitespace) {
+                true => write!(f, "{key}='{value}' ")?,
+                false => write!(f, "{key}={value} ")?,
+            }
+        }
+        write!(f, "{}", self.tool)?;
+        for arg in self.args.iter() {
+            match arg.contains(char::is_whitespace) {
+                true => write!(f, " '{arg}'")?,
+                false => write!(f, " {arg}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert between Jpeg and Png, applying any of the requested image operations
+pub fn convert_with_magick(
+    input_path: &Path,
+    output_path: &Path,
+    target: ImageFormat,
+    config: &ConversionConfig,
+) -> Invocation {
+    let mut args = vec![input_path.to_str().unwrap().to_string()];
+    // colors are transformed first, the other operations work on the result
+    match &config.srgb_profile {
+        Some(profile) if config.to_srgb => args.extend([
+            "-profile".to_string(),
+            profile.to_str().unwrap().to_string(),
+        ]),
+        _ if config.to_srgb => args.extend(["-colorspace".to_string(), "sRGB".to_string()]),
+        _ => (),
+    }
+    if let Some(max) = config.max_dimension {
+        // '>' only ever shrinks images, the aspect ratio is always kept
+        args.extend(["-resize".to_string(), format!("{max}x{max}>")]);
+    }
+    if config.grayscale {
+        args.extend(["-colorspace".to_string(), "Gray".to_string()]);
+    }
+    if target == ImageFormat::Jpeg {
+        if let Some(background) = &config.alpha_background {
+            args.extend(
+                [
+                    "-background",
+                    background,
+                    "-alpha",
+                    "remove",
+                    "-alpha",
+                    "off",
+                ]
+                .map(String::from),
+            );
+        }
+        args.extend(["-quality".to_string(), config.quality.jpeg.to_string()]);
+    }
+    // magick goes by the extension, which may be one it does not know like avifs
+    let output = output_path.to_str().unwrap();
+    match output_path
+        .extension()
+        .is_some_and(|ext| ext == target.extensions()[0])
+    {
+        true => args.push(output.to_string()),
+        false => args.push(format!(
+            "{}:{output}",
+            target.extensions()[0].to_uppercase()
+        )),
+    }
+    Invocation::new(
+        Tool::Magick,
+        &args.iter().map(String::as_str).collect::<Vec<_>>(),
+    )
+}
+
+pub fn encode_avif(input_path: &Path, output_path: &Path, quality: u8) -> Invocation {
+    match avif_encoder() {
+        Tool::Avifenc => Invocation::new(
+            Tool::Avifenc,
+            &[
+                "--speed",
+                "3",
+                "--jobs",
+                "1",
+                "--qcolor",
+                &quality.to_string(),
+                input_path.to_str().unwrap(),
+                output_path.to_str().unwrap(),
+            ],
+        ),
+        _ => Invocation::new(
+            Tool::Cavif,
+            &[
+                "--speed=3",
+                "--threads=1",
+                &format!("--quality={quality}"),
+                input_path.to_str().unwrap(),
+                "-o",
+                output_path.to_str().unwrap(),
+            ],
+        ),
+    }
+}
+
+/// Encode losslessly, Jpegs are recompressed so that djxl can restore the original file
+pub fn encode_jxl(input_path: &Path, output_path: &Path, from: ImageFormat) -> Invocation {
+    let mut args = vec!["--effort=9", "--num_threads=1", "--distance=0"];
+    if from == ImageFormat::Jpeg {
+        args.push("--lossless_jpeg=1");
+    }
+    args.extend([input_path.to_str().unwrap(), output_path.to_str().unwrap()]);
+    Invocation::new(Tool::Cjxl, &args)
+}
+
+pub fn encode_webp(input_path: &Path, output_path: &Path, quality: u8) -> Invocation {
+    Invocation::new(
+        Tool::Cwebp,
+        &[
+            "-q",
+            &quality.to_string(),
+            input_path.to_str().unwrap(),
+            "-o",
+            output_path.to_str().unwrap(),
+        ],
+    )
+}
+
+pub fn encode_jpeg(input_path: &Path, output_path: &Path, quality: u8) -> Invocation {
+    Invocation::new(
+        Tool::Cjpeg,
+        &[
+            "-quality",
+            &quality.to_string(),
+            "-outfile",
+            output_path.to_str().unwrap(),
+            input_path.to_str().unwrap(),
+        ],
+    )
+}
+
+pub fn optimize_png(input_path: &Path, output_path: &Path) -> Invocation {
+    Invocation::new(
+        Tool::Oxipng,
+        &[
+            "--opt",
+            "2",
+            "--threads",
+            "1",
+            "--out",
+            output_path.to_str().unwrap(),
+            input_path.to_str().unwrap(),
+        ],
+    )
+}
+
+pub fn decode_webp(input_path: &Path, output_path: &Path) -> Invocation {
+    Invocation::new(
+        Tool::Dwebp,
+        &[
+            input_path.to_str().unwrap(),
+            "-o",
+            output_path.to_str().unwrap(),
+        ],
+    )
+}
+
+pub fn decode_jxl_to_png(input_path: &Path, output_path: &Path) -> Invocation {
+    Invocation::new(
+        Tool::Djxl,
+        &[
+            input_path.to_str().unwrap(),
+            output_path.to_str().unwrap(),
+            "--num_threads=1",
+        ],
+    )
+}
+
+pub fn decode_jxl_to_jpeg(input_path: &Path, output_path: &Path) -> Invocation {
+    Invocation::new(
+        Tool::Djxl,
+        &[
+            input_path.to_str().unwrap(),
+            output_path.to_str().unwrap(),
+            "--num_threads=1",
+        ],
+    )
+}
+
+pub fn decode_avif_to_png(input_path: &Path, output_path: &Path) -> Invocation {
+    Invocation::new(
+        Tool::Avifdec,
+        &[
+            "--jobs",
+            "1",
+            input_path.to_str().unwrap(),
+            output_path.to_str().unwrap(),
+        ],
+    )
+}
+
+pub fn decode_avif_to_jpeg(input_path: &Path, output_path: &Path, quality: u8) -> Invocation {
+    Invocation::new(
+        Tool::Avifdec,
+        &[
+            "--jobs",
+            "1",
+            "--quality",
+            &quality.to_string(),
+            input_path.to_str().unwrap(),
+            output_path.to_str().unwrap(),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tool_warnings() {
+        let output = "JPEG XL encoder v0.10.2\n\
+            Warning: ICC profile ignored\n\
+            Compressed to 120.5 kB (1.2 bpp).\n";
+        assert_eq!(tool_warnings(output), ["Warning: ICC profile ignored"]);
+        assert!(tool_warnings("Encoding [VarDCT, d1.000, effort: 7]").is_empty());
+    }
+
+    #[test]
+    fn test_writable_formats() {
+        let listing = "   Format  Mode  Description\n\
+            -------------------------------------------------------------------------------\n\
+            \x20     AVIF  r--   AV1 Image File Format (1.0.0)\n\
+            \x20      JXL  rw-   JPEG XL (ISO/IEC 18181) (libjxl 0.8.2)\n\
+            \x20     WEBP* rw+   WebP Image Format (libwebp 1.3.2 [020F])\n";
+        assert_eq!(writable_formats(listing), ["JXL", "WEBP"]);
+    }
+
+    #[test]
+    fn test_invocation_with_env() {
+        let env = [("OMP_NUM_THREADS", "2"), ("TMPDIR", "/mnt/ram disk")]
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+        let invocation = Invocation::new(Tool::Cjxl, &["in.png", "out.jxl"]).with_env(&env);
+        assert_eq!(
+            invocation.to_string(),
+            "OMP_NUM_THREADS=2 TMPDIR='/mnt/ram disk' cjxl in.png out.jxl"
+        );
+    }
+
+    #[test]
+    fn test_classify_failure() {
+        let classify = classify_failure;
+        assert_eq!(
+            classify(Tool::Cjxl, "JxlEncoderProcessOutput failed: Out of memory"),
+            Failure::OutOfMemory
+        );
+        assert_eq!(
+            classify(
+                Tool::Magick,
+                "magick: no decode delegate for this image format `PNG'"
+            ),
+            Failure::UnsupportedInput
+        );
+        assert_eq!(
+            classify(Tool::Dwebp, "Status: 4(UNSUPPORTED_FEATURE)"),
+            Failure::UnsupportedInput
+        );
+        assert_eq!(
+            classify(
+                Tool::Cwebp,
+                "Open input file failed: No such file or directory"
+            ),
+            Failure::MissingInput
+        );
+        assert_eq!(
+            classify(
+                Tool::Cavif,
+                "error: invalid JPEG format: first two bytes are not an SOI marker"
+            ),
+            Failure::UnsupportedInput
+        );
+        assert_eq!(
+            classify(Tool::Cavif, "unsupported color space"),
+            Failure::Unknown
+        );
+        // patterns of other tools do not apply
+        assert_eq!(classify(Tool::Cwebp, "failed to decode"), Failure::Unknown);
+    }
 }
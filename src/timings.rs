@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::output;
+
+/// Time spent per stage and how often it ran, only collected when enabled at startup
+static TIMINGS: Mutex<Option<BTreeMap<String, (Duration, usize)>>> = Mutex::new(None);
+
+pub fn enable() {
+    *TIMINGS.lock().unwrap() = Some(BTreeMap::new());
+}
+
+/// Add the time passed since `start` to the stage
+pub fn record(stage: impl ToString, start: Instant) {
+    let elapsed = start.elapsed();
+    if let Some(timings) = TIMINGS.lock().unwrap().as_mut() {
+        let (duration, count) = timings.entry(stage.to_string()).or_default();
+        *duration += elapsed;
+        *count += 1;
+    }
+}
+
+/// Print the accumulated time of all stages
+///
+/// Images are converted in parallel, so the stages can add up to more than the wall time.
+pub fn report() {
+    let Some(timings) = TIMINGS.lock().unwrap().take() else {
+        return;
+    };
+    output::stdout("timings:");
+    for (stage, (duration, count)) in timings {
+        output::stdout(format!(
+            "  {stage}: {:.2}s in {count} runs",
+            duration.as_secs_f64()
+        ));
+    }
+}